@@ -1 +1,7 @@
 pub mod ai;
+pub mod book;
+pub mod book_builder;
+pub mod book_file;
+pub mod book_learning;
+pub mod game_book;
+pub mod opening_suite;
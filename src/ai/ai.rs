@@ -1,24 +1,44 @@
+use crate::ai::book::*;
 use crate::learning::learning::*;
+use crate::learning::nnue::*;
 use crate::reversi::reversi::*;
 use crate::util::util::*;
 use rand::seq::SliceRandom;
 use rand::{rngs::ThreadRng, Rng};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
 use std::option::Option;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use std::time::Instant;
 
 const MAX_SCORE: i32 = 100000;
 
+// Search progress output, turned off for batch jobs such as self-play.
+static SEARCH_LOG: AtomicBool = AtomicBool::new(true);
+
+macro_rules! search_log {
+    ($($arg:tt)*) => {
+        if SEARCH_LOG.load(Ordering::Relaxed) {
+            log!($($arg)*);
+        }
+    };
+}
+
+pub fn set_search_log(on: bool) {
+    SEARCH_LOG.store(on, Ordering::Relaxed);
+}
+
 fn evaluate_board(board: &Board) -> i32 {
-    let legal_score = evaluate_board_legal_score(board);
+    if let Some(nnue) = nnue() {
+        let score = nnue.evaluate(board);
+        return if board.turn == BLACK { score } else { -score };
+    }
+    let scalar_score = evaluate_board_scalar_score(board);
     let pattern_score = evaluate_board_pattern_score(board);
     if board.turn == BLACK {
-        legal_score + pattern_score
+        scalar_score + pattern_score
     } else {
-        -(legal_score + pattern_score)
+        -(scalar_score + pattern_score)
     }
     // let mut black_score: u32 = 0;
     // let mut white_score: u32 = 0;
@@ -54,6 +74,16 @@ fn evaluate_board(board: &Board) -> i32 {
     // }
 }
 
+// A disc difference in the units of `evaluate_board`. The patterns are
+// trained on disc differences and the network scales them by `NNUE_SCALE`.
+pub fn discs_to_score(discs: i32) -> i32 {
+    if nnue().is_some() {
+        discs * NNUE_SCALE
+    } else {
+        discs
+    }
+}
+
 pub fn random_pos(board: &Board) -> u64 {
     let legal_poss_vec: Vec<u64> = legal_poss(&board);
     let len = legal_poss_vec.len();
@@ -127,6 +157,14 @@ fn alpha_beta(
     (count_sum, alpha)
 }
 
+// Alpha-beta score of `board` for the side to move, in the units of
+// `evaluate_board`.
+pub fn search_score(board: &Board, depth: i32) -> i32 {
+    let mut board = *board;
+    let mut rng = rand::thread_rng();
+    alpha_beta(&mut board, &mut rng, i32::MIN + 1, i32::MAX, depth).1
+}
+
 pub fn alpha_beta_pos(board: &Board, depth: i32) -> u64 {
     let legal_poss_vec = legal_poss(board);
     let mut best_pos;
@@ -152,7 +190,7 @@ pub fn alpha_beta_pos(board: &Board, depth: i32) -> u64 {
         count_sum += count;
         score = -score;
         if score >= MAX_SCORE {
-            println!("Complete");
+            search_log!("Complete");
             return legal_poss_vec[i];
         }
         if score > alpha {
@@ -160,7 +198,7 @@ pub fn alpha_beta_pos(board: &Board, depth: i32) -> u64 {
             alpha = score;
         }
     }
-    println!(
+    search_log!(
         "score: {} {}  searched: {}",
         alpha,
         pos_to_cmd(&best_pos),
@@ -275,7 +313,7 @@ pub fn nega_alpha_transpose_pos(board: &Board, depth: i32, thinking_time: Durati
         //     return best_pos;
         // }
         if start_time.elapsed() >= thinking_time {
-            println!("score: {}", best_score);
+            search_log!("score: {}", best_score);
             return best_pos;
         }
         let mut alpha = std::i32::MIN + 1;
@@ -308,7 +346,7 @@ pub fn nega_alpha_transpose_pos(board: &Board, depth: i32, thinking_time: Durati
             score = -score;
             searched_nodes += count;
             if score >= MAX_SCORE {
-                println!("Complete");
+                search_log!("Complete");
                 return child.before_pos;
             }
             if score > alpha {
@@ -317,7 +355,7 @@ pub fn nega_alpha_transpose_pos(board: &Board, depth: i32, thinking_time: Durati
                 best_score = alpha;
             }
         }
-        println!(
+        search_log!(
             "searched_depth: {}  command: {}  visited nodes: {}",
             search_depth,
             pos_to_cmd(&best_pos),
@@ -325,9 +363,9 @@ pub fn nega_alpha_transpose_pos(board: &Board, depth: i32, thinking_time: Durati
         );
         former_transpose_table = transpose_table.clone();
         transpose_table.clear();
-        println!("score: {}", best_score);
+        search_log!("score: {}", best_score);
     }
-    println!("score: {}", best_score);
+    search_log!("score: {}", best_score);
     best_pos
 }
 
@@ -666,7 +704,7 @@ pub fn nega_scout_transpose_pos(board: &Board, depth: i32, thinking_time: Durati
         before_best_pos = best_pos;
         before_best_score = best_score;
         if start_time.elapsed() >= thinking_time {
-            println!("score: {}", before_best_score);
+            search_log!("score: {}", before_best_score);
             return before_best_pos;
         }
         let mut alpha = std::i32::MIN + 1;
@@ -687,7 +725,7 @@ pub fn nega_scout_transpose_pos(board: &Board, depth: i32, thinking_time: Durati
             child_boards.sort_by(|a, b| b.value.cmp(&a.value));
         }
         if start_time.elapsed() >= thinking_time {
-            println!("score: {}", before_best_score);
+            search_log!("score: {}", before_best_score);
             return before_best_pos;
         }
         let (count, mut score) = nega_scout(
@@ -706,13 +744,13 @@ pub fn nega_scout_transpose_pos(board: &Board, depth: i32, thinking_time: Durati
         alpha = score;
         best_pos = child_boards[0].before_pos;
         if score >= MAX_SCORE {
-            println!("Complete");
+            search_log!("Complete");
             return best_pos;
         }
 
         for mut child in &mut child_boards.clone()[1..] {
             if start_time.elapsed() >= thinking_time {
-                println!("score: {}", before_best_score);
+                search_log!("score: {}", before_best_score);
                 return before_best_pos;
             }
             let (count, mut score) = nega_scout_transpose(
@@ -729,7 +767,7 @@ pub fn nega_scout_transpose_pos(board: &Board, depth: i32, thinking_time: Durati
             score = -score;
             searched_nodes += count;
             if score >= MAX_SCORE {
-                println!("Complete");
+                search_log!("Complete");
                 return child.before_pos;
             }
             if score > alpha {
@@ -737,7 +775,7 @@ pub fn nega_scout_transpose_pos(board: &Board, depth: i32, thinking_time: Durati
                 alpha = score;
                 best_score = alpha;
                 if start_time.elapsed() >= thinking_time {
-                    println!("score: {}", before_best_score);
+                    search_log!("score: {}", before_best_score);
                     return before_best_pos;
                 }
                 (_, score) = nega_scout(
@@ -758,7 +796,7 @@ pub fn nega_scout_transpose_pos(board: &Board, depth: i32, thinking_time: Durati
                 best_score = alpha;
             }
         }
-        println!(
+        search_log!(
             "searched_depth: {}  command: {}  visited nodes: {}",
             search_depth,
             pos_to_cmd(&best_pos),
@@ -768,87 +806,17 @@ pub fn nega_scout_transpose_pos(board: &Board, depth: i32, thinking_time: Durati
         former_transpose_table_lower = transpose_table_lower.clone();
         transpose_table_upper.clear();
         transpose_table_lower.clear();
-        println!("score: {}", best_score);
+        search_log!("score: {}", best_score);
     }
-    println!("score: {}", best_score);
+    search_log!("score: {}", best_score);
     best_pos
 }
 
-pub fn create_book(path: &str, book: &mut HashMap<Board, u64>) {
-    match File::open(path) {
-        Err(e) => {
-            println!("Failed in opening file ({}).", e);
-        }
-        Ok(file) => {
-            println!("Success in opening file.");
-            let reader = BufReader::new(file);
-
-            for line in reader.lines() {
-                let line_content = line.unwrap();
-                let len = line_content.len();
-                let mut board: Board = Board {
-                    black_board: 0,
-                    white_board: 0,
-                    turn: BLACK,
-                    no_legal_command: 0,
-                    value: 0,
-                    before_pos: 0,
-                };
-
-                init_board(&mut board);
-                for i in 0..(len / 2 - 1) {
-                    board =
-                        execute_cmd(&mut board, line_content[(2 * i)..=(2 * i + 1)].to_string());
-                }
-                book.insert(
-                    board,
-                    cmd_to_pos(line_content[(len - 2)..=(len - 1)].to_string()),
-                );
-
-                init_board(&mut board);
-                for i in 0..(len / 2 - 1) {
-                    let mut pos = cmd_to_pos(line_content[(2 * i)..=(2 * i + 1)].to_string());
-                    pos = rotate180_pos(pos);
-                    board = execute_pos(&mut board, pos);
-                }
-                book.insert(
-                    board,
-                    rotate180_pos(cmd_to_pos(line_content[(len - 2)..=(len - 1)].to_string())),
-                );
-
-                init_board(&mut board);
-                for i in 0..(len / 2 - 1) {
-                    let mut pos = cmd_to_pos(line_content[(2 * i)..=(2 * i + 1)].to_string());
-                    pos = flip_diagonal_pos(pos);
-                    board = execute_pos(&mut board, pos);
-                }
-                book.insert(
-                    board,
-                    flip_diagonal_pos(cmd_to_pos(line_content[(len - 2)..=(len - 1)].to_string())),
-                );
-
-                init_board(&mut board);
-                for i in 0..(len / 2 - 1) {
-                    let mut pos = cmd_to_pos(line_content[(2 * i)..=(2 * i + 1)].to_string());
-                    pos = rotate180_pos(flip_diagonal_pos(pos));
-                    board = execute_pos(&mut board, pos);
-                }
-                book.insert(
-                    board,
-                    rotate180_pos(flip_diagonal_pos(cmd_to_pos(
-                        line_content[(len - 2)..=(len - 1)].to_string(),
-                    ))),
-                );
-            }
-        }
-    }
-}
-
 pub fn ai_pos(
     board: &mut Board,
     depth: i32,
     ai_name: String,
-    book: &HashMap<Board, u64>,
+    book: &Book,
     remaining_time: u64,
 ) -> (u64, Duration) {
     let pos;
@@ -864,8 +832,11 @@ pub fn ai_pos(
         pos = random_pos(&board);
     } else if ai_name == "ab" {
         pos = alpha_beta_pos(&board, 9);
-    } else if let Some(pos_ref) = book.get(board) {
-        pos = *pos_ref;
+    } else if let Some(book_pos) = book.probe(board) {
+        for book_move in book.moves(board) {
+            search_log!("book: {}", book_move);
+        }
+        pos = book_pos;
     } else if ai_name == "na" {
         if count >= 48 {
             pos =
@@ -874,17 +845,17 @@ pub fn ai_pos(
             pos = nega_alpha_transpose_pos(&board, depth, Duration::from_millis(thinking_time));
         }
     } else if count >= 44 {
-        println!(
+        search_log!(
             "Let's think {:.2?}",
             Duration::from_millis(thinking_time * 2)
         );
         pos =
             nega_scout_transpose_pos(&board, 65 - count, Duration::from_millis(thinking_time * 2));
     } else {
-        println!("Let's think {:.2?}", Duration::from_millis(thinking_time));
+        search_log!("Let's think {:.2?}", Duration::from_millis(thinking_time));
         pos = nega_scout_transpose_pos(&board, depth, Duration::from_millis(thinking_time));
     }
     let duration = start_time.elapsed();
-    println!("Thinking time: {:.2?}", duration);
+    search_log!("Thinking time: {:.2?}", duration);
     (pos, duration)
 }
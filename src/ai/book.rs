@@ -0,0 +1,387 @@
+use crate::ai::book_file::*;
+use crate::record::wthor::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+
+// Number of plies of each WTHOR game used as book lines.
+const WTHOR_BOOK_DEPTH: usize = 20;
+
+// One known move of a book position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BookMove {
+    pub pos: u64,
+    // Minimax value for the side to move in the engine's evaluation units,
+    // when it is known.
+    pub value: Option<f32>,
+    // Games this move was played in, and how many of them the side to move
+    // won, draws counting half.
+    pub count: u32,
+    pub wins: f32,
+}
+
+impl BookMove {
+    pub fn win_rate(&self) -> Option<f32> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.wins / self.count as f32)
+        }
+    }
+}
+
+impl fmt::Display for BookMove {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", pos_to_cmd(&self.pos))?;
+        if let Some(value) = self.value {
+            write!(f, "  value: {:+.1}", value)?;
+        }
+        if let Some(win_rate) = self.win_rate() {
+            write!(
+                f,
+                "  games: {}  win rate: {:.1}%",
+                self.count,
+                win_rate * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Positions are stored once for all 8 symmetries, keyed by the canonical
+// board with the moves in its orientation.
+#[derive(Clone, Default)]
+pub struct Book {
+    pub entries: HashMap<Board, Vec<BookMove>>,
+    // Moves up to this much worse than the best one are played as well.
+    pub margin: f32,
+    // Binary book looked up on demand for positions not in `entries`.
+    pub file: Option<BookFile>,
+}
+
+// The symmetric board with the smallest discs and the symmetry that gives
+// it.
+pub fn canonical_board(board: &Board) -> (Board, usize) {
+    let k = (0..8)
+        .min_by_key(|&k| {
+            (
+                symmetric_pos(board.black_board, k),
+                symmetric_pos(board.white_board, k),
+            )
+        })
+        .unwrap();
+    let canonical = Board::new(
+        symmetric_pos(board.black_board, k),
+        symmetric_pos(board.white_board, k),
+        board.turn,
+    );
+    (canonical, k)
+}
+
+// `pos` at `board` in the orientation of `canonical_board`. At symmetric
+// positions equivalent moves become the same move.
+pub fn canonical_move(board: &Board, pos: u64) -> (Board, u64) {
+    let (canonical, _) = canonical_board(board);
+    let pos = (0..8)
+        .filter(|&k| {
+            symmetric_pos(board.black_board, k) == canonical.black_board
+                && symmetric_pos(board.white_board, k) == canonical.white_board
+        })
+        .map(|k| symmetric_pos(pos, k))
+        .min()
+        .unwrap();
+    (canonical, pos)
+}
+
+// Game result in wins for the side to move, draws counting half.
+pub fn score_wins(score: i32) -> f32 {
+    match score {
+        s if s > 0 => 1.0,
+        0 => 0.5,
+        _ => 0.0,
+    }
+}
+
+impl Book {
+    fn canonical_moves(&self, canonical: &Board) -> Option<Vec<BookMove>> {
+        if let Some(moves) = self.entries.get(canonical) {
+            return Some(moves.clone());
+        }
+        match self.file.as_ref()?.lookup(canonical) {
+            Ok(moves) => moves,
+            Err(e) => {
+                log!("Failed in reading book ({}).", e);
+                None
+            }
+        }
+    }
+
+    pub fn contains(&self, board: &Board) -> bool {
+        self.canonical_moves(&canonical_board(board).0).is_some()
+    }
+
+    // Known moves of `board` in its own orientation.
+    pub fn moves(&self, board: &Board) -> Vec<BookMove> {
+        let (canonical, k) = canonical_board(board);
+        self.canonical_moves(&canonical).map_or(vec![], |moves| {
+            moves
+                .into_iter()
+                .map(|m| BookMove {
+                    pos: inverse_symmetric_pos(m.pos, k),
+                    ..m
+                })
+                .collect()
+        })
+    }
+
+    // Drops moves played in fewer than `min_games` games or valued more than
+    // `window` below the best move, and positions left without moves.
+    pub fn prune(&mut self, min_games: u32, window: f32) {
+        for moves in self.entries.values_mut() {
+            let best = moves.iter().filter_map(|m| m.value).reduce(f32::max);
+            moves.retain(|m| {
+                m.count >= min_games
+                    && match (best, m.value) {
+                        (Some(best), Some(value)) => value >= best - window,
+                        _ => true,
+                    }
+            });
+        }
+        self.entries.retain(|_, moves| !moves.is_empty());
+    }
+
+    // Adds `count` games of `pos` at `board`, `wins` of them won by the side
+    // to move. `value` replaces the stored one when given.
+    pub fn add_move(&mut self, board: &Board, pos: u64, value: Option<f32>, count: u32, wins: f32) {
+        let (canonical, pos) = canonical_move(board, pos);
+        // A position of the binary book is copied first, so that its other
+        // moves are not hidden.
+        if !self.entries.contains_key(&canonical) {
+            if let Some(moves) = self.canonical_moves(&canonical) {
+                self.entries.insert(canonical, moves);
+            }
+        }
+        let moves = self.entries.entry(canonical).or_default();
+        let index = match moves.iter().position(|m| m.pos == pos) {
+            Some(index) => index,
+            None => {
+                moves.push(BookMove {
+                    pos,
+                    value: None,
+                    count: 0,
+                    wins: 0.0,
+                });
+                moves.len() - 1
+            }
+        };
+        let book_move = &mut moves[index];
+        if value.is_some() {
+            book_move.value = value;
+        }
+        book_move.count += count;
+        book_move.wins += wins;
+    }
+
+    // Adds the last move of `moves` at the position the others reach.
+    // `result` is the final disc difference from black's point of view.
+    pub fn add_line(&mut self, moves: &[u64], value: Option<f32>, result: Option<i32>) {
+        let Some((&last, line)) = moves.split_last() else {
+            return;
+        };
+        let board = line_board(line);
+        match result {
+            Some(result) => {
+                let score = if board.turn == BLACK { result } else { -result };
+                self.add_move(&board, last, value, 1, score_wins(score));
+            }
+            None => self.add_move(&board, last, value, 0, 0.0),
+        }
+    }
+
+    // Picks one of the moves within `margin` of the best value, weighted by
+    // its win rate, an unplayed move counting as half a win in one game.
+    // Moves without a value are only candidates when no move of the position
+    // has one.
+    pub fn probe(&self, board: &Board) -> Option<u64> {
+        let moves = self.moves(board);
+        let best = moves.iter().filter_map(|m| m.value).reduce(f32::max);
+        let candidates: Vec<&BookMove> = moves
+            .iter()
+            .filter(|m| match (best, m.value) {
+                (Some(best), Some(value)) => value >= best - self.margin,
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .collect();
+        candidates
+            .choose_weighted(&mut rand::thread_rng(), |m| {
+                (m.wins + 0.5) / (m.count as f32 + 1.0)
+            })
+            .ok()
+            .map(|m| m.pos)
+    }
+}
+
+// Plays `line` from the start position, passing where needed.
+pub fn line_board(line: &[u64]) -> Board {
+    let mut board = cmds_to_board("");
+    for &pos in line {
+        board = execute_pos(&mut board, pos);
+        if legal(board) == 0 {
+            board.turn = !board.turn;
+        }
+    }
+    board
+}
+
+// One line of a book file.
+struct BookLine {
+    moves: Vec<u64>,
+    value: Option<f32>,
+    count: u32,
+    wins: f32,
+}
+
+// Parses a book line: the moves, where the last one is the answer for the
+// position reached by the others, optionally followed by its value for the
+// side to move (`-` when unknown), the number of games and the games won,
+// e.g. `F5D6C3 -1.5 12 5.5`.
+fn parse_book_line(line: &str) -> Result<BookLine, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let cmds = fields.first().copied().unwrap_or("");
+    if cmds.is_empty() || !cmds.len().is_multiple_of(2) || fields.len() > 4 {
+        return Err(format!("invalid line \"{}\"", line));
+    }
+    let moves: Vec<u64> = (0..cmds.len() / 2)
+        .map(|i| cmd_to_pos(cmds[2 * i..2 * i + 2].to_string()))
+        .collect();
+    let invalid = |field: &str| format!("invalid number \"{}\"", field);
+    let value = match fields.get(1) {
+        Some(&"-") | None => None,
+        Some(field) => Some(field.parse().map_err(|_| invalid(field))?),
+    };
+    let count = match fields.get(2) {
+        Some(field) => field.parse().map_err(|_| invalid(field))?,
+        None => 0,
+    };
+    let wins = match fields.get(3) {
+        Some(field) => field.parse().map_err(|_| invalid(field))?,
+        None => 0.0,
+    };
+    Ok(BookLine {
+        moves,
+        value,
+        count,
+        wins,
+    })
+}
+
+// Reads book lines such as `F5D6C3` or `F5D6C3 -1.5 12 5.5`. A `.wtb`
+// database is read as every prefix of the first `WTHOR_BOOK_DEPTH` plies of
+// each game, counting the game results. A binary book is only opened and
+// looked up when it is probed.
+pub fn create_book(path: &str, book: &mut Book) {
+    if is_book_file(path) {
+        match BookFile::open(path) {
+            Ok(file) => {
+                log!(
+                    "Book has {} positions and {} moves.",
+                    file.positions,
+                    file.moves
+                );
+                book.file = Some(file);
+            }
+            Err(e) => log!("Failed in opening file ({}).", e),
+        }
+        return;
+    }
+    if is_wtb(path) {
+        match read_wtb(path) {
+            Err(e) => log!("Failed in opening file ({}).", e),
+            Ok(base) => {
+                log!("Success in opening file.");
+                for game in &base.games {
+                    for ply in 1..=game.moves.len().min(WTHOR_BOOK_DEPTH) {
+                        book.add_line(&game.moves[..ply], None, Some(game.disc_diff()));
+                    }
+                }
+            }
+        }
+        return;
+    }
+    match fs::read_to_string(path) {
+        Err(e) => log!("Failed in opening file ({}).", e),
+        Ok(text) => {
+            log!("Success in opening file.");
+            for (i, line) in text.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match parse_book_line(line) {
+                    Ok(BookLine {
+                        moves,
+                        value,
+                        count,
+                        wins,
+                    }) => {
+                        let (&last, line) = moves.split_last().unwrap();
+                        book.add_move(&line_board(line), last, value, count, wins);
+                    }
+                    Err(e) => log!("{}:{}: {}", path, i + 1, e),
+                }
+            }
+        }
+    }
+}
+
+// Writes every move reachable from the start position as a book line,
+// visiting each position once. Lines may pass through one position that is
+// not in the book, like the opponent's replies of a hand-written book.
+pub fn save_book(path: &str, book: &Book) -> Result<usize, String> {
+    let mut text = String::new();
+    let mut visited: HashSet<Board> = HashSet::new();
+    let mut stack: Vec<Vec<u64>> = vec![vec![]];
+    while let Some(line) = stack.pop() {
+        let board = line_board(&line);
+        if !visited.insert(canonical_board(&board).0) {
+            continue;
+        }
+        let moves = book.moves(&board);
+        let cmds: String = line.iter().map(pos_to_cmd).collect();
+        for book_move in &moves {
+            text += &format!("{}{}", cmds, pos_to_cmd(&book_move.pos));
+            if let Some(value) = book_move.value {
+                // `+ 0.0` writes -0 as 0.
+                text += &format!(" {}", value + 0.0);
+            } else if book_move.count > 0 {
+                text += " -";
+            }
+            if book_move.count > 0 {
+                text += &format!(" {} {}", book_move.count, book_move.wins);
+            }
+            text += "\n";
+        }
+        let children: Vec<u64> = if moves.is_empty() {
+            legal_poss(&board)
+        } else {
+            moves.iter().map(|m| m.pos).collect()
+        };
+        for pos in children {
+            let mut next = line.clone();
+            next.push(pos);
+            let next_board = line_board(&next);
+            if book.contains(&next_board) || (!moves.is_empty() && legal(next_board) != 0) {
+                stack.push(next);
+            }
+        }
+    }
+    let positions = visited
+        .iter()
+        .filter(|b| book.entries.contains_key(b))
+        .count();
+    fs::write(path, text).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(positions)
+}
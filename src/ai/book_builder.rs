@@ -0,0 +1,224 @@
+use crate::ai::ai::*;
+use crate::ai::book::*;
+use crate::ai::book_file::*;
+use crate::learning::learning::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+// Builds a book by drop-out expansion: every book position holds the search
+// values of all its moves, values are backed up by minimax, and the next
+// position to expand is the unexpanded one with the smallest drop, i.e. the
+// sum of `best value - move value` along its line, as long as the drop stays
+// within `window`.
+
+#[derive(Clone, Debug)]
+pub struct BookBuildOption {
+    // Book to start from, empty to start from the initial position only.
+    pub input: String,
+    // An existing file is only replaced when it is the input book.
+    pub output: String,
+    // Search depth used to evaluate the moves of an expanded position.
+    pub depth: i32,
+    // Positions to expand.
+    pub nodes: usize,
+    // Largest drop, in evaluation units, of a line that is still expanded.
+    pub window: f32,
+    // Positions deeper than this many plies are not expanded.
+    pub max_ply: usize,
+    pub threads: usize,
+}
+
+impl Default for BookBuildOption {
+    fn default() -> BookBuildOption {
+        BookBuildOption {
+            input: String::new(),
+            output: "built_book.txt".to_string(),
+            depth: 8,
+            nodes: 200,
+            window: 4.0,
+            max_ply: 20,
+            threads: 0,
+        }
+    }
+}
+
+impl BookBuildOption {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "input" => self.input = value.to_string(),
+            "output" => self.output = value.to_string(),
+            "depth" => self.depth = option_value(key, value)?,
+            "nodes" => self.nodes = option_value(key, value)?,
+            "window" => self.window = option_value(key, value)?,
+            "max_ply" => self.max_ply = option_value(key, value)?,
+            "threads" => self.threads = option_value(key, value)?,
+            _ => return Err(format!("unknown option: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+// Plays `pos`, passing when the opponent has no move.
+pub fn play(board: &Board, pos: u64) -> Board {
+    let mut child = *board;
+    child = execute_pos(&mut child, pos);
+    if legal(child) == 0 {
+        child.turn = !child.turn;
+    }
+    child
+}
+
+// A value of `child` seen from the side to move at `board`.
+pub fn from_parent(board: &Board, child: &Board, value: f32) -> f32 {
+    if child.turn == board.turn {
+        value
+    } else {
+        -value
+    }
+}
+
+// Searches every move of `board` and stores the values.
+pub fn expand(book: &mut Book, board: &Board, option: &BookBuildOption) {
+    let poss = legal_poss(board);
+    let values = parallel_map(&poss, thread_count(option.threads), |&pos| {
+        let child = play(board, pos);
+        from_parent(board, &child, search_score(&child, option.depth - 1) as f32)
+    });
+    for (&pos, &value) in poss.iter().zip(values.iter()) {
+        book.add_move(board, pos, Some(value), 0, 0.0);
+    }
+}
+
+// Minimax value of `board`, storing the backed up value of every move whose
+// position is in the book. `None` when no move has a value yet.
+pub fn backup(
+    book: &mut Book,
+    board: &Board,
+    memo: &mut HashMap<Board, Option<f32>>,
+) -> Option<f32> {
+    let key = canonical_board(board).0;
+    if let Some(&value) = memo.get(&key) {
+        return value;
+    }
+    let mut best: Option<f32> = None;
+    for book_move in book.moves(board) {
+        let child = play(board, book_move.pos);
+        let value = match backup(book, &child, memo) {
+            Some(child_value) => {
+                let value = from_parent(board, &child, child_value);
+                book.add_move(board, book_move.pos, Some(value), 0, 0.0);
+                Some(value)
+            }
+            None => book_move.value,
+        };
+        best = match (best, value) {
+            (Some(best), Some(value)) => Some(best.max(value)),
+            (best, value) => best.or(value),
+        };
+    }
+    memo.insert(key, best);
+    best
+}
+
+// The unexpanded position with the smallest drop, then the smallest ply.
+fn select_leaf(book: &Book, option: &BookBuildOption) -> Option<(Vec<u64>, f32)> {
+    let mut visited: HashSet<Board> = HashSet::new();
+    let mut leaves: Vec<(Vec<u64>, f32)> = vec![];
+    let mut stack: Vec<(Vec<u64>, f32)> = vec![(vec![], 0.0)];
+    while let Some((line, drop)) = stack.pop() {
+        let board = line_board(&line);
+        if !visited.insert(canonical_board(&board).0) || legal(board) == 0 {
+            continue;
+        }
+        let moves = book.moves(&board);
+        if moves.iter().all(|m| m.value.is_none()) {
+            leaves.push((line, drop));
+            continue;
+        }
+        if line.len() >= option.max_ply {
+            continue;
+        }
+        let best = moves
+            .iter()
+            .filter_map(|m| m.value)
+            .reduce(f32::max)
+            .unwrap();
+        for book_move in &moves {
+            let Some(value) = book_move.value else {
+                continue;
+            };
+            let child_drop = drop + best - value;
+            if child_drop <= option.window {
+                let mut next = line.clone();
+                next.push(book_move.pos);
+                stack.push((next, child_drop));
+            }
+        }
+    }
+    leaves.into_iter().min_by(|a, b| {
+        a.1.partial_cmp(&b.1)
+            .unwrap_or(Ordering::Equal)
+            .then(a.0.len().cmp(&b.0.len()))
+    })
+}
+
+pub fn build_book(option: &BookBuildOption) -> Result<Book, String> {
+    let mut book = if option.input.is_empty() {
+        Book::default()
+    } else {
+        load_book(&option.input)?
+    };
+    set_search_log(false);
+    // Positions of the input book without values are searched first.
+    let unvalued: Vec<Board> = book
+        .entries
+        .iter()
+        .filter(|(_, moves)| moves.iter().any(|m| m.value.is_none()))
+        .map(|(board, _)| *board)
+        .collect();
+    for board in &unvalued {
+        expand(&mut book, board, option);
+    }
+    let root = cmds_to_board("");
+    for i in 0..option.nodes {
+        backup(&mut book, &root, &mut HashMap::new());
+        let Some((line, drop)) = select_leaf(&book, option) else {
+            println!("No position is left within the window.");
+            break;
+        };
+        expand(&mut book, &line_board(&line), option);
+        let cmds: String = line.iter().map(pos_to_cmd).collect();
+        println!(
+            "node: {} / {}  line: {}  drop: {}",
+            i + 1,
+            option.nodes,
+            if cmds.is_empty() { "-" } else { &cmds },
+            drop
+        );
+    }
+    let value = backup(&mut book, &root, &mut HashMap::new());
+    set_search_log(true);
+    println!(
+        "positions: {}  value of the initial position: {:?}",
+        book.entries.len(),
+        value
+    );
+    Ok(book)
+}
+
+pub fn build_book_file(option: &BookBuildOption) {
+    if option.output != option.input && Path::new(&option.output).exists() {
+        println!(
+            "{} already exists; pass it as input to extend it or choose another output",
+            option.output
+        );
+        return;
+    }
+    match build_book(option).and_then(|book| write_book(&option.output, &book)) {
+        Ok(positions) => println!("Saved {} positions to {}.", positions, option.output),
+        Err(e) => println!("{}", e),
+    }
+}
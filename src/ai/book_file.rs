@@ -0,0 +1,490 @@
+use crate::ai::book::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/*
+
+binary book (little endian)
+
+"RVBK"  version u32
+positions u64  moves u64
+positions, sorted by hash:
+    hash u64  black u64  white u64  turn u8 (1 = black to move)
+    first move u64  move count u8
+moves, in the orientation of the canonical board:
+    square u8 (0-63)  value f32 (NaN when unknown)  count u32  wins f32
+
+`hash` is the Zobrist hash of the canonical board, so a position is found
+by a binary search over the fixed-size position records without reading
+the whole file. This index takes the place of a memory map: a lookup reads
+about log2(positions) records through one open file.
+
+*/
+
+const BOOK_MAGIC: &[u8; 4] = b"RVBK";
+const BOOK_VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 24;
+const POSITION_SIZE: u64 = 34;
+const MOVE_SIZE: u64 = 13;
+
+const fn splitmix64(state: u64) -> (u64, u64) {
+    let state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    (state, z ^ (z >> 31))
+}
+
+// Fixed keys for each square and colour, then one for black to move. They
+// are part of the file format and must not change.
+const fn zobrist_keys() -> [u64; 129] {
+    let mut keys = [0; 129];
+    let mut state = 0x5265766572736921;
+    let mut i = 0;
+    while i < 129 {
+        let (next, key) = splitmix64(state);
+        state = next;
+        keys[i] = key;
+        i += 1;
+    }
+    keys
+}
+
+const ZOBRIST_KEYS: [u64; 129] = zobrist_keys();
+
+pub fn zobrist_hash(board: &Board) -> u64 {
+    let mut hash = if board.turn == BLACK {
+        ZOBRIST_KEYS[128]
+    } else {
+        0
+    };
+    for (discs, offset) in [(board.black_board, 0), (board.white_board, 64)] {
+        let mut rest = discs;
+        while rest != 0 {
+            hash ^= ZOBRIST_KEYS[offset + rest.trailing_zeros() as usize];
+            rest &= rest - 1;
+        }
+    }
+    hash
+}
+
+pub fn is_book_file(path: &str) -> bool {
+    let mut magic = [0; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && &magic == BOOK_MAGIC
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_f32(bytes: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+struct PositionRecord {
+    hash: u64,
+    board: Board,
+    first: u64,
+    count: u8,
+}
+
+fn parse_position(bytes: &[u8]) -> PositionRecord {
+    PositionRecord {
+        hash: read_u64(bytes, 0),
+        board: Board::new(read_u64(bytes, 8), read_u64(bytes, 16), bytes[24] != 0),
+        first: read_u64(bytes, 25),
+        count: bytes[33],
+    }
+}
+
+fn parse_move(bytes: &[u8]) -> io::Result<BookMove> {
+    if bytes[0] >= 64 {
+        return Err(invalid("book move square out of range"));
+    }
+    let value = read_f32(bytes, 1);
+    Ok(BookMove {
+        pos: 1 << bytes[0],
+        value: if value.is_nan() { None } else { Some(value) },
+        count: u32::from_le_bytes(bytes[5..9].try_into().unwrap()),
+        wins: read_f32(bytes, 9),
+    })
+}
+
+// An opened binary book. Only the header is read when it is opened; the
+// file stays open for lookups and clones share it.
+#[derive(Clone, Debug)]
+pub struct BookFile {
+    pub path: String,
+    pub positions: u64,
+    pub moves: u64,
+    file: Arc<Mutex<File>>,
+}
+
+impl BookFile {
+    pub fn open(path: &str) -> io::Result<BookFile> {
+        let mut file = File::open(path)?;
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        if &header[..4] != BOOK_MAGIC {
+            return Err(invalid("not a binary book"));
+        }
+        if u32::from_le_bytes(header[4..8].try_into().unwrap()) != BOOK_VERSION {
+            return Err(invalid("unsupported book version"));
+        }
+        let positions = read_u64(&header, 8);
+        let moves = read_u64(&header, 16);
+        if file.metadata()?.len() != HEADER_SIZE + positions * POSITION_SIZE + moves * MOVE_SIZE {
+            return Err(invalid("file size does not match the header"));
+        }
+        Ok(BookFile {
+            path: path.to_string(),
+            positions,
+            moves,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    // Fails unless the moves of `record` lie within the move section.
+    fn check_moves(&self, record: &PositionRecord) -> io::Result<()> {
+        match record.first.checked_add(record.count as u64) {
+            Some(end) if end <= self.moves => Ok(()),
+            _ => Err(invalid("book moves out of range")),
+        }
+    }
+
+    fn read_position(&self, file: &mut File, index: u64) -> io::Result<PositionRecord> {
+        let mut bytes = [0; POSITION_SIZE as usize];
+        file.seek(SeekFrom::Start(HEADER_SIZE + index * POSITION_SIZE))?;
+        file.read_exact(&mut bytes)?;
+        Ok(parse_position(&bytes))
+    }
+
+    fn read_moves(&self, file: &mut File, record: &PositionRecord) -> io::Result<Vec<BookMove>> {
+        self.check_moves(record)?;
+        let offset = HEADER_SIZE + self.positions * POSITION_SIZE + record.first * MOVE_SIZE;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; record.count as usize * MOVE_SIZE as usize];
+        file.read_exact(&mut bytes)?;
+        bytes.chunks(MOVE_SIZE as usize).map(parse_move).collect()
+    }
+
+    // Moves of a canonical board, in its orientation.
+    pub fn lookup(&self, canonical: &Board) -> io::Result<Option<Vec<BookMove>>> {
+        let hash = zobrist_hash(canonical);
+        let file = &mut *self.file.lock().unwrap();
+        // The first position whose hash is not below `hash`.
+        let (mut low, mut high) = (0, self.positions);
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.read_position(file, middle)?.hash < hash {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        // Positions that collide on the hash are next to each other.
+        for index in low..self.positions {
+            let record = self.read_position(file, index)?;
+            if record.hash != hash {
+                break;
+            }
+            if record.board == *canonical {
+                return Ok(Some(self.read_moves(file, &record)?));
+            }
+        }
+        Ok(None)
+    }
+
+    // Reads every position, for merging, pruning and exporting.
+    pub fn read_all(&self) -> io::Result<Book> {
+        let bytes = fs::read(&self.path)?;
+        let moves_start = (HEADER_SIZE + self.positions * POSITION_SIZE) as usize;
+        let mut book = Book::default();
+        for i in 0..self.positions as usize {
+            let start = HEADER_SIZE as usize + i * POSITION_SIZE as usize;
+            let record = parse_position(&bytes[start..start + POSITION_SIZE as usize]);
+            self.check_moves(&record)?;
+            let first = moves_start + record.first as usize * MOVE_SIZE as usize;
+            let moves = bytes[first..first + record.count as usize * MOVE_SIZE as usize]
+                .chunks(MOVE_SIZE as usize)
+                .map(parse_move)
+                .collect::<io::Result<_>>()?;
+            book.entries.insert(record.board, moves);
+        }
+        Ok(book)
+    }
+}
+
+// Writes the positions held in memory, sorted by hash. Positions that only
+// live in `book.file` are not written.
+pub fn write_book_file(path: &str, book: &Book) -> io::Result<usize> {
+    let mut positions: Vec<(u64, &Board, &Vec<BookMove>)> = book
+        .entries
+        .iter()
+        .filter(|(_, moves)| !moves.is_empty())
+        .map(|(board, moves)| (zobrist_hash(board), board, moves))
+        .collect();
+    positions.sort_unstable_by_key(|(hash, _, _)| *hash);
+    let moves: usize = positions.iter().map(|(_, _, moves)| moves.len()).sum();
+
+    write_atomically(path, |file| {
+        file.write_all(BOOK_MAGIC)?;
+        file.write_all(&BOOK_VERSION.to_le_bytes())?;
+        file.write_all(&(positions.len() as u64).to_le_bytes())?;
+        file.write_all(&(moves as u64).to_le_bytes())?;
+        let mut first = 0u64;
+        for (hash, board, moves) in &positions {
+            file.write_all(&hash.to_le_bytes())?;
+            file.write_all(&board.black_board.to_le_bytes())?;
+            file.write_all(&board.white_board.to_le_bytes())?;
+            file.write_all(&[board.turn as u8])?;
+            file.write_all(&first.to_le_bytes())?;
+            file.write_all(&[moves.len() as u8])?;
+            first += moves.len() as u64;
+        }
+        for (_, _, moves) in &positions {
+            for book_move in moves.iter() {
+                file.write_all(&[book_move.pos.trailing_zeros() as u8])?;
+                file.write_all(&book_move.value.unwrap_or(f32::NAN).to_le_bytes())?;
+                file.write_all(&book_move.count.to_le_bytes())?;
+                file.write_all(&book_move.wins.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(positions.len())
+}
+
+// Binary books end in `.book`; anything else is written as text lines.
+pub fn is_binary_book_path(path: &str) -> bool {
+    path.to_lowercase().ends_with(".book")
+}
+
+// Reads a whole book of any format into memory.
+pub fn load_book(path: &str) -> Result<Book, String> {
+    if is_book_file(path) {
+        return BookFile::open(path)
+            .and_then(|file| file.read_all())
+            .map_err(|e| format!("{}: {}", path, e));
+    }
+    if !Path::new(path).is_file() {
+        return Err(format!("{}: no such file", path));
+    }
+    let mut book = Book::default();
+    create_book(path, &mut book);
+    Ok(book)
+}
+
+pub fn write_book(path: &str, book: &Book) -> Result<usize, String> {
+    if is_binary_book_path(path) {
+        write_book_file(path, book).map_err(|e| format!("{}: {}", path, e))
+    } else {
+        save_book(path, book)
+    }
+}
+
+// Converts between book formats by the extension of `output`. Text books
+// keep only the positions reachable from the start position.
+pub fn convert_book(input: &str, output: &str) {
+    match load_book(input).and_then(|book| write_book(output, &book)) {
+        Ok(positions) => println!("Wrote {} positions to {}.", positions, output),
+        Err(e) => println!("{}", e),
+    }
+}
+
+// Adds the books up: game counts and wins are summed and later values
+// replace earlier ones.
+pub fn merge_books(inputs: &[String], output: &str) {
+    let mut merged = Book::default();
+    for input in inputs {
+        match load_book(input) {
+            Ok(book) => {
+                for (board, moves) in &book.entries {
+                    for m in moves {
+                        merged.add_move(board, m.pos, m.value, m.count, m.wins);
+                    }
+                }
+            }
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        }
+    }
+    match write_book(output, &merged) {
+        Ok(positions) => println!("Wrote {} positions to {}.", positions, output),
+        Err(e) => println!("{}", e),
+    }
+}
+
+// Takes `min_games=N` and `window=X`, see `Book::prune`.
+pub fn prune_book(input: &str, output: &str, options: &[String]) {
+    let (mut min_games, mut window) = (0, f32::INFINITY);
+    for option in options {
+        let (key, value) = option.split_once('=').unwrap_or((option, ""));
+        let parsed = match key {
+            "min_games" => value.parse().map(|v| min_games = v).is_ok(),
+            "window" => value.parse().map(|v| window = v).is_ok(),
+            _ => {
+                println!("unknown option: {}", key);
+                return;
+            }
+        };
+        if !parsed {
+            println!("invalid value for {}: {}", key, value);
+            return;
+        }
+    }
+    match load_book(input).and_then(|mut book| {
+        let before = book.entries.len();
+        book.prune(min_games, window);
+        println!("positions: {} -> {}", before, book.entries.len());
+        write_book(output, &book)
+    }) {
+        Ok(positions) => println!("Wrote {} positions to {}.", positions, output),
+        Err(e) => println!("{}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(cmd: &str) -> u64 {
+        cmd_to_pos(cmd.to_string())
+    }
+
+    fn sample_book() -> Book {
+        let mut book = Book::default();
+        let start = cmds_to_board("");
+        book.add_move(&start, pos("F5"), Some(0.5), 10, 5.5);
+        book.add_move(&start, pos("D3"), None, 2, 1.0);
+        let f5 = cmds_to_board("F5");
+        book.add_move(&f5, pos("D6"), Some(-1.0), 3, 1.0);
+        book.add_move(&f5, pos("F6"), None, 5, 2.0);
+        book.add_move(&cmds_to_board("F5D6"), pos("C3"), Some(0.25), 1, 0.5);
+        book
+    }
+
+    // A book file with the given position records and one move on a1 per
+    // move record.
+    fn raw_book(positions: &[(u64, Board, u64, u8)], moves: &[u8]) -> Vec<u8> {
+        let mut bytes = BOOK_MAGIC.to_vec();
+        bytes.extend(BOOK_VERSION.to_le_bytes());
+        bytes.extend((positions.len() as u64).to_le_bytes());
+        bytes.extend((moves.len() as u64).to_le_bytes());
+        for (hash, board, first, count) in positions {
+            bytes.extend(hash.to_le_bytes());
+            bytes.extend(board.black_board.to_le_bytes());
+            bytes.extend(board.white_board.to_le_bytes());
+            bytes.push(board.turn as u8);
+            bytes.extend(first.to_le_bytes());
+            bytes.push(*count);
+        }
+        for square in moves {
+            bytes.push(*square);
+            bytes.extend(1.0f32.to_le_bytes());
+            bytes.extend(1u32.to_le_bytes());
+            bytes.extend(0.5f32.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn lookup_finds_written_positions_only() {
+        let path = temp_path("lookup.book");
+        let book = sample_book();
+        assert_eq!(write_book(&path, &book), Ok(book.entries.len()));
+
+        let file = BookFile::open(&path).unwrap();
+        // A clone looks up through the same open file.
+        let clone = file.clone();
+        let (missing, _) = canonical_board(&cmds_to_board("F5D6C3"));
+        for file in [&file, &clone] {
+            for (board, moves) in &book.entries {
+                assert_eq!(file.lookup(board).unwrap().as_ref(), Some(moves));
+            }
+            assert_eq!(file.lookup(&missing).unwrap(), None);
+        }
+        let read = file.read_all().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(read.entries == book.entries);
+    }
+
+    #[test]
+    fn merge_and_prune() {
+        let input = temp_path("merge_input.book");
+        let merged = temp_path("merged.book");
+        let pruned = temp_path("pruned.book");
+        write_book(&input, &sample_book()).unwrap();
+        merge_books(&[input.clone(), input.clone()], &merged);
+        prune_book(&merged, &pruned, &["min_games=7".to_string()]);
+
+        let merged_book = load_book(&merged).unwrap();
+        let pruned_book = load_book(&pruned).unwrap();
+        for path in [&input, &merged, &pruned] {
+            fs::remove_file(path).unwrap();
+        }
+        let counts = |book: &Book, cmds: &str| -> Vec<u32> {
+            book.moves(&cmds_to_board(cmds))
+                .iter()
+                .map(|m| m.count)
+                .collect()
+        };
+        // D3 is F5 under symmetry.
+        assert_eq!(counts(&merged_book, ""), vec![24]);
+        assert_eq!(merged_book.moves(&cmds_to_board(""))[0].wins, 13.0);
+        assert_eq!(counts(&merged_book, "F5"), vec![6, 10]);
+        assert_eq!(counts(&merged_book, "F5D6"), vec![2]);
+        assert_eq!(counts(&pruned_book, ""), vec![24]);
+        assert_eq!(counts(&pruned_book, "F5"), vec![10]);
+        assert!(!pruned_book.contains(&cmds_to_board("F5D6")));
+    }
+
+    #[test]
+    fn colliding_hashes_are_all_searched() {
+        let path = temp_path("collision.book");
+        let (first, _) = canonical_board(&cmds_to_board("F5"));
+        let (second, _) = canonical_board(&cmds_to_board("F5D6"));
+        let hash = zobrist_hash(&second);
+        let positions = [(hash, first, 0, 1), (hash, second, 1, 1)];
+        fs::write(&path, raw_book(&positions, &[0, 1])).unwrap();
+
+        let file = BookFile::open(&path).unwrap();
+        let found = file.lookup(&second).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(found.map(|moves| moves[0].pos), Some(1 << 1));
+    }
+
+    #[test]
+    fn moves_out_of_range_are_rejected() {
+        let path = temp_path("out_of_range.book");
+        let (board, _) = canonical_board(&cmds_to_board(""));
+        let hash = zobrist_hash(&board);
+        let mut errors = vec![];
+        // A square past h8, then a move record past the end of the file.
+        for (position, moves) in [((hash, board, 0, 1), [64]), ((hash, board, 1, 1), [0])] {
+            fs::write(&path, raw_book(&[position], &moves)).unwrap();
+            let file = BookFile::open(&path).unwrap();
+            errors.push(file.lookup(&board).err().unwrap());
+            errors.push(file.read_all().err().unwrap());
+        }
+        fs::remove_file(&path).unwrap();
+        for error in errors {
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use crate::ai::ai::*;
+use crate::ai::book::*;
+use crate::ai::book_builder::*;
+use crate::ai::book_file::*;
+use crate::record::record::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+// Learns from the client's own games. Every book move of a finished game
+// gets the game counted, so a lost game lowers the win rate `probe` weights
+// the move by. When the engine lost, its last book move that had an
+// alternative (the deviation) is valued no better than the final score, the
+// lower value is backed up along the game, and the line is queued so that
+// the positions after the deviation can be searched again offline.
+//
+// Learned positions are not written into the book the client was given but
+// into a binary book next to it, whose positions replace the book's ones
+// when both are loaded.
+
+// Lines to search again, one `moves ply` per line where `ply` is the index
+// of the deviation in `moves`.
+pub const LEARN_QUEUE_PATH: &str = "book_learn.txt";
+
+pub struct Lesson {
+    // Canonical positions of the plies that were in the book.
+    pub boards: Vec<Board>,
+    // Book moves up to the first position out of the book and the ply of the
+    // deviation, when the game was lost.
+    pub losing_line: Option<(Vec<u64>, usize)>,
+}
+
+// Value of `pos` at `board`, which may be stored as an equivalent move when
+// the position is symmetric.
+fn played_value(book: &Book, board: &Board, pos: u64) -> Option<f32> {
+    let played = canonical_move(board, pos).1;
+    book.moves(board)
+        .into_iter()
+        .find(|m| canonical_move(board, m.pos).1 == played)
+        .and_then(|m| m.value)
+}
+
+pub fn learn_game(book: &mut Book, record: &GameRecord, own_turn: bool) -> Lesson {
+    let mut lesson = Lesson {
+        boards: vec![],
+        losing_line: None,
+    };
+    let Some(result) = record.result else {
+        return lesson;
+    };
+    let mut boards: Vec<Board> = vec![];
+    let mut line: Vec<u64> = vec![];
+    let mut board = cmds_to_board("");
+    for pos in record.moves.iter().map(|m| m.pos).filter(|&pos| pos != 0) {
+        if !book.contains(&board) {
+            break;
+        }
+        let score = if board.turn == BLACK { result } else { -result };
+        book.add_move(&board, pos, None, 1, score_wins(score));
+        boards.push(board);
+        line.push(pos);
+        board = play(&board, pos);
+    }
+    lesson.boards = boards.iter().map(|b| canonical_board(b).0).collect();
+    let own_score = if own_turn == BLACK { result } else { -result };
+    if own_score >= 0 || line.is_empty() {
+        return lesson;
+    }
+
+    let deviation = (0..line.len())
+        .rev()
+        .find(|&i| boards[i].turn == own_turn && book.moves(&boards[i]).len() > 1);
+    if let Some(deviation) = deviation {
+        // Moves without values are left alone, since a valued move would
+        // become the only one the book plays.
+        if let Some(value) = played_value(book, &boards[deviation], line[deviation]) {
+            let value = value.min(discs_to_score(own_score) as f32);
+            book.add_move(&boards[deviation], line[deviation], Some(value), 0, 0.0);
+            for i in (0..deviation).rev() {
+                let best = book
+                    .moves(&boards[i + 1])
+                    .iter()
+                    .filter_map(|m| m.value)
+                    .reduce(f32::max);
+                if let (Some(best), Some(_)) = (best, played_value(book, &boards[i], line[i])) {
+                    let value = from_parent(&boards[i], &boards[i + 1], best);
+                    book.add_move(&boards[i], line[i], Some(value), 0, 0.0);
+                }
+            }
+        }
+    }
+    lesson.losing_line = Some((line, deviation.unwrap_or(0)));
+    lesson
+}
+
+// `book.txt` learns into `book_learned.book`.
+pub fn learned_book_path(book_path: &str) -> String {
+    let path = Path::new(book_path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}_learned.book", stem))
+        .to_string_lossy()
+        .to_string()
+}
+
+// Puts the learned positions of `book_path`, if there are any, over `book`
+// and returns them.
+pub fn load_learned_book(book_path: &str, book: &mut Book) -> Result<Book, String> {
+    let path = learned_book_path(book_path);
+    if !Path::new(&path).is_file() {
+        return Ok(Book::default());
+    }
+    let learned = BookFile::open(&path)
+        .and_then(|file| file.read_all())
+        .map_err(|e| format!("{}: {}", path, e))?;
+    for (board, moves) in &learned.entries {
+        book.entries.insert(*board, moves.clone());
+    }
+    Ok(learned)
+}
+
+fn append_learn_queue(path: &str, line: &[u64], deviation: usize) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let cmds: String = line.iter().map(pos_to_cmd).collect();
+    writeln!(file, "{} {}", cmds, deviation)
+}
+
+// Called by the client at the end of each game, after the result is set.
+// `learned` holds the positions learned so far, which are saved again.
+pub fn learn_network_game(
+    book: &mut Book,
+    learned: &mut Book,
+    book_path: &str,
+    record: &GameRecord,
+    own_turn: bool,
+) {
+    if book.entries.is_empty() && book.file.is_none() {
+        return;
+    }
+    let lesson = learn_game(book, record, own_turn);
+    if lesson.boards.is_empty() {
+        return;
+    }
+    log!("Learned {} book plies of the game.", lesson.boards.len());
+    for board in &lesson.boards {
+        if let Some(moves) = book.entries.get(board) {
+            learned.entries.insert(*board, moves.clone());
+        }
+    }
+    let path = learned_book_path(book_path);
+    if let Err(e) = write_book_file(&path, learned) {
+        log!("Failed in saving learned book ({}: {}).", path, e);
+    }
+    if let Some((line, deviation)) = lesson.losing_line {
+        match append_learn_queue(LEARN_QUEUE_PATH, &line, deviation) {
+            Ok(()) => log!("Losing line is queued in {}.", LEARN_QUEUE_PATH),
+            Err(e) => log!("Failed in queueing line ({}).", e),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BookLearnOption {
+    pub book: String,
+    // The whole book is written here when given; otherwise the changed
+    // positions go to the learned book of `book`.
+    pub output: String,
+    pub queue: String,
+    pub depth: i32,
+    pub threads: usize,
+}
+
+impl Default for BookLearnOption {
+    fn default() -> BookLearnOption {
+        BookLearnOption {
+            book: "book.txt".to_string(),
+            output: String::new(),
+            queue: LEARN_QUEUE_PATH.to_string(),
+            depth: 8,
+            threads: 0,
+        }
+    }
+}
+
+impl BookLearnOption {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "book" => self.book = value.to_string(),
+            "output" => self.output = value.to_string(),
+            "queue" => self.queue = value.to_string(),
+            "depth" => self.depth = option_value(key, value)?,
+            "threads" => self.threads = option_value(key, value)?,
+            _ => return Err(format!("unknown option: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+fn parse_queue_line(line: &str) -> Result<(Vec<u64>, usize), String> {
+    let invalid = || format!("invalid line \"{}\"", line);
+    let (cmds, ply) = line.split_once(' ').ok_or_else(invalid)?;
+    if !cmds.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    let moves = (0..cmds.len() / 2)
+        .map(|i| cmd_to_pos(cmds[2 * i..2 * i + 2].to_string()))
+        .collect();
+    Ok((moves, ply.trim().parse().map_err(|_| invalid())?))
+}
+
+// Searches the positions after each queued deviation, up to the first one
+// out of the book, backs the values up and empties the queue.
+pub fn learn_book(option: &BookLearnOption) -> Result<(), String> {
+    let text = fs::read_to_string(&option.queue).map_err(|e| format!("{}: {}", option.queue, e))?;
+    let mut book = load_book(&option.book)?;
+    let mut learned = load_learned_book(&option.book, &mut book)?;
+    let before = book.entries.clone();
+    let build = BookBuildOption {
+        depth: option.depth,
+        threads: option.threads,
+        ..BookBuildOption::default()
+    };
+    set_search_log(false);
+    let mut searched = 0;
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (moves, deviation) = match parse_queue_line(line) {
+            Ok(queued) => queued,
+            Err(e) => {
+                println!("{}:{}: {}", option.queue, i + 1, e);
+                continue;
+            }
+        };
+        for ply in deviation + 1..=moves.len() {
+            let board = line_board(&moves[..ply]);
+            if legal(board) != 0 {
+                expand(&mut book, &board, &build);
+                searched += 1;
+            }
+        }
+        println!("line: {}  searched positions: {}", line, searched);
+    }
+    let value = backup(&mut book, &cmds_to_board(""), &mut HashMap::new());
+    set_search_log(true);
+    println!("value of the initial position: {:?}", value);
+    if option.output.is_empty() {
+        for (board, moves) in &book.entries {
+            if before.get(board) != Some(moves) {
+                learned.entries.insert(*board, moves.clone());
+            }
+        }
+        let path = learned_book_path(&option.book);
+        let positions = write_book_file(&path, &learned).map_err(|e| format!("{}: {}", path, e))?;
+        println!("Saved {} learned positions to {}.", positions, path);
+    } else {
+        let positions = write_book(&option.output, &book)?;
+        println!("Saved {} positions to {}.", positions, option.output);
+    }
+    fs::write(&option.queue, "").map_err(|e| format!("{}: {}", option.queue, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moves(cmds: &str) -> Vec<u64> {
+        (0..cmds.len() / 2)
+            .map(|i| cmd_to_pos(cmds[2 * i..2 * i + 2].to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn lost_game_is_learned_next_to_the_book() {
+        let mut book = Book::default();
+        let start = cmds_to_board("");
+        book.add_move(&start, moves("F5")[0], None, 0, 0.0);
+        let f5 = cmds_to_board("F5");
+        book.add_move(&f5, moves("D6")[0], None, 0, 0.0);
+        book.add_move(&f5, moves("F6")[0], None, 0, 0.0);
+        let f5d6 = cmds_to_board("F5D6");
+        book.add_move(&f5d6, moves("C3")[0], Some(2.0), 0, 0.0);
+        book.add_move(&f5d6, moves("C5")[0], Some(1.0), 0, 0.0);
+        let record = GameRecord {
+            moves: moves("F5D6C3D3")
+                .into_iter()
+                .map(|pos| GameMove { pos, time: None })
+                .collect(),
+            result: Some(-10),
+            ..GameRecord::default()
+        };
+
+        let lesson = learn_game(&mut book, &record, BLACK);
+        assert_eq!(lesson.boards.len(), 3);
+        assert_eq!(lesson.losing_line, Some((moves("F5D6C3"), 2)));
+        let c3 = &book.moves(&f5d6)[0];
+        assert_eq!((c3.value, c3.count, c3.wins), (Some(-10.0), 1, 0.0));
+        // Winning moves of the opponent are counted as won.
+        assert_eq!(book.moves(&f5)[0].wins, 1.0);
+
+        let book_path = temp_path("learn.txt");
+        let path = learned_book_path(&book_path);
+        assert!(path.ends_with("learn_learned.book"));
+        write_book_file(&path, &book).unwrap();
+        let mut base = Book::default();
+        base.add_move(&f5d6, moves("C3")[0], Some(2.0), 0, 0.0);
+        let learned = load_learned_book(&book_path, &mut base).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(learned.entries.len(), 3);
+        assert_eq!(base.moves(&f5d6), book.moves(&f5d6));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::ai::book::*;
+use crate::ai::book_file::*;
+use crate::learning::config::*;
+use crate::record::record::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use std::collections::HashMap;
+use std::fs;
+
+// Builds a book from game records: positions of the first `depth` plies are
+// merged under symmetry, and a move is kept when it was played in at least
+// `min_games` games with an average final score of at least `min_score`
+// discs for the side that played it.
+
+#[derive(Clone, Debug)]
+pub struct GameBookOption {
+    // Game files or glob patterns, anything `read_games` accepts. Required.
+    pub files: Vec<String>,
+    pub output: String,
+    // Readable summary of the main lines, empty to skip it.
+    pub report: String,
+    pub depth: usize,
+    pub min_games: u32,
+    pub min_score: f32,
+    // Moves with at least this share of a position's games are followed in
+    // the report, up to `report_depth` plies.
+    pub report_share: f32,
+    pub report_depth: usize,
+}
+
+impl Default for GameBookOption {
+    fn default() -> GameBookOption {
+        GameBookOption {
+            files: vec![],
+            output: "game_book.txt".to_string(),
+            report: "book_report.txt".to_string(),
+            depth: 20,
+            min_games: 5,
+            min_score: -4.0,
+            report_share: 0.1,
+            report_depth: 12,
+        }
+    }
+}
+
+impl GameBookOption {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "files" => {
+                self.files = value
+                    .split(',')
+                    .filter(|f| !f.is_empty())
+                    .map(|f| f.to_string())
+                    .collect()
+            }
+            "output" => self.output = value.to_string(),
+            "report" => self.report = value.to_string(),
+            "depth" => self.depth = option_value(key, value)?,
+            "min_games" => self.min_games = option_value(key, value)?,
+            "min_score" => self.min_score = option_value(key, value)?,
+            "report_share" => self.report_share = option_value(key, value)?,
+            "report_depth" => self.report_depth = option_value(key, value)?,
+            _ => return Err(format!("unknown option: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+// Statistics of one move at a canonical position.
+#[derive(Clone, Debug, Default)]
+struct MoveStats {
+    count: u32,
+    wins: f32,
+    // Sum of final disc differences for the side to move.
+    score_sum: f64,
+}
+
+// Final disc difference from black's point of view, from the record or by
+// playing the game out. `None` when the game does not end.
+fn game_result(record: &GameRecord) -> Option<i32> {
+    if record.result.is_some() {
+        return record.result;
+    }
+    let moves: Vec<u64> = record
+        .moves
+        .iter()
+        .map(|m| m.pos)
+        .filter(|&pos| pos != 0)
+        .collect();
+    let board = line_board(&moves);
+    if legal(board) != 0 {
+        return None;
+    }
+    Some(board.black_board.count_ones() as i32 - board.white_board.count_ones() as i32)
+}
+
+// Adds every move of the first `depth` plies of `record`.
+fn add_game(
+    stats: &mut HashMap<(Board, u64), MoveStats>,
+    record: &GameRecord,
+    result: i32,
+    depth: usize,
+) {
+    let mut board = cmds_to_board("");
+    for game_move in record.moves.iter().filter(|m| m.pos != 0).take(depth) {
+        let score = if board.turn == BLACK { result } else { -result };
+        let entry = stats
+            .entry(canonical_move(&board, game_move.pos))
+            .or_default();
+        entry.count += 1;
+        entry.wins += score_wins(score);
+        entry.score_sum += score as f64;
+        board = execute_pos(&mut board, game_move.pos);
+        if legal(board) == 0 {
+            board.turn = !board.turn;
+        }
+    }
+}
+
+fn read_game_files(option: &GameBookOption) -> Result<Vec<GameRecord>, String> {
+    let mut records: Vec<GameRecord> = vec![];
+    for pattern in &option.files {
+        let paths = glob(pattern);
+        if paths.is_empty() {
+            return Err(format!("no file matches \"{}\"", pattern));
+        }
+        for path in paths {
+            records.extend(read_games(&path)?);
+        }
+    }
+    Ok(records)
+}
+
+pub fn book_from_games(option: &GameBookOption) -> Result<Book, String> {
+    if option.files.is_empty() {
+        return Err("files is required, e.g. files=wthor/*.wtb,self_play/*.txt".to_string());
+    }
+    let records = read_game_files(option)?;
+    let mut stats: HashMap<(Board, u64), MoveStats> = HashMap::new();
+    let mut used = 0;
+    for record in &records {
+        if let Some(result) = game_result(record) {
+            add_game(&mut stats, record, result, option.depth);
+            used += 1;
+        }
+    }
+    let mut book = Book::default();
+    let mut kept = 0;
+    for ((board, pos), move_stats) in &stats {
+        let score = (move_stats.score_sum / move_stats.count as f64) as f32;
+        if move_stats.count >= option.min_games && score >= option.min_score {
+            book.add_move(board, *pos, Some(score), move_stats.count, move_stats.wins);
+            kept += 1;
+        }
+    }
+    println!(
+        "games: {}  finished: {}  moves: {}  kept: {}  positions: {}",
+        records.len(),
+        used,
+        stats.len(),
+        kept,
+        book.entries.len()
+    );
+    Ok(book)
+}
+
+// Lists the moves of each position along the main lines, most played first.
+pub fn book_report(book: &Book, option: &GameBookOption) -> String {
+    fn visit(book: &Book, option: &GameBookOption, line: &mut Vec<u64>, text: &mut String) {
+        let board = line_board(line);
+        let mut moves = book.moves(&board);
+        if moves.is_empty() || line.len() >= option.report_depth {
+            return;
+        }
+        moves.sort_by_key(|m| std::cmp::Reverse(m.count));
+        let total: u32 = moves.iter().map(|m| m.count).sum();
+        let cmds: String = line.iter().map(pos_to_cmd).collect();
+        *text += &format!(
+            "{}  ({} games, {} to move)\n",
+            if cmds.is_empty() { "start" } else { &cmds },
+            total,
+            if board.turn == BLACK {
+                "black"
+            } else {
+                "white"
+            }
+        );
+        for book_move in &moves {
+            *text += &format!(
+                "    {}  games: {:>6}  win rate: {:>5.1}%  score: {:+.1}\n",
+                pos_to_cmd(&book_move.pos),
+                book_move.count,
+                book_move.win_rate().unwrap_or(0.0) * 100.0,
+                book_move.value.unwrap_or(0.0)
+            );
+        }
+        for book_move in &moves {
+            if book_move.count as f32 >= option.report_share * total as f32 {
+                line.push(book_move.pos);
+                visit(book, option, line, text);
+                line.pop();
+            }
+        }
+    }
+
+    let mut text = String::new();
+    visit(book, option, &mut vec![], &mut text);
+    text
+}
+
+pub fn make_game_book(option: &GameBookOption) {
+    let book = match book_from_games(option) {
+        Ok(book) => book,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    match write_book(&option.output, &book) {
+        Ok(positions) => println!("Saved {} positions to {}.", positions, option.output),
+        Err(e) => println!("{}", e),
+    }
+    if !option.report.is_empty() {
+        match fs::write(&option.report, book_report(&book, option)) {
+            Ok(()) => println!("Wrote the main lines to {}.", option.report),
+            Err(e) => println!("{}: {}", option.report, e),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use crate::ai::ai::*;
+use crate::ai::book::*;
+use crate::learning::learning::*;
+use crate::record::ggf::*;
+use crate::record::record::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+use std::fs;
+
+// Balanced openings for engine matches: random `plies`-ply openings whose
+// search score is within `window` of zero, stored as one transcript per
+// line so that anything reading game records (self-play `book=` included)
+// can use them.
+
+#[derive(Clone, Debug)]
+pub struct SuiteOption {
+    pub output: String,
+    pub plies: usize,
+    pub count: usize,
+    // Search depth of the score of an opening.
+    pub depth: i32,
+    // Largest absolute score, in the engine's evaluation units.
+    pub window: f32,
+    pub seed: u64,
+    pub threads: usize,
+}
+
+impl Default for SuiteOption {
+    fn default() -> SuiteOption {
+        SuiteOption {
+            output: "suite.txt".to_string(),
+            plies: 8,
+            count: 100,
+            depth: 8,
+            window: 4.0,
+            seed: 0,
+            threads: 0,
+        }
+    }
+}
+
+impl SuiteOption {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "output" => self.output = value.to_string(),
+            "plies" => self.plies = option_value(key, value)?,
+            "count" => self.count = option_value(key, value)?,
+            "depth" => self.depth = option_value(key, value)?,
+            "window" => self.window = option_value(key, value)?,
+            "seed" => self.seed = option_value(key, value)?,
+            "threads" => self.threads = option_value(key, value)?,
+            _ => return Err(format!("unknown option: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+// Random moves from the start position, `None` when the game ends first.
+fn random_opening(plies: usize, rng: &mut StdRng) -> Option<(Vec<u64>, Board)> {
+    let mut board = cmds_to_board("");
+    let mut moves = vec![];
+    while moves.len() < plies {
+        if legal(board) == 0 {
+            board.turn = !board.turn;
+            if legal(board) == 0 {
+                return None;
+            }
+        }
+        let poss = legal_poss(&board);
+        let pos = poss[rng.gen_range(0..poss.len())];
+        board = execute_pos(&mut board, pos);
+        moves.push(pos);
+    }
+    if legal(board) == 0 {
+        board.turn = !board.turn;
+        if legal(board) == 0 {
+            return None;
+        }
+    }
+    Some((moves, board))
+}
+
+// Openings that reach distinct positions under symmetry, with scores closest
+// to zero first.
+pub fn make_suite(option: &SuiteOption) -> Vec<(Vec<u64>, f32)> {
+    let mut seen: HashSet<Board> = HashSet::new();
+    let mut candidates: Vec<(Vec<u64>, Board)> = vec![];
+    // Ten candidates for each opening kept leave a choice of scores.
+    let attempts = option.count.saturating_mul(100).max(1000);
+    let mut i = 0;
+    while candidates.len() < option.count.saturating_mul(10) && i < attempts {
+        let mut rng = StdRng::seed_from_u64(option.seed.wrapping_add(i as u64));
+        i += 1;
+        if let Some((moves, board)) = random_opening(option.plies, &mut rng) {
+            if seen.insert(canonical_board(&board).0) {
+                candidates.push((moves, board));
+            }
+        }
+    }
+    set_search_log(false);
+    let scores = parallel_map(&candidates, thread_count(option.threads), |(_, board)| {
+        search_score(board, option.depth) as f32
+    });
+    set_search_log(true);
+    let mut suite: Vec<(Vec<u64>, f32)> = candidates
+        .into_iter()
+        .zip(scores)
+        .map(|((moves, _), score)| (moves, score))
+        .filter(|(_, score)| score.abs() <= option.window)
+        .collect();
+    println!(
+        "distinct openings: {}  within the window: {}",
+        seen.len(),
+        suite.len()
+    );
+    suite.sort_by(|a, b| a.1.abs().total_cmp(&b.1.abs()));
+    suite.truncate(option.count);
+    suite
+}
+
+pub fn write_suite(option: &SuiteOption) -> Result<(), String> {
+    let suite = make_suite(option);
+    let text: String = suite
+        .iter()
+        .map(|(moves, _)| {
+            moves
+                .iter()
+                .map(|pos| pos_to_cmd(pos).to_lowercase())
+                .collect::<String>()
+                + "\n"
+        })
+        .collect();
+    fs::write(&option.output, text).map_err(|e| format!("{}: {}", option.output, e))?;
+    let largest = suite
+        .iter()
+        .map(|(_, score)| score.abs())
+        .fold(0.0, f32::max);
+    println!(
+        "Wrote {} openings to {}, largest absolute score: {}.",
+        suite.len(),
+        option.output,
+        largest
+    );
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct MatchOption {
+    // AI names as in `-debug`.
+    pub engine1: String,
+    pub engine2: String,
+    pub suite: String,
+    // Openings of the suite to play, 0 for all of them.
+    pub openings: usize,
+    pub depth: i32,
+    // Milliseconds on each side's clock at the start of a game.
+    pub time: u64,
+    // GGF file the games are appended to, empty for none.
+    pub output: String,
+}
+
+impl Default for MatchOption {
+    fn default() -> MatchOption {
+        MatchOption {
+            engine1: "ns".to_string(),
+            engine2: "na".to_string(),
+            suite: "suite.txt".to_string(),
+            openings: 0,
+            depth: 12,
+            time: 60000,
+            output: String::new(),
+        }
+    }
+}
+
+impl MatchOption {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "engine1" => self.engine1 = value.to_string(),
+            "engine2" => self.engine2 = value.to_string(),
+            "suite" => self.suite = value.to_string(),
+            "openings" => self.openings = option_value(key, value)?,
+            "depth" => self.depth = option_value(key, value)?,
+            "time" => self.time = option_value(key, value)?,
+            "output" => self.output = value.to_string(),
+            _ => return Err(format!("unknown option: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+// Plays `opening` and then lets the engines finish the game without a book.
+fn play_match_game(option: &MatchOption, opening: &[u64], black: &str, white: &str) -> GameRecord {
+    let book = Book::default();
+    let mut board = cmds_to_board("");
+    let mut record = GameRecord {
+        black: black.to_string(),
+        white: white.to_string(),
+        ..GameRecord::default()
+    };
+    let (mut black_time, mut white_time) = (option.time, option.time);
+    let mut ply = 0;
+    loop {
+        if legal(board) == 0 {
+            board.turn = !board.turn;
+            if legal(board) == 0 {
+                break;
+            }
+            record.moves.push(GameMove::default());
+        }
+        let game_move = if ply < opening.len() {
+            GameMove {
+                pos: opening[ply],
+                time: None,
+            }
+        } else {
+            let (engine, clock) = if board.turn == BLACK {
+                (black, &mut black_time)
+            } else {
+                (white, &mut white_time)
+            };
+            let (pos, duration) =
+                ai_pos(&mut board, option.depth, engine.to_string(), &book, *clock);
+            *clock = clock.saturating_sub(duration.as_millis() as u64);
+            GameMove {
+                pos,
+                time: Some(duration.as_secs_f32()),
+            }
+        };
+        board = execute_pos(&mut board, game_move.pos);
+        record.moves.push(game_move);
+        ply += 1;
+    }
+    record.result =
+        Some(board.black_board.count_ones() as i32 - board.white_board.count_ones() as i32);
+    record
+}
+
+// Plays every opening of the suite twice, once with each engine as black,
+// and reports the results from `engine1`'s point of view.
+pub fn run_match(option: &MatchOption) -> Result<(), String> {
+    let mut openings: Vec<Vec<u64>> = read_games(&option.suite)?
+        .iter()
+        .map(|record| {
+            record
+                .moves
+                .iter()
+                .map(|m| m.pos)
+                .filter(|&pos| pos != 0)
+                .collect()
+        })
+        .collect();
+    if option.openings > 0 {
+        openings.truncate(option.openings);
+    }
+    if openings.is_empty() {
+        return Err(format!("{}: no openings", option.suite));
+    }
+    let (engine1, engine2) = (option.engine1.as_str(), option.engine2.as_str());
+    let (mut wins, mut draws, mut losses, mut discs) = (0, 0, 0, 0);
+    set_search_log(false);
+    for (i, opening) in openings.iter().enumerate() {
+        let as_black = play_match_game(option, opening, engine1, engine2);
+        let as_white = play_match_game(option, opening, engine2, engine1);
+        let scores = [as_black.result.unwrap(), -as_white.result.unwrap()];
+        for score in scores {
+            match score {
+                s if s > 0 => wins += 1,
+                0 => draws += 1,
+                _ => losses += 1,
+            }
+            discs += score;
+        }
+        let cmds: String = opening
+            .iter()
+            .map(|pos| pos_to_cmd(pos).to_lowercase())
+            .collect();
+        println!(
+            "opening {} / {}  {}  as black: {:+}  as white: {:+}",
+            i + 1,
+            openings.len(),
+            cmds,
+            scores[0],
+            scores[1]
+        );
+        if !option.output.is_empty() {
+            for record in [&as_black, &as_white] {
+                append_ggf(&option.output, record)?;
+            }
+        }
+    }
+    set_search_log(true);
+    let games = wins + draws + losses;
+    println!(
+        "{} vs {}  games: {}  wins: {}  draws: {}  losses: {}  score: {:.1}%  average discs: {:+.2}",
+        engine1,
+        engine2,
+        games,
+        wins,
+        draws,
+        losses,
+        (wins as f32 + draws as f32 / 2.0) / games as f32 * 100.0,
+        discs as f32 / games as f32
+    );
+    Ok(())
+}
@@ -1,9 +1,40 @@
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use std::fmt;
+
+/*
+
+game server protocol, one message per line
+
+OPEN name                        client -> server
+START BLACK|WHITE name time      server -> client, opponent's name and own time
+MOVE square|PASS                 both ways, e.g. MOVE F5
+ACK time                         server -> client, own remaining time
+END WIN|LOSE|TIE n m reason      server -> client, own and opponent's discs
+BYE stat                         server -> client, the rest of the line
+
+times are in milliseconds. Lines may end in CRLF, fields may be separated
+by any whitespace, and keywords and squares are read in either case.
+
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Win,
+    Lose,
+    Tie,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Command {
     Open(String),
-    Start(String, String, String),
-    Move(String),
+    // Own colour, opponent's name and own time.
+    Start(bool, String, u64),
+    // The square, 0 for a pass.
+    Move(u64),
     Ack(u64),
-    End(String, u32, u32, String),
+    // Own and opponent's discs, then the reason.
+    End(Outcome, u32, u32, String),
     Bye(String),
 }
 
@@ -15,44 +46,253 @@ pub enum ClientState {
     Ended,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    Empty,
+    UnknownCommand(String),
+    // The command, the number of arguments it takes and the number given.
+    ArgumentCount(&'static str, usize, usize),
+    InvalidArgument(&'static str, String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Empty => write!(f, "empty message"),
+            ProtocolError::UnknownCommand(command) => {
+                write!(f, "unknown command \"{}\"", command)
+            }
+            ProtocolError::ArgumentCount(command, expected, found) => write!(
+                f,
+                "{} takes {} arguments but {} were given",
+                command, expected, found
+            ),
+            ProtocolError::InvalidArgument(command, argument) => {
+                write!(f, "{}: invalid argument \"{}\"", command, argument)
+            }
+        }
+    }
+}
+
 use Command::*;
 
-pub fn mes_to_command(mes: &String) -> Command {
-    assert_eq!(mes.ends_with('\n'), true);
-    let mes = mes[..mes.len() - 1].to_string();
-    let parsed_mes: Vec<String> = mes.split_whitespace().map(|s| s.to_string()).collect();
-    let length = parsed_mes.len();
-    assert!(length > 1);
-
-    if parsed_mes[0] == String::from("OPEN") {
-        assert_eq!(length, 2);
-        Move(parsed_mes[1].to_string())
-    } else if parsed_mes[0] == String::from("ACK") {
-        assert_eq!(length, 2);
-        let time: u64 = parsed_mes[1].parse().unwrap();
-        Ack(time)
-    } else if parsed_mes[0] == String::from("END") {
-        assert_eq!(length, 5);
-        let n: u32 = parsed_mes[2].parse().unwrap();
-        let m: u32 = parsed_mes[3].parse().unwrap();
-        End(parsed_mes[1].to_string(), n, m, parsed_mes[4].to_string())
-    } else if parsed_mes[0] == String::from("START") {
-        assert_eq!(length, 4);
-        Start(
-            parsed_mes[1].to_string(),
-            parsed_mes[2].to_string(),
-            parsed_mes[3].to_string(),
-        )
-    } else if parsed_mes[0] == String::from("MOVE") {
-        assert_eq!(length, 2);
-        Move(parsed_mes[1].to_string())
-    } else if parsed_mes[0] == String::from("OPEN") {
-        assert_eq!(length, 2);
-        Open(parsed_mes[1].to_string())
-    } else if parsed_mes[0] == String::from("BYE") {
-        assert!(mes.len() > 4);
-        Bye(mes[4..].to_string())
+// Reads `F5` or `f5`.
+fn parse_square(text: &str) -> Option<u64> {
+    let bytes = text.to_ascii_uppercase().into_bytes();
+    match bytes[..] {
+        [column @ b'A'..=b'H', row @ b'1'..=b'8'] => {
+            Some(1 << ((column - b'A') + 8 * (row - b'1')))
+        }
+        _ => None,
+    }
+}
+
+fn expect_arguments(
+    command: &'static str,
+    arguments: &[&str],
+    expected: usize,
+) -> Result<(), ProtocolError> {
+    if arguments.len() == expected {
+        Ok(())
     } else {
-        panic!();
+        Err(ProtocolError::ArgumentCount(
+            command,
+            expected,
+            arguments.len(),
+        ))
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(
+    command: &'static str,
+    text: &str,
+) -> Result<T, ProtocolError> {
+    text.parse()
+        .map_err(|_| ProtocolError::InvalidArgument(command, text.to_string()))
+}
+
+pub fn mes_to_command(mes: &str) -> Result<Command, ProtocolError> {
+    let fields: Vec<&str> = mes.split_whitespace().collect();
+    let Some((&keyword, arguments)) = fields.split_first() else {
+        return Err(ProtocolError::Empty);
+    };
+    match keyword.to_ascii_uppercase().as_str() {
+        "OPEN" => {
+            expect_arguments("OPEN", arguments, 1)?;
+            Ok(Open(arguments[0].to_string()))
+        }
+        "START" => {
+            expect_arguments("START", arguments, 3)?;
+            let colour = match arguments[0].to_ascii_uppercase().as_str() {
+                "BLACK" => BLACK,
+                "WHITE" => WHITE,
+                _ => {
+                    return Err(ProtocolError::InvalidArgument(
+                        "START",
+                        arguments[0].to_string(),
+                    ))
+                }
+            };
+            let time = parse_number("START", arguments[2])?;
+            Ok(Start(colour, arguments[1].to_string(), time))
+        }
+        "MOVE" => {
+            expect_arguments("MOVE", arguments, 1)?;
+            if arguments[0].eq_ignore_ascii_case("PASS") {
+                return Ok(Move(0));
+            }
+            parse_square(arguments[0])
+                .map(Move)
+                .ok_or(ProtocolError::InvalidArgument(
+                    "MOVE",
+                    arguments[0].to_string(),
+                ))
+        }
+        "ACK" => {
+            expect_arguments("ACK", arguments, 1)?;
+            Ok(Ack(parse_number("ACK", arguments[0])?))
+        }
+        "END" => {
+            expect_arguments("END", arguments, 4)?;
+            let outcome = match arguments[0].to_ascii_uppercase().as_str() {
+                "WIN" => Outcome::Win,
+                "LOSE" => Outcome::Lose,
+                "TIE" => Outcome::Tie,
+                _ => {
+                    return Err(ProtocolError::InvalidArgument(
+                        "END",
+                        arguments[0].to_string(),
+                    ))
+                }
+            };
+            let own = parse_number("END", arguments[1])?;
+            let opponent = parse_number("END", arguments[2])?;
+            Ok(End(outcome, own, opponent, arguments[3].to_string()))
+        }
+        "BYE" => Ok(Bye(arguments.join(" "))),
+        _ => Err(ProtocolError::UnknownCommand(keyword.to_string())),
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Win => write!(f, "WIN"),
+            Outcome::Lose => write!(f, "LOSE"),
+            Outcome::Tie => write!(f, "TIE"),
+        }
+    }
+}
+
+// A message without the newline.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Open(name) => write!(f, "OPEN {}", name),
+            Start(colour, name, time) => write!(
+                f,
+                "START {} {} {}",
+                if *colour == BLACK { "BLACK" } else { "WHITE" },
+                name,
+                time
+            ),
+            Move(0) => write!(f, "MOVE PASS"),
+            Move(pos) => write!(f, "MOVE {}", pos_to_cmd(pos)),
+            Ack(time) => write!(f, "ACK {}", time),
+            End(outcome, own, opponent, reason) => {
+                write!(f, "END {} {} {} {}", outcome, own, opponent, reason)
+            }
+            Bye(stat) if stat.is_empty() => write!(f, "BYE"),
+            Bye(stat) => write!(f, "BYE {}", stat),
+        }
+    }
+}
+
+pub fn command_to_mes(command: &Command) -> String {
+    format!("{}\n", command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn word() -> impl Strategy<Value = String> {
+        "[A-Za-z0-9_.-]{1,16}"
+    }
+
+    fn command() -> impl Strategy<Value = Command> {
+        let outcome = prop_oneof![Just(Outcome::Win), Just(Outcome::Lose), Just(Outcome::Tie)];
+        prop_oneof![
+            word().prop_map(Open),
+            (any::<bool>(), word(), any::<u64>())
+                .prop_map(|(colour, name, time)| Start(colour, name, time)),
+            prop_oneof![Just(0u64), (0..64u32).prop_map(|i| 1u64 << i)].prop_map(Move),
+            any::<u64>().prop_map(Ack),
+            (outcome, any::<u32>(), any::<u32>(), word())
+                .prop_map(|(outcome, own, opponent, reason)| End(outcome, own, opponent, reason)),
+            prop::collection::vec(word(), 0..8).prop_map(|words| Bye(words.join(" "))),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(command in command()) {
+            prop_assert_eq!(mes_to_command(&command_to_mes(&command)), Ok(command));
+        }
+
+        #[test]
+        fn crlf_and_extra_whitespace(
+            command in command(),
+            separator in "[ \t]{1,3}",
+            leading in "[ \t]{0,3}",
+            trailing in "[ \t]{0,3}",
+        ) {
+            let mes = format!(
+                "{}{}{}\r\n",
+                leading,
+                command.to_string().replace(' ', &separator),
+                trailing
+            );
+            prop_assert_eq!(mes_to_command(&mes), Ok(command));
+        }
+
+        #[test]
+        fn any_line_is_parsed_without_panicking(mes in "\\PC*") {
+            let _ = mes_to_command(&mes);
+        }
+    }
+
+    #[test]
+    fn open_is_not_a_move() {
+        assert_eq!(
+            mes_to_command("OPEN player1\n"),
+            Ok(Open("player1".to_string()))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(mes_to_command("\r\n"), Err(ProtocolError::Empty));
+        assert_eq!(
+            mes_to_command("HELLO\n"),
+            Err(ProtocolError::UnknownCommand("HELLO".to_string()))
+        );
+        assert_eq!(
+            mes_to_command("MOVE\n"),
+            Err(ProtocolError::ArgumentCount("MOVE", 1, 0))
+        );
+        assert_eq!(
+            mes_to_command("MOVE I9\n"),
+            Err(ProtocolError::InvalidArgument("MOVE", "I9".to_string()))
+        );
+        assert_eq!(
+            mes_to_command("END DRAW 32 32 DOUBLE_PASS\n"),
+            Err(ProtocolError::InvalidArgument("END", "DRAW".to_string()))
+        );
+        assert_eq!(
+            mes_to_command("ACK -1\n"),
+            Err(ProtocolError::InvalidArgument("ACK", "-1".to_string()))
+        );
     }
 }
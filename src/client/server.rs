@@ -0,0 +1,489 @@
+use crate::client::client::{Command::*, *};
+use crate::client::session::*;
+use crate::record::ggf::*;
+use crate::record::record::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use std::io;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// A reference server for the protocol of `client`, so that clients can be
+// tested without the tournament server. Clients are paired in the order
+// their OPEN arrives, and each pair plays a series of games with the colours swapped
+// after every game. A move that is not legal, a message that is not a move,
+// a clock that runs out or a closed connection loses the game.
+
+// Milliseconds a client has to send OPEN after connecting.
+const OPEN_TIMEOUT: u64 = 10000;
+
+#[derive(Clone, Debug)]
+pub struct ServerOption {
+    pub port: u16,
+    // Games of each series.
+    pub games: usize,
+    // Milliseconds on each player's clock at the start of a game.
+    pub time: u64,
+    // GGF file the games are appended to, empty for none.
+    pub output: String,
+}
+
+impl Default for ServerOption {
+    fn default() -> ServerOption {
+        ServerOption {
+            port: 3000,
+            games: 2,
+            time: 60000,
+            output: String::new(),
+        }
+    }
+}
+
+impl ServerOption {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "port" => self.port = option_value(key, value)?,
+            "games" => self.games = option_value(key, value)?,
+            "time" => self.time = option_value(key, value)?,
+            "output" => self.output = value.to_string(),
+            _ => return Err(format!("unknown option: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+pub struct Player {
+    pub name: String,
+    pub connection: LineTransport<BufReader<TcpStream>, TcpStream>,
+    // Totals of the series: disc difference, wins and losses.
+    pub score: i32,
+    pub wins: u32,
+    pub losses: u32,
+}
+
+// Why a player lost the game before it was played out.
+enum Fault {
+    IllegalMove,
+    Timeout,
+    Disconnected,
+}
+
+impl Fault {
+    fn reason(&self) -> &'static str {
+        match self {
+            Fault::IllegalMove => "ILLEGAL_MOVE",
+            Fault::Timeout => "TIMEOUT",
+            Fault::Disconnected => "DISCONNECTED",
+        }
+    }
+}
+
+impl Player {
+    fn send(&mut self, command: &Command) -> Result<(), Fault> {
+        self.connection
+            .send(&command_to_mes(command))
+            .map_err(|_| Fault::Disconnected)
+    }
+
+    // The next message within `timeout` milliseconds.
+    fn receive(&mut self, timeout: u64) -> Result<Command, Fault> {
+        let timeout = Duration::from_millis(timeout.max(1));
+        if self
+            .connection
+            .writer
+            .set_read_timeout(Some(timeout))
+            .is_err()
+        {
+            return Err(Fault::Disconnected);
+        }
+        match self.connection.receive() {
+            Ok(Some(line)) => mes_to_command(&line).map_err(|_| Fault::IllegalMove),
+            Ok(None) => Err(Fault::Disconnected),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Err(Fault::Timeout)
+            }
+            Err(_) => Err(Fault::Disconnected),
+        }
+    }
+}
+
+// Waits for OPEN from a new connection.
+pub fn open(stream: TcpStream) -> Result<Player, String> {
+    let connection = LineTransport::tcp(stream).map_err(|e| e.to_string())?;
+    let mut player = Player {
+        name: String::new(),
+        connection,
+        score: 0,
+        wins: 0,
+        losses: 0,
+    };
+    match player.receive(OPEN_TIMEOUT) {
+        Ok(Open(name)) => {
+            player.name = name;
+            Ok(player)
+        }
+        Ok(command) => Err(format!("expected OPEN but got \"{}\"", command)),
+        Err(fault) => Err(fault.reason().to_lowercase()),
+    }
+}
+
+pub struct GameEnd {
+    pub record: GameRecord,
+    pub discs: [u32; 2],
+    // The colour that lost by a fault, `None` when the discs decide.
+    pub forfeit: Option<bool>,
+    pub reason: &'static str,
+}
+
+impl GameEnd {
+    fn outcome(&self, colour: bool) -> Outcome {
+        let i = if colour == BLACK { 0 } else { 1 };
+        match self.forfeit {
+            Some(loser) if loser == colour => Outcome::Lose,
+            Some(_) => Outcome::Win,
+            None if self.discs[i] > self.discs[1 - i] => Outcome::Win,
+            None if self.discs[i] < self.discs[1 - i] => Outcome::Lose,
+            None => Outcome::Tie,
+        }
+    }
+}
+
+// Waits for each move, checks it against the board and the mover's clock,
+// and passes it on to the other player after the ACK. The game ends with END
+// to both players when neither side can move or on the first fault.
+pub fn play_game(black: &mut Player, white: &mut Player, time: u64) -> GameEnd {
+    let mut players = [black, white];
+    let mut record = GameRecord {
+        black: players[0].name.clone(),
+        white: players[1].name.clone(),
+        date: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+            .to_string(),
+        ..GameRecord::default()
+    };
+    let mut board = cmds_to_board("");
+    let mut clocks = [time, time];
+    let mut fault = None;
+    for (i, colour) in [(0, BLACK), (1, WHITE)] {
+        let opponent_name = players[1 - i].name.clone();
+        if let Err(e) = players[i].send(&Start(colour, opponent_name, time)) {
+            fault = Some((colour, e));
+        }
+    }
+    while fault.is_none() {
+        let i = if board.turn == BLACK { 0 } else { 1 };
+        let start = Instant::now();
+        let pos = match players[i].receive(clocks[i]) {
+            Ok(Move(pos)) => pos,
+            Ok(_) => {
+                fault = Some((board.turn, Fault::IllegalMove));
+                break;
+            }
+            Err(e) => {
+                fault = Some((board.turn, e));
+                break;
+            }
+        };
+        let elapsed = start.elapsed().as_millis() as u64;
+        if elapsed > clocks[i] {
+            fault = Some((board.turn, Fault::Timeout));
+            break;
+        }
+        clocks[i] -= elapsed;
+        if (pos == 0 && legal(board) != 0) || (pos != 0 && !is_legal_pos(&board, &pos)) {
+            fault = Some((board.turn, Fault::IllegalMove));
+            break;
+        }
+        let mover = board.turn;
+        if pos == 0 {
+            board.no_legal_command += 1;
+            board.turn = !board.turn;
+        } else {
+            board = execute_pos(&mut board, pos);
+        }
+        record.moves.push(GameMove {
+            pos,
+            time: Some(elapsed as f32 / 1000.0),
+        });
+        if let Err(e) = players[i].send(&Ack(clocks[i])) {
+            fault = Some((mover, e));
+            break;
+        }
+        let mut passed = board;
+        passed.turn = !passed.turn;
+        if legal(board) == 0 && legal(passed) == 0 {
+            break;
+        }
+        if let Err(e) = players[1 - i].send(&Move(pos)) {
+            fault = Some((!mover, e));
+        }
+    }
+
+    let discs = [
+        board.black_board.count_ones(),
+        board.white_board.count_ones(),
+    ];
+    let end = GameEnd {
+        discs,
+        forfeit: fault.as_ref().map(|(colour, _)| *colour),
+        reason: fault.as_ref().map_or("DOUBLE_PASS", |(_, e)| e.reason()),
+        record: GameRecord {
+            result: Some(discs[0] as i32 - discs[1] as i32),
+            ..record
+        },
+    };
+    for (i, colour) in [(0, BLACK), (1, WHITE)] {
+        let outcome = end.outcome(colour);
+        let player = &mut players[i];
+        player.score += discs[i] as i32 - discs[1 - i] as i32;
+        match outcome {
+            Outcome::Win => player.wins += 1,
+            Outcome::Lose => player.losses += 1,
+            Outcome::Tie => {}
+        }
+        let _ = player.send(&End(
+            outcome,
+            discs[i],
+            discs[1 - i],
+            end.reason.to_string(),
+        ));
+    }
+    end
+}
+
+fn stat(player: &Player) -> String {
+    format!(
+        "{} {:+} {} {}",
+        player.name, player.score, player.wins, player.losses
+    )
+}
+
+// Plays `option.games` games, `first` taking black in the odd ones, and says
+// goodbye with the totals of both players. A closed connection ends the
+// series.
+pub fn play_series(first: &mut Player, second: &mut Player, option: &ServerOption) {
+    for game in 0..option.games {
+        let (black, white) = if game % 2 == 0 {
+            (&mut *first, &mut *second)
+        } else {
+            (&mut *second, &mut *first)
+        };
+        let mut end = play_game(black, white, option.time);
+        println!(
+            "{} vs {}  game {} / {}  {} - {}  {}",
+            black.name,
+            white.name,
+            game + 1,
+            option.games,
+            end.discs[0],
+            end.discs[1],
+            end.reason
+        );
+        if !option.output.is_empty() {
+            end.record.reason = end.reason.to_lowercase();
+            if let Err(e) = append_ggf(&option.output, &end.record) {
+                println!("Failed in saving game ({}).", e);
+            }
+        }
+        if end.reason == Fault::Disconnected.reason() {
+            break;
+        }
+    }
+    let stat = format!("{} {}", stat(first), stat(second));
+    println!("BYE {}", stat);
+    for player in [first, second] {
+        let _ = player.send(&Bye(stat.clone()));
+    }
+}
+
+// Accepts clients forever and plays a series in its own thread for every
+// two of them. Each connection waits for its OPEN in its own thread, so a
+// silent client does not hold up the others.
+pub fn serve(option: &ServerOption) -> Result<(), String> {
+    let listener = TcpListener::bind(("0.0.0.0", option.port))
+        .map_err(|e| format!("port {}: {}", option.port, e))?;
+    println!("Listening on port {}.", option.port);
+    let (sender, receiver) = mpsc::channel::<Player>();
+    let option = option.clone();
+    thread::spawn(move || {
+        let mut waiting: Option<Player> = None;
+        for player in receiver {
+            println!("{} connected.", player.name);
+            match waiting.take() {
+                None => waiting = Some(player),
+                Some(mut first) => {
+                    let mut second = player;
+                    let option = option.clone();
+                    thread::spawn(move || play_series(&mut first, &mut second, &option));
+                }
+            }
+        }
+    });
+    for stream in listener.incoming() {
+        let sender = sender.clone();
+        thread::spawn(
+            move || match stream.map_err(|e| e.to_string()).and_then(open) {
+                Ok(player) => {
+                    let _ = sender.send(player);
+                }
+                Err(e) => println!("Failed in opening connection ({}).", e),
+            },
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Plays the first legal move and records how each game ended.
+    #[derive(Default)]
+    struct FirstMove {
+        results: Vec<(bool, (u32, u32), String)>,
+    }
+
+    impl Engine for FirstMove {
+        fn choose(&mut self, board: &Board, _remaining_time: u64) -> (u64, Duration) {
+            let pos = legal_poss(board).first().copied().unwrap_or(0);
+            (pos, Duration::ZERO)
+        }
+
+        fn game_over(
+            &mut self,
+            _record: &mut GameRecord,
+            own_turn: bool,
+            discs: (u32, u32),
+            reason: &str,
+        ) {
+            self.results.push((own_turn, discs, reason.to_string()));
+        }
+    }
+
+    type Raw = LineTransport<BufReader<TcpStream>, TcpStream>;
+
+    fn listener() -> (TcpListener, u16) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    fn accept(listener: &TcpListener) -> Player {
+        open(listener.accept().unwrap().0).unwrap()
+    }
+
+    fn session_client(port: u16, name: &str) -> thread::JoinHandle<FirstMove> {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let transport = LineTransport::tcp(stream).unwrap();
+        let mut session = ClientSession::new(transport, FirstMove::default(), name);
+        thread::spawn(move || {
+            session.run().unwrap();
+            session.engine
+        })
+    }
+
+    fn raw_client(port: u16, name: &str) -> Raw {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut raw = LineTransport::tcp(stream).unwrap();
+        raw.send(&command_to_mes(&Open(name.to_string()))).unwrap();
+        raw
+    }
+
+    fn next(raw: &mut Raw) -> Command {
+        mes_to_command(&raw.receive().unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn series_swaps_colours() {
+        let (listener, port) = listener();
+        let first_client = session_client(port, "first");
+        let mut first = accept(&listener);
+        let second_client = session_client(port, "second");
+        let mut second = accept(&listener);
+        play_series(&mut first, &mut second, &ServerOption::default());
+
+        let first_results = first_client.join().unwrap().results;
+        let second_results = second_client.join().unwrap().results;
+        assert_eq!(first_results.len(), 2);
+        assert_eq!(first_results[0].0, BLACK);
+        assert_eq!(second_results[0].0, WHITE);
+        assert_eq!(first_results[1].0, WHITE);
+        assert_eq!(second_results[1].0, BLACK);
+        // Both engines play the same moves, so the games are the same.
+        assert_eq!(first_results[0].1, second_results[1].1);
+        assert_eq!(first_results[1].1, second_results[0].1);
+        assert!(first_results
+            .iter()
+            .chain(&second_results)
+            .all(|(_, _, reason)| reason == "DOUBLE_PASS"));
+        assert_eq!(first.score, -second.score);
+        assert_eq!(first.wins, second.losses);
+    }
+
+    #[test]
+    fn illegal_move_loses() {
+        let (listener, port) = listener();
+        let mut raw = raw_client(port, "raw");
+        let mut black = accept(&listener);
+        let client = session_client(port, "session");
+        let mut white = accept(&listener);
+        let option = ServerOption {
+            games: 1,
+            ..ServerOption::default()
+        };
+        let server = thread::spawn(move || play_series(&mut black, &mut white, &option));
+
+        assert_eq!(next(&mut raw), Start(BLACK, "session".to_string(), 60000));
+        raw.send("MOVE A1\n").unwrap();
+        assert_eq!(
+            next(&mut raw),
+            End(Outcome::Lose, 2, 2, "ILLEGAL_MOVE".to_string())
+        );
+        assert_eq!(next(&mut raw), Bye("raw +0 0 1 session +0 1 0".to_string()));
+        server.join().unwrap();
+        assert_eq!(
+            client.join().unwrap().results,
+            [(WHITE, (2, 2), "ILLEGAL_MOVE".to_string())]
+        );
+    }
+
+    #[test]
+    fn clock_runs_out() {
+        let (listener, port) = listener();
+        let client = session_client(port, "session");
+        let mut black = accept(&listener);
+        let mut raw = raw_client(port, "raw");
+        let mut white = accept(&listener);
+        let option = ServerOption {
+            games: 1,
+            time: 200,
+            ..ServerOption::default()
+        };
+        let server = thread::spawn(move || play_series(&mut black, &mut white, &option));
+
+        assert_eq!(next(&mut raw), Start(WHITE, "session".to_string(), 200));
+        let Move(pos) = next(&mut raw) else {
+            panic!("expected a move");
+        };
+        assert_ne!(pos, 0);
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(
+            next(&mut raw),
+            End(Outcome::Lose, 1, 4, "TIMEOUT".to_string())
+        );
+        server.join().unwrap();
+        assert_eq!(
+            client.join().unwrap().results,
+            [(BLACK, (4, 1), "TIMEOUT".to_string())]
+        );
+    }
+}
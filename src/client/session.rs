@@ -0,0 +1,536 @@
+use crate::ai::ai::*;
+use crate::ai::book::*;
+use crate::ai::book_learning::*;
+use crate::client::client::{ClientState::*, Command::*, *};
+use crate::record::ggf::*;
+use crate::record::opening::*;
+use crate::record::record::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Where protocol lines come from and go to.
+pub trait Transport {
+    // The next line, `None` once the other side has closed the connection.
+    fn receive(&mut self) -> io::Result<Option<String>>;
+    fn send(&mut self, mes: &str) -> io::Result<()>;
+}
+
+// Lines over any reader and writer, e.g. a socket, or `io::stdin().lock()`
+// and `io::stdout()`.
+pub struct LineTransport<R: BufRead, W: Write> {
+    pub reader: R,
+    pub writer: W,
+}
+
+impl LineTransport<BufReader<TcpStream>, TcpStream> {
+    pub fn tcp(stream: TcpStream) -> io::Result<Self> {
+        Ok(LineTransport {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+}
+
+impl<R: BufRead, W: Write> Transport for LineTransport<R, W> {
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line))
+    }
+
+    fn send(&mut self, mes: &str) -> io::Result<()> {
+        self.writer.write_all(mes.as_bytes())?;
+        self.writer.flush()
+    }
+}
+
+// What plays the moves of a session.
+pub trait Engine {
+    // The move for `board`, 0 to pass, and the time it took. `remaining_time`
+    // is in milliseconds.
+    fn choose(&mut self, board: &Board, remaining_time: u64) -> (u64, Duration);
+
+    // Called after every move of either side.
+    fn after_move(&mut self, _record: &GameRecord) {}
+
+    // Called when a game ends; `discs` is (own, opponent's) as sent in END.
+    fn game_over(
+        &mut self,
+        _record: &mut GameRecord,
+        _own_turn: bool,
+        _discs: (u32, u32),
+        _reason: &str,
+    ) {
+    }
+}
+
+// The engine of the network client: the book, then the nega scout search.
+// Finished games are saved to `games_path` and learned into the book.
+pub struct SearchEngine {
+    pub book: Book,
+    // Positions learned from the games, saved next to `book_path`.
+    pub learned: Book,
+    pub book_path: String,
+    pub games_path: String,
+    pub openings: Openings,
+    pub depth: i32,
+}
+
+impl Engine for SearchEngine {
+    fn choose(&mut self, board: &Board, remaining_time: u64) -> (u64, Duration) {
+        let mut board = *board;
+        ai_pos(
+            &mut board,
+            self.depth,
+            String::from("ns"),
+            &self.book,
+            remaining_time,
+        )
+    }
+
+    fn after_move(&mut self, record: &GameRecord) {
+        self.openings.log_opening(record);
+    }
+
+    fn game_over(
+        &mut self,
+        record: &mut GameRecord,
+        own_turn: bool,
+        discs: (u32, u32),
+        reason: &str,
+    ) {
+        self.openings.tag(record);
+        save_network_game(&self.games_path, record, own_turn, discs, reason);
+        learn_network_game(
+            &mut self.book,
+            &mut self.learned,
+            &self.book_path,
+            record,
+            own_turn,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Io(io::Error),
+    Closed,
+    // The error and the line it was found in.
+    Protocol(ProtocolError, String),
+    // A valid message the current state does not expect.
+    Unexpected(&'static str, Command),
+    // A move of the opponent that is not legal, 0 for a pass.
+    IllegalMove(u64),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Io(e) => write!(f, "{}", e),
+            SessionError::Closed => write!(f, "connection closed by the server"),
+            SessionError::Protocol(e, line) => write!(f, "{} in \"{}\"", e, line.trim_end()),
+            SessionError::Unexpected(state, command) => {
+                write!(f, "unexpected message ({}): {}", state, command)
+            }
+            SessionError::IllegalMove(0) => write!(f, "opponent passed with legal moves left"),
+            SessionError::IllegalMove(pos) => {
+                write!(f, "opponent's move {} is illegal", pos_to_cmd(pos))
+            }
+        }
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> SessionError {
+        SessionError::Io(e)
+    }
+}
+
+pub struct ClientSession<T: Transport, E: Engine> {
+    pub transport: T,
+    pub engine: E,
+    pub name: String,
+    pub state: ClientState,
+    pub board: Board,
+    pub own_turn: bool,
+    // Milliseconds left on the own clock.
+    pub remaining_time: u64,
+    pub record: GameRecord,
+}
+
+impl<T: Transport, E: Engine> ClientSession<T, E> {
+    pub fn new(transport: T, engine: E, name: &str) -> Self {
+        ClientSession {
+            transport,
+            engine,
+            name: name.to_string(),
+            state: CardWaiting,
+            board: cmds_to_board(""),
+            own_turn: BLACK,
+            remaining_time: 60000,
+            record: GameRecord::default(),
+        }
+    }
+
+    fn send(&mut self, command: &Command) -> Result<(), SessionError> {
+        let mes = command_to_mes(command);
+        self.transport.send(&mes)?;
+        log!("Sent: {}", mes.trim_end());
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Command, SessionError> {
+        let line = self.transport.receive()?.ok_or(SessionError::Closed)?;
+        log!("Recieved: {}", line.trim_end());
+        mes_to_command(&line).map_err(|e| SessionError::Protocol(e, line))
+    }
+
+    fn start_game(&mut self, colour: bool, opponent_name: &str, time: u64) {
+        log!("Opponent name: {}", opponent_name);
+        self.own_turn = colour;
+        self.remaining_time = time;
+        self.board = cmds_to_board("");
+        self.record = GameRecord {
+            date: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+                .to_string(),
+            ..GameRecord::default()
+        };
+        (self.record.black, self.record.white) = if colour == BLACK {
+            (self.name.clone(), opponent_name.to_string())
+        } else {
+            (opponent_name.to_string(), self.name.clone())
+        };
+        self.state = if colour == BLACK {
+            MyTurn
+        } else {
+            OpponentTurn
+        };
+    }
+
+    fn end_game(&mut self, outcome: Outcome, own: u32, opponent: u32, reason: &str) {
+        log!("{} : {}", own, opponent);
+        match outcome {
+            Outcome::Win => log!("Win!"),
+            Outcome::Lose => log!("Lose..."),
+            Outcome::Tie => log!("Tie."),
+        }
+        log!("Ended reason: {}", reason);
+        self.engine
+            .game_over(&mut self.record, self.own_turn, (own, opponent), reason);
+        log!("{}", board_text(&self.board));
+        self.board = cmds_to_board("");
+        self.state = CardWaiting;
+    }
+
+    fn play_own_move(&mut self) -> Result<(), SessionError> {
+        let (pos, thinking_time) = self.engine.choose(&self.board, self.remaining_time);
+        self.record.moves.push(GameMove {
+            pos,
+            time: Some(thinking_time.as_secs_f32()),
+        });
+        self.engine.after_move(&self.record);
+        if pos == 0 {
+            log!("No legal command");
+            self.board.no_legal_command += 1;
+            self.board.turn = !self.board.turn;
+        } else {
+            log!("{}", pos_to_cmd(&pos));
+            self.board = execute_pos(&mut self.board, pos);
+        }
+        self.send(&Move(pos))?;
+        self.state = AckWaiting;
+        Ok(())
+    }
+
+    fn play_opponent_move(&mut self, pos: u64) -> Result<(), SessionError> {
+        if pos == 0 {
+            if legal(self.board) != 0 {
+                return Err(SessionError::IllegalMove(0));
+            }
+            self.board.no_legal_command += 1;
+            self.board.turn = !self.board.turn;
+        } else {
+            if !is_legal_pos(&self.board, &pos) {
+                return Err(SessionError::IllegalMove(pos));
+            }
+            self.board = execute_pos(&mut self.board, pos);
+        }
+        self.record.moves.push(GameMove { pos, time: None });
+        self.engine.after_move(&self.record);
+        self.state = MyTurn;
+        Ok(())
+    }
+
+    // Handles one message, or plays one own move.
+    pub fn step(&mut self) -> Result<(), SessionError> {
+        match self.state {
+            CardWaiting => match self.receive()? {
+                Start(colour, opponent_name, time) => self.start_game(colour, &opponent_name, time),
+                Bye(stat) => {
+                    log!("Stat: {}", stat);
+                    log!("Bybye.");
+                    self.state = Ended;
+                }
+                command => return Err(SessionError::Unexpected("waiting card", command)),
+            },
+            MyTurn => self.play_own_move()?,
+            AckWaiting => match self.receive()? {
+                Ack(time) => {
+                    self.remaining_time = time;
+                    log!("Remaining time: {}", time);
+                    self.state = OpponentTurn;
+                }
+                End(outcome, own, opponent, reason) => {
+                    self.end_game(outcome, own, opponent, &reason)
+                }
+                command => return Err(SessionError::Unexpected("waiting ack", command)),
+            },
+            OpponentTurn => match self.receive()? {
+                Move(pos) => self.play_opponent_move(pos)?,
+                End(outcome, own, opponent, reason) => {
+                    self.end_game(outcome, own, opponent, &reason)
+                }
+                command => return Err(SessionError::Unexpected("opponent turn", command)),
+            },
+            Ended => {}
+        }
+        Ok(())
+    }
+
+    // Opens the session and plays until BYE.
+    pub fn run(&mut self) -> Result<(), SessionError> {
+        let name = self.name.clone();
+        self.send(&Open(name))?;
+        while !matches!(self.state, Ended) {
+            self.step()?;
+        }
+        log!("Ended");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // A server that answers with a fixed script and records what it gets.
+    #[derive(Default)]
+    struct MockServer {
+        script: VecDeque<String>,
+        sent: Vec<String>,
+    }
+
+    impl MockServer {
+        fn new(script: &[&str]) -> MockServer {
+            MockServer {
+                script: script.iter().map(|line| format!("{}\n", line)).collect(),
+                sent: vec![],
+            }
+        }
+    }
+
+    impl Transport for MockServer {
+        fn receive(&mut self) -> io::Result<Option<String>> {
+            Ok(self.script.pop_front())
+        }
+
+        fn send(&mut self, mes: &str) -> io::Result<()> {
+            self.sent.push(mes.trim_end().to_string());
+            Ok(())
+        }
+    }
+
+    // Plays the given moves in order, then the first legal move, and passes
+    // when it has no move.
+    #[derive(Default)]
+    struct ScriptedEngine {
+        moves: VecDeque<u64>,
+        results: Vec<(Option<i32>, String)>,
+    }
+
+    impl ScriptedEngine {
+        fn new(cmds: &[&str]) -> ScriptedEngine {
+            ScriptedEngine {
+                moves: cmds.iter().map(|cmd| cmd_to_pos(cmd.to_string())).collect(),
+                results: vec![],
+            }
+        }
+    }
+
+    impl Engine for ScriptedEngine {
+        fn choose(&mut self, board: &Board, _remaining_time: u64) -> (u64, Duration) {
+            if legal(*board) == 0 {
+                return (0, Duration::ZERO);
+            }
+            let pos = self
+                .moves
+                .pop_front()
+                .unwrap_or_else(|| legal_poss(board)[0]);
+            (pos, Duration::ZERO)
+        }
+
+        fn game_over(
+            &mut self,
+            record: &mut GameRecord,
+            own_turn: bool,
+            discs: (u32, u32),
+            reason: &str,
+        ) {
+            let (own, opponent) = (discs.0 as i32, discs.1 as i32);
+            record.result = Some(if own_turn == BLACK {
+                own - opponent
+            } else {
+                opponent - own
+            });
+            self.results.push((record.result, reason.to_string()));
+        }
+    }
+
+    fn scripted_session(
+        script: &[&str],
+        moves: &[&str],
+    ) -> ClientSession<MockServer, ScriptedEngine> {
+        ClientSession::new(MockServer::new(script), ScriptedEngine::new(moves), "me")
+    }
+
+    #[test]
+    fn plays_a_game_and_says_goodbye() {
+        let mut session = scripted_session(
+            &[
+                "START BLACK opponent 60000",
+                "ACK 59000",
+                "MOVE D6",
+                "ACK 58000",
+                "END WIN 3 0 RESIGN",
+                "BYE me 1 0 0",
+            ],
+            &["F5", "C3"],
+        );
+        session.run().unwrap();
+        assert_eq!(session.transport.sent, ["OPEN me", "MOVE F5", "MOVE C3"]);
+        assert_eq!(session.remaining_time, 58000);
+        assert_eq!(session.record.black, "me");
+        assert_eq!(session.record.moves.len(), 3);
+        assert_eq!(session.engine.results, [(Some(3), "RESIGN".to_string())]);
+        assert!(matches!(session.state, Ended));
+    }
+
+    // After D3 C3 B3 B2 F5 A3 A1 C1 black has no move.
+    #[test]
+    fn opponent_passes() {
+        let mut session = scripted_session(
+            &[
+                "START WHITE opponent 60000",
+                "MOVE D3",
+                "ACK 60000",
+                "MOVE B3",
+                "ACK 60000",
+                "MOVE F5",
+                "ACK 60000",
+                "MOVE A1",
+                "ACK 60000",
+                "MOVE PASS",
+                "ACK 60000",
+                "END LOSE 4 8 TIMEOUT",
+                "BYE",
+            ],
+            &["C3", "B2", "A3", "C1"],
+        );
+        session.run().unwrap();
+        assert_eq!(session.transport.sent.len(), 6);
+        assert_ne!(session.transport.sent[5], "MOVE PASS");
+        assert_eq!(session.record.moves[8], GameMove::default());
+        assert_eq!(session.engine.results.len(), 1);
+    }
+
+    #[test]
+    fn own_pass_is_sent() {
+        let mut session = scripted_session(
+            &[
+                "START BLACK opponent 60000",
+                "ACK 60000",
+                "MOVE C3",
+                "ACK 60000",
+                "MOVE B2",
+                "ACK 60000",
+                "MOVE A3",
+                "ACK 60000",
+                "MOVE C1",
+                "ACK 60000",
+                "END LOSE 2 10 DOUBLE_PASS",
+                "BYE",
+            ],
+            &["D3", "B3", "F5", "A1"],
+        );
+        session.run().unwrap();
+        assert_eq!(session.transport.sent.last().unwrap(), "MOVE PASS");
+        assert_eq!(
+            session.engine.results,
+            [(Some(-8), "DOUBLE_PASS".to_string())]
+        );
+    }
+
+    #[test]
+    fn illegal_moves_are_rejected() {
+        let mut session = scripted_session(&["START WHITE opponent 60000", "MOVE A1"], &[]);
+        let error = session.run().unwrap_err();
+        assert!(matches!(error, SessionError::IllegalMove(pos) if pos == 1));
+
+        let mut session = scripted_session(&["START WHITE opponent 60000", "MOVE PASS"], &[]);
+        let error = session.run().unwrap_err();
+        assert!(matches!(error, SessionError::IllegalMove(0)));
+    }
+
+    #[test]
+    // The client reads nothing while it thinks, so an END sent during its own
+    // turn is read after its move, in place of the ACK.
+    fn end_instead_of_ack_or_move() {
+        let mut session = scripted_session(
+            &[
+                "START BLACK opponent 60000",
+                "END LOSE 0 64 TIMEOUT",
+                "START WHITE opponent 60000",
+                "END WIN 64 0 ILLEGAL_MOVE",
+                "BYE",
+            ],
+            &["F5"],
+        );
+        session.run().unwrap();
+        assert_eq!(session.transport.sent, ["OPEN me", "MOVE F5"]);
+        assert_eq!(
+            session.engine.results,
+            [
+                (Some(-64), "TIMEOUT".to_string()),
+                (Some(-64), "ILLEGAL_MOVE".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_and_unexpected_messages() {
+        let mut session = scripted_session(&["HELLO"], &[]);
+        assert!(matches!(
+            session.run().unwrap_err(),
+            SessionError::Protocol(ProtocolError::UnknownCommand(_), _)
+        ));
+
+        let mut session = scripted_session(&["ACK 1000"], &[]);
+        assert!(matches!(
+            session.run().unwrap_err(),
+            SessionError::Unexpected("waiting card", Ack(1000))
+        ));
+
+        let mut session = scripted_session(&["START BLACK opponent 60000"], &["F5"]);
+        assert!(matches!(session.run().unwrap_err(), SessionError::Closed));
+    }
+}
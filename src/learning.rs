@@ -1 +1,8 @@
+pub mod checkpoint;
+pub mod config;
+pub mod dataset;
 pub mod learning;
+pub mod nnue;
+pub mod relabel;
+pub mod self_play;
+pub mod trainer;
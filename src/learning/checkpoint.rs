@@ -0,0 +1,186 @@
+use crate::learning::trainer::*;
+use crate::util::util::*;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/*
+
+checkpoint file (little endian)
+
+"RVCK"  version u32
+epoch u32  (next epoch to run)
+stale u32  (epochs since the best validation loss)
+step i32   (optimiser step)
+best_loss f32
+size u32
+weights [f32; size]  first moment [f32; size]  second moment [f32; size]
+
+*/
+
+const CHECKPOINT_MAGIC: &[u8; 4] = b"RVCK";
+const CHECKPOINT_VERSION: u32 = 1;
+
+pub struct Checkpoint {
+    pub epoch: usize,
+    pub stale: usize,
+    pub best_loss: f32,
+    pub weights: Vec<f32>,
+    pub state: OptimiserState,
+}
+
+fn write_f32s(bytes: &mut Vec<u8>, values: &[f32]) {
+    for value in values {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: &mut usize) -> io::Result<u32> {
+    let end = *offset + 4;
+    let slice = bytes
+        .get(*offset..end)
+        .ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated"))?;
+    *offset = end;
+    Ok(u32::from_le_bytes(slice.try_into().unwrap()))
+}
+
+fn read_f32s(bytes: &[u8], offset: &mut usize, size: usize) -> io::Result<Vec<f32>> {
+    (0..size)
+        .map(|_| read_u32(bytes, offset).map(f32::from_bits))
+        .collect()
+}
+
+// Written to a temporary file first so a crash never leaves a broken
+// checkpoint behind.
+pub fn save_checkpoint(
+    path: &str,
+    epoch: usize,
+    stale: usize,
+    best_loss: f32,
+    model: &Model,
+    state: &OptimiserState,
+) -> io::Result<()> {
+    let mut bytes: Vec<u8> = vec![];
+    bytes.extend_from_slice(CHECKPOINT_MAGIC);
+    bytes.extend_from_slice(&CHECKPOINT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(epoch as u32).to_le_bytes());
+    bytes.extend_from_slice(&(stale as u32).to_le_bytes());
+    bytes.extend_from_slice(&state.step.to_le_bytes());
+    bytes.extend_from_slice(&best_loss.to_le_bytes());
+    bytes.extend_from_slice(&(model.weights.len() as u32).to_le_bytes());
+    write_f32s(&mut bytes, &model.weights);
+    write_f32s(&mut bytes, &state.first);
+    write_f32s(&mut bytes, &state.second);
+
+    write_atomically(path, |file| file.write_all(&bytes))
+}
+
+pub fn load_checkpoint(path: &str) -> io::Result<Checkpoint> {
+    let mut bytes: Vec<u8> = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    if bytes.len() < 4 || &bytes[..4] != CHECKPOINT_MAGIC {
+        return Err(invalid("not a checkpoint file"));
+    }
+    let mut offset = 4;
+    if read_u32(&bytes, &mut offset)? != CHECKPOINT_VERSION {
+        return Err(invalid("unsupported checkpoint version"));
+    }
+    let epoch = read_u32(&bytes, &mut offset)? as usize;
+    let stale = read_u32(&bytes, &mut offset)? as usize;
+    let step = read_u32(&bytes, &mut offset)? as i32;
+    let best_loss = f32::from_bits(read_u32(&bytes, &mut offset)?);
+    let size = read_u32(&bytes, &mut offset)? as usize;
+    let weights = read_f32s(&bytes, &mut offset, size)?;
+    let first = read_f32s(&bytes, &mut offset, size)?;
+    let second = read_f32s(&bytes, &mut offset, size)?;
+    Ok(Checkpoint {
+        epoch,
+        stale,
+        best_loss,
+        weights,
+        state: OptimiserState {
+            first,
+            second,
+            step,
+        },
+    })
+}
+
+// Appends one CSV row per phase and epoch, writing the header on a new file.
+pub fn append_metrics(
+    path: &str,
+    phase: usize,
+    epoch: usize,
+    learning_rate: f32,
+    train_loss: (f32, f32),
+    validation_loss: (f32, f32),
+) -> io::Result<()> {
+    let is_new = !Path::new(path).exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if is_new {
+        writeln!(
+            file,
+            "phase,epoch,learning_rate,train_mae,train_mse,validation_mae,validation_mse"
+        )?;
+    }
+    writeln!(
+        file,
+        "{},{},{},{},{},{},{}",
+        phase,
+        epoch,
+        learning_rate,
+        train_loss.0,
+        train_loss.1,
+        validation_loss.0,
+        validation_loss.1
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round_trip.ckpt");
+        let mut model = Model::new(PatternSet::Mini, true);
+        let size = model.weights.len();
+        for (i, weight) in model.weights.iter_mut().enumerate() {
+            *weight = i as f32 * 0.5 - 7.0;
+        }
+        let mut state = OptimiserState::new(size);
+        state.first.iter_mut().for_each(|v| *v = 0.25);
+        state.second.iter_mut().for_each(|v| *v = 1.5);
+        state.step = 42;
+        save_checkpoint(&path, 3, 1, 0.125, &model, &state).unwrap();
+
+        let checkpoint = load_checkpoint(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoint.epoch, 3);
+        assert_eq!(checkpoint.stale, 1);
+        assert_eq!(checkpoint.best_loss, 0.125);
+        assert_eq!(checkpoint.weights, model.weights);
+        assert_eq!(checkpoint.state.first, state.first);
+        assert_eq!(checkpoint.state.second, state.second);
+        assert_eq!(checkpoint.state.step, 42);
+    }
+
+    #[test]
+    fn broken_files_are_rejected() {
+        let path = temp_path("broken.ckpt");
+        fs::write(&path, b"RVBK\x01\x00\x00\x00").unwrap();
+        let magic = load_checkpoint(&path).err().unwrap();
+        let model = Model::new(PatternSet::Mini, true);
+        let state = OptimiserState::new(model.weights.len());
+        save_checkpoint(&path, 0, 0, 0.0, &model, &state).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let truncated = load_checkpoint(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(magic.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(truncated.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
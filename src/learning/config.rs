@@ -0,0 +1,444 @@
+use crate::learning::trainer::*;
+use crate::record::loader::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+
+// Training config, a small subset of TOML:
+//
+// [data]
+// train = ["self_play/*.txt", "wthor_*.txt"]
+// validation = ["self_play/0000019.txt"]
+// # or hold out a share of the training records instead
+// validation_split = 0.05
+// # stream the training positions from a dataset instead of `train`
+// dataset = "dataset.bin"
+//
+// [train]
+// pattern_set = "main"
+// side_to_move = false
+// phases = 1
+// optimiser = "adam"
+// learning_rate = 0.01
+// batch_size = 256
+// l2 = 0.0
+// schedule = "cosine"
+// epoch = 100
+// seed = 0
+// output = "train_result4.txt"
+// checkpoint = "train.ckpt"
+// checkpoint_every = 1
+// resume = false
+// patience = 0
+// metrics = "metrics.csv"
+// threads = 0
+// strict = false
+// label_exact = 14
+// label_depth = 4
+// label_lambda = 0.5
+// label_model = "train_result4.txt"
+// label_cache = "label_cache"
+// stream_block = 1048576
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    Text(String),
+    List(Vec<String>),
+}
+
+#[derive(Clone, Debug)]
+pub struct TrainConfig {
+    pub option: TrainOption,
+    pub train: Vec<String>,
+    pub validation: Vec<String>,
+    pub validation_split: f32,
+}
+
+fn parse_string(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        Some(value[1..value.len() - 1].to_string())
+    } else {
+        None
+    }
+}
+
+fn parse_value(value: &str) -> Option<ConfigValue> {
+    let value = value.trim();
+    if let Some(inner) = value.strip_prefix('[') {
+        let inner = inner.strip_suffix(']')?;
+        let mut items: Vec<String> = vec![];
+        for item in split_list(inner) {
+            if item.trim().is_empty() {
+                continue;
+            }
+            items.push(parse_string(item)?);
+        }
+        Some(ConfigValue::List(items))
+    } else if let Some(text) = parse_string(value) {
+        Some(ConfigValue::Text(text))
+    } else if value.is_empty() || value.contains(char::is_whitespace) {
+        None
+    } else {
+        // numbers and booleans are kept as written
+        Some(ConfigValue::Text(value.to_string()))
+    }
+}
+
+// Splits list items on the commas outside quotes.
+fn split_list(inner: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&inner[start..]);
+    items
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// Returns `(line number, "section.key", value)` for every assignment.
+pub fn parse_config(text: &str) -> Result<Vec<(usize, String, ConfigValue)>, Vec<String>> {
+    let mut entries = vec![];
+    let mut errors = vec![];
+    let mut section = String::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_string();
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) => match parse_value(value) {
+                Some(value) => {
+                    entries.push((line_number, format!("{}.{}", section, key.trim()), value))
+                }
+                None => errors.push(format!("line {}: invalid value: {}", line_number, value)),
+            },
+            None => errors.push(format!("line {}: expected key = value", line_number)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(entries)
+    } else {
+        Err(errors)
+    }
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+// Expands `*` and `?` in any path component. The result is sorted so the
+// record order does not depend on the file system.
+pub fn glob(pattern: &str) -> Vec<String> {
+    let absolute = pattern.starts_with('/');
+    let mut paths: Vec<String> = vec![if absolute {
+        "/".to_string()
+    } else {
+        String::new()
+    }];
+    for component in pattern.split('/').filter(|c| !c.is_empty()) {
+        let mut next: Vec<String> = vec![];
+        for base in &paths {
+            if !component.contains(['*', '?']) {
+                next.push(format!("{}{}", base, component));
+                continue;
+            }
+            let dir = if base.is_empty() { "." } else { base.as_str() };
+            if let Ok(entries) = fs::read_dir(dir) {
+                for entry in entries.flatten() {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    if wildcard_match(component.as_bytes(), name.as_bytes()) {
+                        next.push(format!("{}{}", base, name));
+                    }
+                }
+            }
+        }
+        paths = next.into_iter().map(|p| p + "/").collect();
+    }
+    let mut paths: Vec<String> = paths
+        .into_iter()
+        .map(|p| p.trim_end_matches('/').to_string())
+        .filter(|p| Path::new(p).is_file())
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn expand_globs(patterns: &[String], key: &str, errors: &mut Vec<String>) -> Vec<String> {
+    let mut paths = vec![];
+    for pattern in patterns {
+        let matched = glob(pattern);
+        if matched.is_empty() {
+            errors.push(format!("{}: no file matches \"{}\"", key, pattern));
+        }
+        for path in matched {
+            if let Err(e) = File::open(&path) {
+                errors.push(format!("{}: cannot read {} ({})", key, path, e));
+            }
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+// Reads and validates the whole config, including that every dataset glob
+// matches readable files, so a bad config fails before any training starts.
+pub fn load_train_config(path: &str) -> Result<TrainConfig, Vec<String>> {
+    let text = fs::read_to_string(path).map_err(|e| vec![format!("{}: {}", path, e)])?;
+    let entries = parse_config(&text)?;
+    let mut option = TrainOption::default();
+    let mut train_patterns: Vec<String> = vec![];
+    let mut validation_patterns: Vec<String> = vec![];
+    let mut validation_split = 0.0;
+    let mut errors: Vec<String> = vec![];
+
+    for (line_number, key, value) in entries {
+        match (key.as_str(), value) {
+            ("data.train", ConfigValue::List(list)) => train_patterns = list,
+            ("data.train", ConfigValue::Text(text)) => train_patterns = vec![text],
+            ("data.validation", ConfigValue::List(list)) => validation_patterns = list,
+            ("data.validation", ConfigValue::Text(text)) => validation_patterns = vec![text],
+            ("data.dataset", ConfigValue::Text(text)) => option.dataset = text,
+            ("data.validation_split", ConfigValue::Text(text)) => match text.parse() {
+                Ok(split) => validation_split = split,
+                Err(_) => errors.push(format!(
+                    "line {}: invalid validation_split: {}",
+                    line_number, text
+                )),
+            },
+            (key, ConfigValue::Text(text)) if key.starts_with("train.") => {
+                if let Err(e) = option.set(&key["train.".len()..], &text) {
+                    errors.push(format!("line {}: {}", line_number, e));
+                }
+            }
+            (key, _) => errors.push(format!("line {}: unknown key {}", line_number, key)),
+        }
+    }
+
+    if train_patterns.is_empty() && option.dataset.is_empty() {
+        errors.push("data.train is empty".to_string());
+    }
+    if !option.dataset.is_empty() {
+        if !Path::new(&option.dataset).is_file() {
+            errors.push(format!("data.dataset: {} does not exist", option.dataset));
+        }
+        if validation_patterns.is_empty() {
+            errors.push("data.dataset needs data.validation".to_string());
+        }
+    }
+    if !(0.0..1.0).contains(&validation_split) {
+        errors.push("data.validation_split must be in [0, 1)".to_string());
+    }
+    if validation_patterns.is_empty() && validation_split == 0.0 {
+        errors.push("either data.validation or data.validation_split is needed".to_string());
+    }
+    if option.epoch == 0 || option.batch_size == 0 || option.phases == 0 {
+        errors.push("train.epoch, train.batch_size and train.phases must be positive".to_string());
+    }
+    if option.label_depth > 0 && option.label_model.is_empty() {
+        errors.push("train.label_depth needs train.label_model".to_string());
+    } else if option.label_depth > 0 && option.phases > 0 {
+        // The same files `Labeller::new` loads.
+        for phase in 0..option.phases {
+            let path = phase_output(&option.label_model, phase, option.phases);
+            if !Path::new(&path).is_file() {
+                errors.push(format!("train.label_model: {} does not exist", path));
+            }
+        }
+    }
+    if !(0.0..=1.0).contains(&option.label_lambda) {
+        errors.push("train.label_lambda must be in [0, 1]".to_string());
+    }
+    if option.learning_rate <= 0.0 || option.l2 < 0.0 {
+        errors.push("train.learning_rate must be positive and train.l2 non-negative".to_string());
+    }
+    for (key, path) in [
+        ("train.output", &option.output),
+        ("train.checkpoint", &option.checkpoint),
+        ("train.metrics", &option.metrics),
+    ] {
+        let parent = Path::new(path).parent();
+        if let Some(parent) = parent.filter(|p| !p.as_os_str().is_empty()) {
+            if !parent.is_dir() {
+                errors.push(format!("{}: {} is not a directory", key, parent.display()));
+            }
+        }
+    }
+    let train = expand_globs(&train_patterns, "data.train", &mut errors);
+    let validation = expand_globs(&validation_patterns, "data.validation", &mut errors);
+
+    if errors.is_empty() {
+        Ok(TrainConfig {
+            option,
+            train,
+            validation,
+            validation_split,
+        })
+    } else {
+        Err(errors)
+    }
+}
+
+pub fn train_from_config(path: &str) {
+    let config = match load_train_config(path) {
+        Ok(config) => config,
+        Err(errors) => {
+            for e in errors {
+                println!("{}", e);
+            }
+            println!("Config is invalid, training is not started.");
+            return;
+        }
+    };
+    println!("train files: {:?}", config.train);
+    println!("validation files: {:?}", config.validation);
+    println!("{:?}", config.option);
+
+    let strict = config.option.strict;
+    let (mut records, mut validation_records) = match load_records(&config.train, strict)
+        .and_then(|records| Ok((records, load_records(&config.validation, strict)?)))
+    {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            println!("{}", e);
+            println!("Training is not started.");
+            return;
+        }
+    };
+    if config.validation_split > 0.0 {
+        let mut rng = StdRng::seed_from_u64(config.option.seed);
+        let (held_out, kept): (Vec<String>, Vec<String>) = records
+            .into_iter()
+            .partition(|_| rng.gen::<f32>() < config.validation_split);
+        records = kept;
+        validation_records.extend(held_out);
+    }
+    train_batches(&config.option, &records, &validation_records);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::util::temp_path;
+
+    #[test]
+    fn parse_config_reads_sections_lists_and_comments() {
+        let text = "[data]\n\
+                    train = [\"a,b/*.txt\", \"c#1.txt\"] # two patterns\n\
+                    \n\
+                    [train]\n\
+                    epoch = 3\n";
+        let entries = parse_config(text).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    2,
+                    "data.train".to_string(),
+                    ConfigValue::List(vec!["a,b/*.txt".to_string(), "c#1.txt".to_string()])
+                ),
+                (
+                    5,
+                    "train.epoch".to_string(),
+                    ConfigValue::Text("3".to_string())
+                ),
+            ]
+        );
+        let errors = parse_config("[train]\nepoch\nseed = 1 2\n").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "line 2: expected key = value".to_string(),
+                "line 3: invalid value:  1 2".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn wildcard_match_handles_stars_and_question_marks() {
+        let matches =
+            |pattern: &str, name: &str| wildcard_match(pattern.as_bytes(), name.as_bytes());
+        assert!(matches("*.txt", "0000001.txt"));
+        assert!(matches("wthor_*_*.txt", "wthor_2005_2009.txt"));
+        assert!(!matches("000000?.txt", "0000019.txt"));
+        assert!(matches("00000??.txt", "0000019.txt"));
+        assert!(matches("*", ""));
+        assert!(!matches("*.txt", "book.bin"));
+        assert!(!matches("?", ""));
+    }
+
+    #[test]
+    fn load_train_config_reports_every_problem() {
+        let record = temp_path("config_record.txt");
+        fs::write(&record, "f5d6c3d3c4\n").unwrap();
+        let config = temp_path("valid.toml");
+        fs::write(
+            &config,
+            format!("[data]\ntrain = [\"{}\"]\nvalidation_split = 0.1\n", record),
+        )
+        .unwrap();
+        let loaded = load_train_config(&config).unwrap();
+        assert_eq!(loaded.train, vec![record.clone()]);
+        assert_eq!(loaded.validation_split, 0.1);
+
+        fs::write(
+            &config,
+            "[data]\n\
+             train = [\"/nonexistent/*.txt\"]\n\
+             [train]\n\
+             epoch = 0\n\
+             label_depth = 2\n\
+             colour = 1\n",
+        )
+        .unwrap();
+        let errors = load_train_config(&config).unwrap_err();
+        fs::remove_file(&record).unwrap();
+        fs::remove_file(&config).unwrap();
+        assert_eq!(
+            errors,
+            vec![
+                "line 6: unknown option: colour".to_string(),
+                "either data.validation or data.validation_split is needed".to_string(),
+                "train.epoch, train.batch_size and train.phases must be positive".to_string(),
+                "train.label_depth needs train.label_model".to_string(),
+                "data.train: no file matches \"/nonexistent/*.txt\"".to_string(),
+            ]
+        );
+    }
+}
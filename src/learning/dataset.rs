@@ -0,0 +1,353 @@
+use crate::learning::learning::*;
+use crate::learning::relabel::*;
+use crate::learning::trainer::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/*
+
+position dataset (little endian)
+
+"RVDS"  version u32
+phases u32
+counts [u64; phases]  (positions per phase)
+positions, grouped by phase and shuffled within a phase:
+    black u64  white u64  side u8 (1 = black to move)  phase u8  target f32
+
+*/
+
+const DATASET_MAGIC: &[u8; 4] = b"RVDS";
+const DATASET_VERSION: u32 = 1;
+const POSITION_SIZE: usize = 22;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    pub black: u64,
+    pub white: u64,
+    pub turn: bool,
+    pub phase: u8,
+    // Disc difference from black's point of view.
+    pub target: f32,
+}
+
+impl Position {
+    pub fn board(&self) -> Board {
+        Board::new(self.black, self.white, self.turn)
+    }
+
+    fn to_bytes(self) -> [u8; POSITION_SIZE] {
+        let mut bytes = [0; POSITION_SIZE];
+        bytes[0..8].copy_from_slice(&self.black.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.white.to_le_bytes());
+        bytes[16] = self.turn as u8;
+        bytes[17] = self.phase;
+        bytes[18..22].copy_from_slice(&self.target.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Position {
+        Position {
+            black: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            white: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            turn: bytes[16] != 0,
+            phase: bytes[17],
+            target: f32::from_le_bytes(bytes[18..22].try_into().unwrap()),
+        }
+    }
+}
+
+// The smallest (black, white) over the 8 symmetries.
+pub fn canonical_discs(black: u64, white: u64) -> (u64, u64) {
+    (0..8)
+        .map(|k| (symmetric_pos(black, k), symmetric_pos(white, k)))
+        .min()
+        .unwrap()
+}
+
+// Distinct symmetric copies of a position, itself included.
+fn symmetric_positions(position: &Position) -> Vec<Position> {
+    let mut copies: Vec<Position> = vec![];
+    for k in 0..8 {
+        let copy = Position {
+            black: symmetric_pos(position.black, k),
+            white: symmetric_pos(position.white, k),
+            ..*position
+        };
+        if !copies
+            .iter()
+            .any(|c| c.black == copy.black && c.white == copy.white)
+        {
+            copies.push(copy);
+        }
+    }
+    copies
+}
+
+#[derive(Clone, Debug)]
+pub struct DatasetOption {
+    pub output: String,
+    // Store every distinct symmetric copy instead of the canonical one only.
+    pub augment: bool,
+    // `phases`, `seed`, `threads`, `strict` and the `label_*` settings.
+    pub train: TrainOption,
+}
+
+impl Default for DatasetOption {
+    fn default() -> DatasetOption {
+        DatasetOption {
+            output: "dataset.bin".to_string(),
+            augment: false,
+            train: TrainOption::default(),
+        }
+    }
+}
+
+impl DatasetOption {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "output" => self.output = value.to_string(),
+            "augment" => self.augment = option_value(key, value)?,
+            _ => self.train.set(key, value)?,
+        }
+        Ok(())
+    }
+}
+
+// Canonicalises the positions of `records` and merges duplicates by
+// averaging their targets. The result is grouped by phase and shuffled with
+// `seed`, so the same records always give the same file.
+pub fn build_positions(
+    option: &DatasetOption,
+    records: &[String],
+) -> Result<Vec<Position>, String> {
+    let train = &option.train;
+    let targets = if train.label_depth > 0 || train.label_exact > 0 {
+        Some(relabel_records(train, records)?)
+    } else {
+        None
+    };
+    let indexes: Vec<usize> = (0..records.len()).collect();
+    let replayed = parallel_map(&indexes, thread_count(train.threads), |&i| {
+        let (boards, final_diff) = replay_record(&records[i]);
+        boards
+            .iter()
+            .enumerate()
+            .map(|(j, board)| {
+                let target = targets
+                    .as_ref()
+                    .map_or(final_diff as f32, |targets| targets[i][j]);
+                let (black, white) = canonical_discs(board.black_board, board.white_board);
+                ((black, white, board.turn), target)
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut merged: HashMap<(u64, u64, bool), (f64, u32)> = HashMap::new();
+    let mut total = 0;
+    for (key, target) in replayed.into_iter().flatten() {
+        let entry = merged.entry(key).or_insert((0.0, 0));
+        entry.0 += target as f64;
+        entry.1 += 1;
+        total += 1;
+    }
+    let mut positions: Vec<Position> = merged
+        .into_iter()
+        .map(|((black, white, turn), (sum, count))| {
+            let mut position = Position {
+                black,
+                white,
+                turn,
+                phase: 0,
+                target: (sum / count as f64) as f32,
+            };
+            position.phase = board_phase(&position.board(), train.phases) as u8;
+            position
+        })
+        .collect();
+    println!(
+        "positions: {}  distinct: {}  duplicates merged: {}",
+        total,
+        positions.len(),
+        total - positions.len()
+    );
+    if option.augment {
+        positions = positions.iter().flat_map(symmetric_positions).collect();
+        println!("positions after augmentation: {}", positions.len());
+    }
+
+    positions.sort_unstable_by_key(|p| (p.phase, p.black, p.white, p.turn));
+    let mut rng = StdRng::seed_from_u64(train.seed);
+    let mut start = 0;
+    while start < positions.len() {
+        let phase = positions[start].phase;
+        let end = positions[start..]
+            .iter()
+            .position(|p| p.phase != phase)
+            .map_or(positions.len(), |len| start + len);
+        positions[start..end].shuffle(&mut rng);
+        start = end;
+    }
+    Ok(positions)
+}
+
+pub fn write_dataset(path: &str, positions: &[Position], phases: usize) -> io::Result<()> {
+    write_atomically(path, |file| {
+        file.write_all(DATASET_MAGIC)?;
+        file.write_all(&DATASET_VERSION.to_le_bytes())?;
+        file.write_all(&(phases as u32).to_le_bytes())?;
+        for phase in 0..phases {
+            let count = positions
+                .iter()
+                .filter(|p| p.phase as usize == phase)
+                .count();
+            file.write_all(&(count as u64).to_le_bytes())?;
+        }
+        for position in positions {
+            file.write_all(&position.to_bytes())?;
+        }
+        Ok(())
+    })
+}
+
+// An opened dataset. Positions are read on demand, so the file can be larger
+// than memory.
+pub struct Dataset {
+    pub path: String,
+    pub counts: Vec<u64>,
+}
+
+impl Dataset {
+    pub fn open(path: &str) -> io::Result<Dataset> {
+        let invalid =
+            |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        let mut file = File::open(path)?;
+        let mut header = [0; 12];
+        file.read_exact(&mut header)?;
+        if &header[..4] != DATASET_MAGIC {
+            return Err(invalid("not a dataset file"));
+        }
+        if u32::from_le_bytes(header[4..8].try_into().unwrap()) != DATASET_VERSION {
+            return Err(invalid("unsupported dataset version"));
+        }
+        let phases = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let mut counts = vec![0; phases];
+        for count in counts.iter_mut() {
+            let mut bytes = [0; 8];
+            file.read_exact(&mut bytes)?;
+            *count = u64::from_le_bytes(bytes);
+        }
+        let expected = 12 + 8 * phases as u64 + counts.iter().sum::<u64>() * POSITION_SIZE as u64;
+        if file.metadata()?.len() != expected {
+            return Err(invalid("file size does not match the header"));
+        }
+        Ok(Dataset {
+            path: path.to_string(),
+            counts,
+        })
+    }
+
+    pub fn phases(&self) -> usize {
+        self.counts.len()
+    }
+
+    // Reads `len` positions of `phase` starting at its `start`-th one.
+    pub fn read(&self, phase: usize, start: u64, len: usize) -> io::Result<Vec<Position>> {
+        let len = len.min(self.counts[phase].saturating_sub(start) as usize);
+        let first = self.counts[..phase].iter().sum::<u64>() + start;
+        let offset = 12 + 8 * self.phases() as u64 + first * POSITION_SIZE as u64;
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; len * POSITION_SIZE];
+        file.read_exact(&mut bytes)?;
+        Ok(bytes
+            .chunks(POSITION_SIZE)
+            .map(Position::from_bytes)
+            .collect())
+    }
+}
+
+pub fn make_dataset(option: &DatasetOption, records: &[String]) -> Result<(), String> {
+    let positions = build_positions(option, records)?;
+    write_dataset(&option.output, &positions, option.train.phases)
+        .map_err(|e| format!("{}: {}", option.output, e))?;
+    println!("Wrote {} positions to {}.", positions.len(), option.output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records that play the same first 16 moves and then part with each
+    // legal move, together with the disc difference each one ends on.
+    fn branching_records() -> Vec<(String, i32)> {
+        let mut board = cmds_to_board("");
+        let mut prefix = String::new();
+        for _ in 0..16 {
+            let pos = legal_poss(&board)[0];
+            prefix += &pos_to_cmd(&pos).to_lowercase();
+            board = execute_pos(&mut board, pos);
+        }
+        legal_poss(&board)
+            .into_iter()
+            .map(|pos| {
+                let record = prefix.clone() + &pos_to_cmd(&pos).to_lowercase();
+                let (_, final_diff) = replay_record(&record);
+                (record, final_diff)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn duplicates_are_merged_by_averaging() {
+        let branches = branching_records();
+        let records: Vec<String> = branches.iter().map(|(r, _)| r.clone()).collect();
+        let positions = build_positions(&DatasetOption::default(), &records).unwrap();
+
+        // The 16th position is shared by every record.
+        let (boards, _) = replay_record(&records[0]);
+        let (black, white) = canonical_discs(boards[0].black_board, boards[0].white_board);
+        let shared: Vec<&Position> = positions
+            .iter()
+            .filter(|p| p.black == black && p.white == white)
+            .collect();
+        let sum: i32 = branches.iter().map(|(_, diff)| diff).sum();
+        let mean = (sum as f64 / branches.len() as f64) as f32;
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].target, mean);
+        assert!(positions.len() <= branches.len() + 1);
+        let keys: Vec<(u64, u64, bool)> = positions
+            .iter()
+            .map(|p| (p.black, p.white, p.turn))
+            .collect();
+        assert!(keys.iter().enumerate().all(|(i, k)| !keys[..i].contains(k)));
+    }
+
+    #[test]
+    fn augment_yields_only_distinct_copies() {
+        let position = |board: Board| Position {
+            black: board.black_board,
+            white: board.white_board,
+            turn: board.turn,
+            phase: 0,
+            target: 2.0,
+        };
+        // The start position is symmetric under 4 of the 8 symmetries, the
+        // position after the first move under none.
+        for (cmds, count) in [("", 2), ("f5", 8)] {
+            let copies = symmetric_positions(&position(cmds_to_board(cmds)));
+            assert_eq!(copies.len(), count, "{}", cmds);
+            for (i, copy) in copies.iter().enumerate() {
+                assert!(!copies[..i].contains(copy));
+                assert_eq!(copy.target, 2.0);
+            }
+        }
+    }
+}
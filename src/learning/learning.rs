@@ -1,8 +1,10 @@
 use crate::parameter::parameter::*;
+use crate::record::loader::*;
 use crate::reversi::reversi::*;
+use crate::util::util::*;
 use std::fs::File;
 use std::io::Write;
-use std::io::{BufRead, BufReader};
+use std::thread;
 
 /*
 
@@ -32,7 +34,141 @@ type P0Index = (usize, usize, usize, usize);
 type P1Index = (usize, usize);
 type P2Index = (usize, usize, usize, usize);
 type PatternIndex = (P0Index, P1Index, P2Index);
-type PatternValue = (Vec<f32>, Vec<f32>, Vec<f32>);
+pub type PatternValue = (Vec<f32>, Vec<f32>, Vec<f32>);
+pub type PatternShares = (PatternShare, PatternShare, PatternShare);
+
+// mobility, potential mobility, frontier discs, parity, stable discs, corners
+pub const SCALAR_NUM: usize = 6;
+pub type ScalarFeature = [f32; SCALAR_NUM];
+pub type ScalarValue = [f32; SCALAR_NUM];
+const SCALAR_NAMES: [&str; SCALAR_NUM] = [
+    "mobility",
+    "potential mobility",
+    "frontier discs",
+    "parity",
+    "stable discs",
+    "corners",
+];
+// Brings each scalar feature to about [-1, 1], the range of a pattern
+// weight's input, so one learning rate suits both.
+const SCALAR_SCALE: [f32; SCALAR_NUM] = [
+    1.0 / 32.0,
+    1.0 / 32.0,
+    1.0 / 32.0,
+    1.0 / 4.0,
+    1.0 / 64.0,
+    1.0 / 4.0,
+];
+
+// Order in which each pattern's squares are read when the pattern is mirrored
+// along its own axis of symmetry.
+const P0_MIRROR: [usize; 10] = [7, 6, 5, 4, 3, 2, 1, 0, 9, 8];
+const P1_MIRROR: [usize; 8] = [7, 6, 5, 4, 3, 2, 1, 0];
+const P2_MIRROR: [usize; 10] = [0, 4, 7, 9, 1, 5, 8, 2, 6, 3];
+const P3_MIRROR: [usize; 8] = [7, 6, 5, 4, 3, 2, 1, 0];
+const P4_MIRROR: [usize; 8] = [7, 6, 5, 4, 3, 2, 1, 0];
+const P5_MIRROR: [usize; 8] = [0, 3, 6, 1, 4, 7, 2, 5];
+
+// Maps every raw pattern index to a shared weight slot. A sequence and its
+// mirrored reading always share a slot; with `colour` the colour-swapped twin
+// shares it too, with the opposite sign.
+pub struct PatternShare {
+    pub slot: Vec<usize>,
+    pub sign: Vec<f32>,
+    pub size: usize,
+}
+
+fn index_to_digits(index: usize, len: usize) -> Vec<usize> {
+    let mut digits = vec![0; len];
+    let mut rest = index;
+    for i in (0..len).rev() {
+        digits[i] = rest % 3;
+        rest /= 3;
+    }
+    digits
+}
+
+fn digits_to_index(digits: &[usize]) -> usize {
+    digits.iter().fold(0, |acc, &d| acc * 3 + d)
+}
+
+fn swap_colour(index: usize, len: usize) -> usize {
+    let digits: Vec<usize> = index_to_digits(index, len)
+        .iter()
+        .map(|&d| if d == 0 { 0 } else { 3 - d })
+        .collect();
+    digits_to_index(&digits)
+}
+
+impl PatternShare {
+    pub fn new(mirror: &[usize], colour: bool) -> PatternShare {
+        let len = mirror.len();
+        let table_size = 3usize.pow(len as u32);
+        let mut slot = vec![usize::MAX; table_size];
+        let mut sign = vec![0.0; table_size];
+        let mut size = 0;
+        for index in 0..table_size {
+            if slot[index] != usize::MAX {
+                continue;
+            }
+            let digits = index_to_digits(index, len);
+            let mirrored: Vec<usize> = mirror.iter().map(|&i| digits[i]).collect();
+            let twin = digits_to_index(&mirrored);
+            slot[index] = size;
+            slot[twin] = size;
+            sign[index] = 1.0;
+            sign[twin] = 1.0;
+            if colour {
+                let swapped = swap_colour(index, len);
+                let swapped_twin = swap_colour(twin, len);
+                slot[swapped] = size;
+                slot[swapped_twin] = size;
+                if swapped == index || swapped == twin {
+                    // w(x) = -w(x), so the weight of this class is always zero.
+                    sign[index] = 0.0;
+                    sign[twin] = 0.0;
+                } else {
+                    sign[swapped] = -1.0;
+                    sign[swapped_twin] = -1.0;
+                }
+            }
+            size += 1;
+        }
+        // The empty pattern only adds a constant and is never trained.
+        sign[0] = 0.0;
+        PatternShare { slot, sign, size }
+    }
+
+    #[inline]
+    pub fn value(&self, value: &[f32], index: usize) -> f32 {
+        value[self.slot[index]] * self.sign[index]
+    }
+
+    #[inline]
+    pub fn update(&self, value: &mut [f32], index: usize, delta: f32) {
+        value[self.slot[index]] += self.sign[index] * delta;
+    }
+
+    pub fn expand(&self, value: &[f32]) -> Vec<f32> {
+        (0..self.slot.len()).map(|i| self.value(value, i)).collect()
+    }
+}
+
+pub fn pattern_shares(side_to_move: bool) -> PatternShares {
+    (
+        PatternShare::new(&P0_MIRROR, side_to_move),
+        PatternShare::new(&P1_MIRROR, side_to_move),
+        PatternShare::new(&P2_MIRROR, side_to_move),
+    )
+}
+
+pub fn pattern_shares_mini(side_to_move: bool) -> PatternShares {
+    (
+        PatternShare::new(&P3_MIRROR, side_to_move),
+        PatternShare::new(&P4_MIRROR, side_to_move),
+        PatternShare::new(&P5_MIRROR, side_to_move),
+    )
+}
 
 #[inline]
 fn calculate_index(board: &Board, index: &usize, i: i32) -> usize {
@@ -84,7 +220,7 @@ pub fn pattern1_indexes(board: &Board) -> P1Index {
 
 pub fn pattern2_indexes(board: &Board) -> P2Index {
     let mut index0: usize = 0;
-    let positions0 = [0, 1, 2, 3, 8, 9, 10, 11, 16, 24];
+    let positions0 = [0, 1, 2, 3, 8, 9, 10, 16, 17, 24];
     for i in positions0 {
         index0 = calculate_index(board, &index0, i);
     }
@@ -106,55 +242,127 @@ pub fn pattern2_indexes(board: &Board) -> P2Index {
     (index0, index1, index2, index3)
 }
 
-pub fn evaluate_board_pattern(board: &Board, pattern_value: &PatternValue) -> (PatternIndex, f32) {
+pub fn evaluate_board_pattern(
+    board: &Board,
+    pattern_value: &PatternValue,
+    shares: &PatternShares,
+) -> (PatternIndex, f32) {
     let mut score: f32 = 0.0;
     let (p0v, p1v, p2v) = pattern_value;
+    let (p0s, p1s, p2s) = shares;
     let p0_indexes = pattern0_indexes(board);
     let p1_indexes = pattern1_indexes(board);
     let p2_indexes = pattern2_indexes(board);
 
     let (p0_0, p0_1, p0_2, p0_3) = p0_indexes;
-    score += p0v[p0_0];
-    score += p0v[p0_1];
-    score += p0v[p0_2];
-    score += p0v[p0_3];
+    score += p0s.value(p0v, p0_0);
+    score += p0s.value(p0v, p0_1);
+    score += p0s.value(p0v, p0_2);
+    score += p0s.value(p0v, p0_3);
 
     let (p1_0, p1_1) = p1_indexes;
-    score += p1v[p1_0];
-    score += p1v[p1_1];
+    score += p1s.value(p1v, p1_0);
+    score += p1s.value(p1v, p1_1);
 
     let (p2_0, p2_1, p2_2, p2_3) = p2_indexes;
-    score += p2v[p2_0];
-    score += p2v[p2_1];
-    score += p2v[p2_2];
-    score += p2v[p2_3];
+    score += p2s.value(p2v, p2_0);
+    score += p2s.value(p2v, p2_1);
+    score += p2s.value(p2v, p2_2);
+    score += p2s.value(p2v, p2_3);
 
     ((p0_indexes, p1_indexes, p2_indexes), score)
 }
 
 pub fn evaluate_board_pattern_score(board: &Board) -> i32 {
-    let mut score: i32 = 0;
-    let p0_indexes = pattern0_indexes(board);
-    let p1_indexes = pattern1_indexes(board);
-    let p2_indexes = pattern2_indexes(board);
-
-    let (p0_0, p0_1, p0_2, p0_3) = p0_indexes;
-    score += P0_VALUE[p0_0];
-    score += P0_VALUE[p0_1];
-    score += P0_VALUE[p0_2];
-    score += P0_VALUE[p0_3];
-
-    let (p1_0, p1_1) = p1_indexes;
-    score += P1_VALUE[p1_0];
-    score += P1_VALUE[p1_1];
+    let indexes = &board.pattern_indexes;
+    P0_VALUE[indexes[0]]
+        + P0_VALUE[indexes[1]]
+        + P0_VALUE[indexes[2]]
+        + P0_VALUE[indexes[3]]
+        + P1_VALUE[indexes[4]]
+        + P1_VALUE[indexes[5]]
+        + P2_VALUE[indexes[6]]
+        + P2_VALUE[indexes[7]]
+        + P2_VALUE[indexes[8]]
+        + P2_VALUE[indexes[9]]
+}
 
-    let (p2_0, p2_1, p2_2, p2_3) = p2_indexes;
-    score += P2_VALUE[p2_0];
-    score += P2_VALUE[p2_1];
-    score += P2_VALUE[p2_2];
-    score += P2_VALUE[p2_3];
+fn pattern_instance_value(p: usize, index: usize) -> i32 {
+    if p < 4 {
+        P0_VALUE[index]
+    } else if p < 6 {
+        P1_VALUE[index]
+    } else {
+        P2_VALUE[index]
+    }
+}
 
-    score
+// Prints how `evaluate_board_pattern_score` and `evaluate_board_scalar_score`
+// add up for `board`. Each instance's contribution is spread evenly over its
+// squares for the heat map.
+pub fn explain(board: &Board) {
+    print_board(board);
+    let indexes = pattern_indexes(board);
+    let mut heat = [0.0f32; 64];
+    let mut pattern_score = 0;
+    println!("pattern  index  contribution  squares");
+    for (p, squares) in PATTERN_SQUARES.iter().enumerate() {
+        let (pattern, instance) = if p < 4 {
+            (0, p)
+        } else if p < 6 {
+            (1, p - 4)
+        } else {
+            (2, p - 6)
+        };
+        let contribution = pattern_instance_value(p, indexes[p]);
+        pattern_score += contribution;
+        let names: Vec<String> = squares.iter().map(|&s| pos_to_cmd(&(1 << s))).collect();
+        println!(
+            "{}-{}  {:>7}  {:>12}  {}",
+            pattern,
+            instance,
+            indexes[p],
+            contribution,
+            names.join(" ")
+        );
+        for &square in squares.iter() {
+            heat[square] += contribution as f32 / squares.len() as f32;
+        }
+    }
+    println!("pattern total: {}", pattern_score);
+    let (feature, _) = evaluate_board_scalar(board, &SCALAR_VALUE);
+    for ((name, f), v) in SCALAR_NAMES.iter().zip(feature).zip(SCALAR_VALUE) {
+        println!("{}: {} x {} = {}", name, f, v, f * v);
+    }
+    let scalar_score = evaluate_board_scalar_score(board);
+    println!("scalar total: {}", scalar_score);
+    println!(
+        "total ({} view): {}",
+        BLACK_STONE,
+        pattern_score + scalar_score
+    );
+    println!(
+        "total (side to move): {}",
+        if board.turn == BLACK {
+            pattern_score + scalar_score
+        } else {
+            -(pattern_score + scalar_score)
+        }
+    );
+
+    print!("      A     B     C     D     E     F     G     H");
+    let line = "+-----+-----+-----+-----+-----+-----+-----+-----+";
+    for i in 0..64 {
+        if i % CELL == 0 {
+            print!("\n  {}\n", line);
+            print!("{} ", i / CELL + 1);
+        }
+        print!("|{:>5.0}", heat[i as usize]);
+        if i % CELL == 7 {
+            print!("|");
+        }
+    }
+    println!("\n  {}", line);
 }
 
 pub fn evaluate_board_legal(board: &Board, legal_value: f32) -> (i32, f32) {
@@ -168,32 +376,201 @@ pub fn evaluate_board_legal(board: &Board, legal_value: f32) -> (i32, f32) {
     (legal_diff, legal_diff as f32 * legal_value)
 }
 
-pub fn evaluate_board_legal_score(board: &Board) -> i32 {
-    let mut tmp_board = *board;
-    tmp_board.turn = BLACK;
-    let black_legal = legal(tmp_board).count_ones();
-    tmp_board.turn = WHITE;
-    let white_legal = legal(tmp_board).count_ones();
+#[inline]
+fn around(bits: u64) -> u64 {
+    let left = 0xfefefefefefefefe;
+    let right = 0x7f7f7f7f7f7f7f7f;
+    ((bits << 1) & left)
+        | ((bits >> 1) & right)
+        | (bits << 8)
+        | (bits >> 8)
+        | ((bits << 9) & left)
+        | ((bits << 7) & right)
+        | ((bits >> 7) & left)
+        | ((bits >> 9) & right)
+}
 
-    let legal_diff = black_legal as i32 - white_legal as i32;
-    legal_diff * LEGAL_VALUE
+pub fn potential_mobility(own: u64, opponent: u64) -> i32 {
+    let empty = !(own | opponent);
+    (empty & around(opponent)).count_ones() as i32
 }
 
-pub fn one_play(
-    record: String,
-    pattern_value: &PatternValue,
-    legal_value: f32,
-) -> (Vec<(PatternIndex, i32, f32)>, i32) {
-    let mut board: Board = Board {
-        black_board: 0,
-        white_board: 0,
-        turn: BLACK,
-        no_legal_command: 0,
-        value: 0,
-        before_pos: 0,
+pub fn frontier_discs(own: u64, opponent: u64) -> i32 {
+    let empty = !(own | opponent);
+    (own & around(empty)).count_ones() as i32
+}
+
+// Number of quadrants with an odd number of empty squares.
+pub fn odd_regions(board: &Board) -> i32 {
+    let empty = !(board.black_board | board.white_board);
+    let quadrants: [u64; 4] = [
+        0x000000000f0f0f0f,
+        0x00000000f0f0f0f0,
+        0x0f0f0f0f00000000,
+        0xf0f0f0f000000000,
+    ];
+    quadrants
+        .iter()
+        .filter(|&&q| (empty & q).count_ones() % 2 == 1)
+        .count() as i32
+}
+
+// Every line in one direction as a mask, indexed by the line's number:
+// 0 rows, 1 columns, 2 a1-h8 diagonals, 3 a8-h1 diagonals.
+const fn line_masks(direction: usize) -> [u64; 15] {
+    let mut masks = [0u64; 15];
+    let mut i = 0;
+    while i < 64 {
+        let (x, y) = (i % 8, i / 8);
+        let line = match direction {
+            0 => y,
+            1 => x,
+            2 => x + 7 - y,
+            _ => x + y,
+        };
+        masks[line] |= 1 << i;
+        i += 1;
+    }
+    masks
+}
+
+const LINE_MASKS: [[u64; 15]; 4] = [line_masks(0), line_masks(1), line_masks(2), line_masks(3)];
+const A_FILE: u64 = 0x0101010101010101;
+const H_FILE: u64 = 0x8080808080808080;
+const RANK_1: u64 = 0x00000000000000ff;
+const RANK_8: u64 = 0xff00000000000000;
+
+// Squares on a full line in `direction`.
+fn full_lines(occupied: u64, direction: usize) -> u64 {
+    LINE_MASKS[direction]
+        .iter()
+        .filter(|&&mask| mask != 0 && occupied & mask == mask)
+        .fold(0, |full, &mask| full | mask)
+}
+
+// Discs that can never be flipped: along each of the four lines through the
+// square, either the line is full or one side is the edge or a stable disc of
+// the same colour. Grown from the edges until nothing changes.
+pub fn stable_discs(own: u64, opponent: u64) -> u64 {
+    let occupied = own | opponent;
+    let full: [u64; 4] = [0, 1, 2, 3].map(|d| full_lines(occupied, d));
+    let mut stable: u64 = 0;
+    loop {
+        let horizontal =
+            full[0] | A_FILE | H_FILE | ((stable << 1) & !A_FILE) | ((stable >> 1) & !H_FILE);
+        let vertical = full[1] | RANK_1 | RANK_8 | (stable << 8) | (stable >> 8);
+        let diagonal = full[2]
+            | A_FILE
+            | H_FILE
+            | RANK_1
+            | RANK_8
+            | ((stable << 9) & !A_FILE)
+            | ((stable >> 9) & !H_FILE);
+        let anti_diagonal = full[3]
+            | A_FILE
+            | H_FILE
+            | RANK_1
+            | RANK_8
+            | ((stable << 7) & !H_FILE)
+            | ((stable >> 7) & !A_FILE);
+        let new_stable = own & horizontal & vertical & diagonal & anti_diagonal;
+        if new_stable == stable {
+            return stable;
+        }
+        stable = new_stable;
+    }
+}
+
+pub fn scalar_features(board: &Board) -> ScalarFeature {
+    let (black, white) = (board.black_board, board.white_board);
+    let (legal_diff, _) = evaluate_board_legal(board, 0.0);
+    let parity = if board.turn == BLACK {
+        odd_regions(board)
+    } else {
+        -odd_regions(board)
     };
+    let raw = [
+        legal_diff,
+        potential_mobility(black, white) - potential_mobility(white, black),
+        frontier_discs(black, white) - frontier_discs(white, black),
+        parity,
+        stable_discs(black, white).count_ones() as i32
+            - stable_discs(white, black).count_ones() as i32,
+        (black & CORNER_BIT).count_ones() as i32 - (white & CORNER_BIT).count_ones() as i32,
+    ];
+    let mut feature = [0.0; SCALAR_NUM];
+    for ((f, r), scale) in feature.iter_mut().zip(raw).zip(SCALAR_SCALE) {
+        *f = r as f32 * scale;
+    }
+    feature
+}
+
+pub fn evaluate_board_scalar(board: &Board, scalar_value: &ScalarValue) -> (ScalarFeature, f32) {
+    let feature = scalar_features(board);
+    let score = feature
+        .iter()
+        .zip(scalar_value.iter())
+        .map(|(&f, &v)| f * v)
+        .sum();
+    (feature, score)
+}
+
+// The scalar part of the engine's evaluation, from black's view.
+pub fn evaluate_board_scalar_score(board: &Board) -> i32 {
+    evaluate_board_scalar(board, &SCALAR_VALUE).1.round() as i32
+}
+
+// 0 means one thread per core.
+pub fn thread_count(threads: usize) -> usize {
+    if threads > 0 {
+        threads
+    } else {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    }
+}
+
+// Maps `f` over `items` on up to `threads` threads. Each thread takes one
+// contiguous chunk and the results come back in input order, so any
+// reduction over them is the same as a serial run.
+pub fn parallel_map<T: Sync, R: Send>(
+    items: &[T],
+    threads: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let threads = threads.clamp(1, items.len().max(1));
+    if threads == 1 {
+        return items.iter().map(f).collect();
+    }
+    let chunk = items.len().div_ceil(threads);
+    let f = &f;
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(chunk)
+            .map(|part| scope.spawn(move || part.iter().map(f).collect::<Vec<R>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+pub fn training_paths() -> Vec<String> {
+    let mut paths: Vec<String> = vec![];
+    for i in 0..19 {
+        paths.push(format!("self_play/{i:0>7}.txt"));
+    }
+    paths.push("wthor_2005_2009.txt".to_string());
+    paths.push("wthor_2010_2023.txt".to_string());
+    paths
+}
+
+// Replays a lowercase training record and returns the positions after the
+// 15th move together with the final disc difference.
+pub fn replay_record(record: &str) -> (Vec<Board>, i32) {
+    let mut board = Board::new(0, 0, BLACK);
     init_board(&mut board);
-    let mut data: Vec<(PatternIndex, i32, f32)> = vec![];
+    let mut boards: Vec<Board> = vec![];
     let len = record.len();
 
     for i in 0..(len / 2) {
@@ -202,25 +579,44 @@ pub fn one_play(
             board.turn = !board.turn;
         }
         if i >= 15 {
-            let (pattern_index, pattern_score) = evaluate_board_pattern(&board, pattern_value);
-            let (legal_diff, legal_score) = evaluate_board_legal(&board, legal_value);
-            data.push((pattern_index, legal_diff, pattern_score + legal_score));
+            boards.push(board);
         }
     }
     let final_diff = board.black_board.count_ones() as i32 - board.white_board.count_ones() as i32;
+    (boards, final_diff)
+}
+
+pub fn one_play(
+    record: String,
+    pattern_value: &PatternValue,
+    shares: &PatternShares,
+    scalar_value: &ScalarValue,
+) -> (Vec<(PatternIndex, ScalarFeature, f32)>, i32) {
+    let (boards, final_diff) = replay_record(&record);
+    let data = boards
+        .iter()
+        .map(|board| {
+            let (pattern_index, pattern_score) =
+                evaluate_board_pattern(board, pattern_value, shares);
+            let (scalar_feature, scalar_score) = evaluate_board_scalar(board, scalar_value);
+            (pattern_index, scalar_feature, pattern_score + scalar_score)
+        })
+        .collect();
     (data, final_diff)
 }
 
 pub fn one_train(
     record: String,
     pattern_value: &PatternValue,
-    legal_value: f32,
+    shares: &PatternShares,
+    scalar_value: &ScalarValue,
     learning_rate: f32,
-) -> (PatternValue, f32) {
-    let (data, final_diff) = one_play(record, pattern_value, legal_value);
+) -> (PatternValue, ScalarValue) {
+    let (data, final_diff) = one_play(record, pattern_value, shares, scalar_value);
+    let (p0s, p1s, p2s) = shares;
     let (mut new_p0_value, mut new_p1_value, mut new_p2_value) = pattern_value.clone();
-    let mut new_legal_value = legal_value;
-    for ((p0_indexes, p1_indexes, p2_indexes), legal_diff, score) in data {
+    let mut new_scalar_value = *scalar_value;
+    for ((p0_indexes, p1_indexes, p2_indexes), scalar_feature, score) in data {
         let error = final_diff as f32 - score;
         // println!(
         //     "final_diff: {}, score: {}, error: {}",
@@ -229,96 +625,92 @@ pub fn one_train(
         let (p0_0, p0_1, p0_2, p0_3) = p0_indexes;
         let (p1_0, p1_1) = p1_indexes;
         let (p2_0, p2_1, p2_2, p2_3) = p2_indexes;
-        if p0_0 > 0 {
-            new_p0_value[p0_0] += error * learning_rate;
-        }
-        if p0_1 > 0 {
-            new_p0_value[p0_1] += error * learning_rate;
-        }
-        if p0_2 > 0 {
-            new_p0_value[p0_2] += error * learning_rate;
-        }
-        if p0_3 > 0 {
-            new_p0_value[p0_3] += error * learning_rate;
-        }
-        if p1_0 > 0 {
-            new_p1_value[p1_0] += error * learning_rate;
-        }
-        if p1_1 > 0 {
-            new_p1_value[p1_1] += error * learning_rate;
+        p0s.update(&mut new_p0_value, p0_0, error * learning_rate);
+        p0s.update(&mut new_p0_value, p0_1, error * learning_rate);
+        p0s.update(&mut new_p0_value, p0_2, error * learning_rate);
+        p0s.update(&mut new_p0_value, p0_3, error * learning_rate);
+        p1s.update(&mut new_p1_value, p1_0, error * learning_rate);
+        p1s.update(&mut new_p1_value, p1_1, error * learning_rate);
+        p2s.update(&mut new_p2_value, p2_0, error * learning_rate);
+        p2s.update(&mut new_p2_value, p2_1, error * learning_rate);
+        p2s.update(&mut new_p2_value, p2_2, error * learning_rate);
+        p2s.update(&mut new_p2_value, p2_3, error * learning_rate);
+        for (value, feature) in new_scalar_value.iter_mut().zip(scalar_feature) {
+            *value += feature * error * learning_rate;
         }
-        if p2_0 > 0 {
-            new_p2_value[p2_0] += error * learning_rate;
-        }
-        if p2_1 > 0 {
-            new_p2_value[p2_1] += error * learning_rate;
-        }
-        if p2_2 > 0 {
-            new_p2_value[p2_2] += error * learning_rate;
-        }
-        if p2_3 > 0 {
-            new_p2_value[p2_3] += error * learning_rate;
-        }
-        new_legal_value += legal_diff as f32 * error * learning_rate;
     }
-    ((new_p0_value, new_p1_value, new_p2_value), new_legal_value)
+    ((new_p0_value, new_p1_value, new_p2_value), new_scalar_value)
 }
 
-pub fn evaluate_model(path: String, pattern_value: &PatternValue, legal_value: f32) -> f32 {
-    let file = File::open(path).unwrap();
-    let reader = BufReader::new(file);
-    let mut loss = 0.0;
-    let mut record_count = 0;
-    for line in reader.lines() {
-        record_count += 1;
-        let mut one_loss = 0.0;
-        let record = line.unwrap();
-        let (data, final_diff) = one_play(record, pattern_value, legal_value);
+pub fn evaluate_model(
+    records: &[String],
+    pattern_value: &PatternValue,
+    shares: &PatternShares,
+    scalar_value: &ScalarValue,
+) -> f32 {
+    let losses: Vec<f32> = parallel_map(records, thread_count(0), |record| {
+        let (data, final_diff) = one_play(record.clone(), pattern_value, shares, scalar_value);
+        // Games of 15 moves or fewer give no positions.
+        if data.is_empty() {
+            return None;
+        }
         let len = data.len();
+        let mut one_loss = 0.0;
         for (_, _, score) in data {
             one_loss += (score - final_diff as f32).abs();
         }
-        loss += one_loss / len as f32;
-    }
-    loss /= record_count as f32;
+        Some(one_loss / len as f32)
+    })
+    .into_iter()
+    .flatten()
+    .collect();
+    let loss = losses.iter().sum::<f32>() / losses.len().max(1) as f32;
     println!("loss = {}", loss);
     loss
 }
 
-pub fn train(epoch: usize, learning_rate: f32) {
-    let mut paths: Vec<String> = vec![];
-    for i in 0..19 {
-        paths.push(format!("self_play/{i:0>7}.txt"));
-    }
-    paths.push("wthor_2005_2009.txt".to_string());
-    paths.push("wthor_2010_2023.txt".to_string());
+pub fn train(epoch: usize, learning_rate: f32, side_to_move: bool) -> Result<(), String> {
+    let records = load_records(&training_paths(), false)?;
+    let validation = load_records(&["self_play/0000019.txt".to_string()], false)?;
 
-    let p0_value: Vec<f32> = vec![0.0; 59049];
-    let p1_value: Vec<f32> = vec![0.0; 6561];
-    let p2_value: Vec<f32> = vec![0.0; 59049];
+    let shares = pattern_shares(side_to_move);
+    let p0_value: Vec<f32> = vec![0.0; shares.0.size];
+    let p1_value: Vec<f32> = vec![0.0; shares.1.size];
+    let p2_value: Vec<f32> = vec![0.0; shares.2.size];
     let mut pattern_value = (p0_value, p1_value, p2_value);
-    let mut legal_value: f32 = 0.0;
+    let mut scalar_value: ScalarValue = [0.0; SCALAR_NUM];
 
     for i in 0..epoch {
         println!("epoch: {}", i);
-        for path in paths.clone() {
-            let file = File::open(path).unwrap();
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                let record = line.unwrap();
-                (pattern_value, legal_value) =
-                    one_train(record, &pattern_value, legal_value, learning_rate);
-            }
+        for record in &records {
+            (pattern_value, scalar_value) = one_train(
+                record.clone(),
+                &pattern_value,
+                &shares,
+                &scalar_value,
+                learning_rate,
+            );
         }
-        evaluate_model(
-            "self_play/0000019.txt".to_string(),
-            &pattern_value,
-            legal_value,
-        );
+        evaluate_model(&validation, &pattern_value, &shares, &scalar_value);
     }
 
+    write_weights("train_result4.txt", &pattern_value, &shares, &scalar_value);
+    Ok(())
+}
+
+// Writes the pattern tables expanded back to one weight per raw index, so the
+// file keeps the layout `parameter` is generated from.
+pub fn write_weights(
+    path: &str,
+    pattern_value: &PatternValue,
+    shares: &PatternShares,
+    scalar_value: &[f32],
+) {
     let (p0_value, p1_value, p2_value) = pattern_value;
-    let mut file = File::create("train_result4.txt").unwrap();
+    let p0_value = shares.0.expand(p0_value);
+    let p1_value = shares.1.expand(p1_value);
+    let p2_value = shares.2.expand(p2_value);
+    let mut file = File::create(path).unwrap();
 
     let mut nonzero_count = 0;
     for item in p0_value {
@@ -342,7 +734,10 @@ pub fn train(epoch: usize, learning_rate: f32) {
         file.write_all(item.to_string().as_bytes()).unwrap();
         file.write_all(b"\n").unwrap();
     }
-    file.write_all(legal_value.to_string().as_bytes()).unwrap();
+    // The scalar weights follow the tables, one per line, in the order of
+    // `SCALAR_VALUE`.
+    let scalar_lines: Vec<String> = scalar_value.iter().map(|v| v.to_string()).collect();
+    file.write_all(scalar_lines.join("\n").as_bytes()).unwrap();
 
     file.flush().unwrap();
 
@@ -411,7 +806,7 @@ pub fn pattern4_indexes(board: &Board) -> P4Index {
 
 pub fn pattern5_indexes(board: &Board) -> P5Index {
     let mut index0: usize = 0;
-    let positions0 = [0, 1, 2, 8, 9, 10, 11, 16];
+    let positions0 = [0, 1, 2, 8, 9, 10, 16, 17];
     for i in positions0 {
         index0 = calculate_index(board, &index0, i);
     }
@@ -436,28 +831,30 @@ pub fn pattern5_indexes(board: &Board) -> P5Index {
 pub fn evaluate_board_pattern_mini(
     board: &Board,
     pattern_value: &PatternValue,
+    shares: &PatternShares,
 ) -> (PatternIndex, f32) {
     let mut score: f32 = 0.0;
     let (p3v, p4v, p5v) = pattern_value;
+    let (p3s, p4s, p5s) = shares;
     let p3_indexes = pattern3_indexes(board);
     let p4_indexes = pattern4_indexes(board);
     let p5_indexes = pattern5_indexes(board);
 
     let (p3_0, p3_1, p3_2, p3_3) = p3_indexes;
-    score += p3v[p3_0];
-    score += p3v[p3_1];
-    score += p3v[p3_2];
-    score += p3v[p3_3];
+    score += p3s.value(p3v, p3_0);
+    score += p3s.value(p3v, p3_1);
+    score += p3s.value(p3v, p3_2);
+    score += p3s.value(p3v, p3_3);
 
     let (p4_0, p4_1) = p4_indexes;
-    score += p4v[p4_0];
-    score += p4v[p4_1];
+    score += p4s.value(p4v, p4_0);
+    score += p4s.value(p4v, p4_1);
 
     let (p5_0, p5_1, p5_2, p5_3) = p5_indexes;
-    score += p5v[p5_0];
-    score += p5v[p5_1];
-    score += p5v[p5_2];
-    score += p5v[p5_3];
+    score += p5s.value(p5v, p5_0);
+    score += p5s.value(p5v, p5_1);
+    score += p5s.value(p5v, p5_2);
+    score += p5s.value(p5v, p5_3);
 
     ((p3_indexes, p4_indexes, p5_indexes), score)
 }
@@ -512,16 +909,10 @@ pub fn evaluate_board_legal_mini(board: &Board, legal_value: f32) -> (i32, f32)
 pub fn one_play_mini(
     record: String,
     pattern_value: &PatternValue,
+    shares: &PatternShares,
     legal_value: f32,
 ) -> (Vec<(PatternIndex, i32, f32)>, i32) {
-    let mut board: Board = Board {
-        black_board: 0,
-        white_board: 0,
-        turn: BLACK,
-        no_legal_command: 0,
-        value: 0,
-        before_pos: 0,
-    };
+    let mut board = Board::new(0, 0, BLACK);
     init_board(&mut board);
     let mut data: Vec<(PatternIndex, i32, f32)> = vec![];
     let len = record.len();
@@ -532,7 +923,8 @@ pub fn one_play_mini(
             board.turn = !board.turn;
         }
         if i >= 15 {
-            let (pattern_index, pattern_score) = evaluate_board_pattern_mini(&board, pattern_value);
+            let (pattern_index, pattern_score) =
+                evaluate_board_pattern_mini(&board, pattern_value, shares);
             let (legal_diff, legal_score) = evaluate_board_legal_mini(&board, legal_value);
             data.push((pattern_index, legal_diff, pattern_score + legal_score));
         }
@@ -544,10 +936,12 @@ pub fn one_play_mini(
 pub fn one_train_mini(
     record: String,
     pattern_value: &PatternValue,
+    shares: &PatternShares,
     legal_value: f32,
     learning_rate: f32,
 ) -> (PatternValue, f32) {
-    let (data, final_diff) = one_play_mini(record, pattern_value, legal_value);
+    let (data, final_diff) = one_play_mini(record, pattern_value, shares, legal_value);
+    let (p3s, p4s, p5s) = shares;
     let (mut new_p3_value, mut new_p4_value, mut new_p5_value) = pattern_value.clone();
     let mut new_legal_value = legal_value;
     for ((p3_indexes, p4_indexes, p5_indexes), legal_diff, score) in data {
@@ -559,95 +953,77 @@ pub fn one_train_mini(
         let (p3_0, p3_1, p3_2, p3_3) = p3_indexes;
         let (p4_0, p4_1) = p4_indexes;
         let (p5_0, p5_1, p5_2, p5_3) = p5_indexes;
-        if p3_0 > 0 {
-            new_p3_value[p3_0] += error * learning_rate;
-        }
-        if p3_1 > 0 {
-            new_p3_value[p3_1] += error * learning_rate;
-        }
-        if p3_2 > 0 {
-            new_p3_value[p3_2] += error * learning_rate;
-        }
-        if p3_3 > 0 {
-            new_p3_value[p3_3] += error * learning_rate;
-        }
-        if p4_0 > 0 {
-            new_p4_value[p4_0] += error * learning_rate;
-        }
-        if p4_1 > 0 {
-            new_p4_value[p4_1] += error * learning_rate;
-        }
-        if p5_0 > 0 {
-            new_p5_value[p5_0] += error * learning_rate;
-        }
-        if p5_1 > 0 {
-            new_p5_value[p5_1] += error * learning_rate;
-        }
-        if p5_2 > 0 {
-            new_p5_value[p5_2] += error * learning_rate;
-        }
-        if p5_3 > 0 {
-            new_p5_value[p5_3] += error * learning_rate;
-        }
+        p3s.update(&mut new_p3_value, p3_0, error * learning_rate);
+        p3s.update(&mut new_p3_value, p3_1, error * learning_rate);
+        p3s.update(&mut new_p3_value, p3_2, error * learning_rate);
+        p3s.update(&mut new_p3_value, p3_3, error * learning_rate);
+        p4s.update(&mut new_p4_value, p4_0, error * learning_rate);
+        p4s.update(&mut new_p4_value, p4_1, error * learning_rate);
+        p5s.update(&mut new_p5_value, p5_0, error * learning_rate);
+        p5s.update(&mut new_p5_value, p5_1, error * learning_rate);
+        p5s.update(&mut new_p5_value, p5_2, error * learning_rate);
+        p5s.update(&mut new_p5_value, p5_3, error * learning_rate);
         new_legal_value += legal_diff as f32 * error * learning_rate;
     }
     ((new_p3_value, new_p4_value, new_p5_value), new_legal_value)
 }
 
-pub fn evaluate_model_mini(path: String, pattern_value: &PatternValue, legal_value: f32) -> f32 {
-    let file = File::open(path).unwrap();
-    let reader = BufReader::new(file);
-    let mut loss = 0.0;
-    let mut record_count = 0;
-    for line in reader.lines() {
-        record_count += 1;
-        let mut one_loss = 0.0;
-        let record = line.unwrap();
-        let (data, final_diff) = one_play_mini(record, pattern_value, legal_value);
+pub fn evaluate_model_mini(
+    records: &[String],
+    pattern_value: &PatternValue,
+    shares: &PatternShares,
+    legal_value: f32,
+) -> f32 {
+    let losses: Vec<f32> = parallel_map(records, thread_count(0), |record| {
+        let (data, final_diff) = one_play_mini(record.clone(), pattern_value, shares, legal_value);
+        // Games of 15 moves or fewer give no positions.
+        if data.is_empty() {
+            return None;
+        }
         let len = data.len();
+        let mut one_loss = 0.0;
         for (_, _, score) in data {
             one_loss += (score - final_diff as f32).abs();
         }
-        loss += one_loss / len as f32;
-    }
-    loss /= record_count as f32;
+        Some(one_loss / len as f32)
+    })
+    .into_iter()
+    .flatten()
+    .collect();
+    let loss = losses.iter().sum::<f32>() / losses.len().max(1) as f32;
     println!("loss = {}", loss);
     loss
 }
 
-pub fn train_mini(epoch: usize, learning_rate: f32) {
-    let mut paths: Vec<String> = vec![];
-    for i in 0..19 {
-        paths.push(format!("self_play/{i:0>7}.txt"));
-    }
-    paths.push("wthor_2005_2009.txt".to_string());
-    paths.push("wthor_2010_2023.txt".to_string());
+pub fn train_mini(epoch: usize, learning_rate: f32, side_to_move: bool) -> Result<(), String> {
+    let records = load_records(&training_paths(), false)?;
+    let validation = load_records(&["self_play/0000019.txt".to_string()], false)?;
 
-    let p0_value: Vec<f32> = vec![0.0; 6561];
-    let p1_value: Vec<f32> = vec![0.0; 6561];
-    let p2_value: Vec<f32> = vec![0.0; 6561];
+    let shares = pattern_shares_mini(side_to_move);
+    let p0_value: Vec<f32> = vec![0.0; shares.0.size];
+    let p1_value: Vec<f32> = vec![0.0; shares.1.size];
+    let p2_value: Vec<f32> = vec![0.0; shares.2.size];
     let mut pattern_value = (p0_value, p1_value, p2_value);
     let mut legal_value: f32 = 0.0;
 
     for i in 0..epoch {
         println!("epoch: {}", i);
-        for path in paths.clone() {
-            let file = File::open(path).unwrap();
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                let record = line.unwrap();
-                (pattern_value, legal_value) =
-                    one_train_mini(record, &pattern_value, legal_value, learning_rate);
-            }
+        for record in &records {
+            (pattern_value, legal_value) = one_train_mini(
+                record.clone(),
+                &pattern_value,
+                &shares,
+                legal_value,
+                learning_rate,
+            );
         }
-        evaluate_model_mini(
-            "self_play/0000019.txt".to_string(),
-            &pattern_value,
-            legal_value,
-        );
+        evaluate_model_mini(&validation, &pattern_value, &shares, legal_value);
     }
 
     let (p0_value, p1_value, p2_value) = pattern_value;
+    let p0_value = shares.0.expand(&p0_value);
+    let p1_value = shares.1.expand(&p1_value);
+    let p2_value = shares.2.expand(&p2_value);
     let mut file = File::create("train_result_mini.txt").unwrap();
 
     let mut nonzero_count = 0;
@@ -677,4 +1053,5 @@ pub fn train_mini(epoch: usize, learning_rate: f32) {
     file.flush().unwrap();
 
     println!("nonzero_count: {}", nonzero_count);
+    Ok(())
 }
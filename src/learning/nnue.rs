@@ -0,0 +1,367 @@
+use crate::learning::learning::*;
+use crate::record::loader::*;
+use crate::reversi::reversi::*;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::fs::File;
+use std::io::Write;
+use std::io::{BufRead, BufReader};
+use std::sync::OnceLock;
+
+/*
+
+input    64 black squares, 64 white squares, black to move
+hidden1  clipped ReLU
+hidden2  clipped ReLU
+output   final disc difference from black's point of view
+
+*/
+
+pub const NNUE_INPUT: usize = 129;
+pub const NNUE_HIDDEN1: usize = 32;
+pub const NNUE_HIDDEN2: usize = 16;
+// Fixed-point scale used by the integer evaluator. `Nnue::evaluate` returns
+// the disc difference multiplied by this value.
+pub const NNUE_SCALE: i32 = 64;
+
+static NNUE: OnceLock<Nnue> = OnceLock::new();
+
+// Float weights used while training and stored in weight files.
+#[derive(Clone)]
+pub struct NnueModel {
+    pub w1: Vec<f32>,
+    pub b1: Vec<f32>,
+    pub w2: Vec<f32>,
+    pub b2: Vec<f32>,
+    pub w3: Vec<f32>,
+    pub b3: f32,
+}
+
+// Quantised weights used by the search.
+pub struct Nnue {
+    w1: Vec<i16>,
+    b1: Vec<i32>,
+    w2: Vec<i32>,
+    b2: Vec<i32>,
+    w3: Vec<i32>,
+    b3: i32,
+}
+
+// At most one of a square's two inputs is set, plus the side to move.
+const NNUE_ACTIVE_INPUTS: usize = 65;
+
+// Indexes of the inputs set for a board, read straight off the bitboards.
+#[derive(Clone, Copy)]
+struct ActiveInputs {
+    black: u64,
+    white: u64,
+    black_to_move: bool,
+}
+
+impl Iterator for ActiveInputs {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        if self.black != 0 {
+            let i = self.black.trailing_zeros() as usize;
+            self.black &= self.black - 1;
+            Some(i)
+        } else if self.white != 0 {
+            let i = self.white.trailing_zeros() as usize;
+            self.white &= self.white - 1;
+            Some(64 + i)
+        } else if self.black_to_move {
+            self.black_to_move = false;
+            Some(128)
+        } else {
+            None
+        }
+    }
+}
+
+#[inline]
+fn active_inputs(board: &Board) -> ActiveInputs {
+    ActiveInputs {
+        black: board.black_board,
+        white: board.white_board,
+        black_to_move: board.turn == BLACK,
+    }
+}
+
+#[inline]
+fn quantise(value: f32, scale: i32) -> i32 {
+    (value * scale as f32).round() as i32
+}
+
+impl NnueModel {
+    pub fn new() -> NnueModel {
+        let mut rng = rand::thread_rng();
+        let mut random = |n: usize, fan_in: usize| -> Vec<f32> {
+            let range = 1.0 / (fan_in as f32).sqrt();
+            (0..n).map(|_| rng.gen_range(-range..range)).collect()
+        };
+        NnueModel {
+            w1: random(NNUE_INPUT * NNUE_HIDDEN1, NNUE_ACTIVE_INPUTS),
+            b1: vec![0.0; NNUE_HIDDEN1],
+            w2: random(NNUE_HIDDEN1 * NNUE_HIDDEN2, NNUE_HIDDEN1),
+            b2: vec![0.0; NNUE_HIDDEN2],
+            w3: random(NNUE_HIDDEN2, NNUE_HIDDEN2),
+            b3: 0.0,
+        }
+    }
+
+    fn forward(&self, inputs: ActiveInputs) -> ([f32; NNUE_HIDDEN1], [f32; NNUE_HIDDEN2], f32) {
+        let mut h1 = [0.0; NNUE_HIDDEN1];
+        h1.copy_from_slice(&self.b1);
+        for i in inputs {
+            let column = &self.w1[i * NNUE_HIDDEN1..(i + 1) * NNUE_HIDDEN1];
+            for (h, w) in h1.iter_mut().zip(column) {
+                *h += w;
+            }
+        }
+        let mut h2 = [0.0; NNUE_HIDDEN2];
+        h2.copy_from_slice(&self.b2);
+        for (j, h) in h1.iter().enumerate() {
+            let a = h.clamp(0.0, 1.0);
+            let row = &self.w2[j * NNUE_HIDDEN2..(j + 1) * NNUE_HIDDEN2];
+            for (h, w) in h2.iter_mut().zip(row) {
+                *h += w * a;
+            }
+        }
+        let out = self.b3
+            + self
+                .w3
+                .iter()
+                .zip(h2.iter())
+                .map(|(w, h)| w * h.clamp(0.0, 1.0))
+                .sum::<f32>();
+        (h1, h2, out)
+    }
+
+    pub fn predict(&self, board: &Board) -> f32 {
+        self.forward(active_inputs(board)).2
+    }
+
+    // One plain SGD step on the squared error; returns the error before the
+    // update.
+    pub fn train_step(&mut self, board: &Board, target: f32, learning_rate: f32) -> f32 {
+        let inputs = active_inputs(board);
+        let (h1, h2, out) = self.forward(inputs);
+        let error = out - target;
+
+        let mut d2 = [0.0; NNUE_HIDDEN2];
+        for ((d, w), &h) in d2.iter_mut().zip(self.w3.iter_mut()).zip(h2.iter()) {
+            if 0.0 < h && h < 1.0 {
+                *d = error * *w;
+            }
+            *w -= learning_rate * error * h.clamp(0.0, 1.0);
+        }
+        self.b3 -= learning_rate * error;
+
+        let mut d1 = [0.0; NNUE_HIDDEN1];
+        for (j, (d, &h)) in d1.iter_mut().zip(h1.iter()).enumerate() {
+            let a = h.clamp(0.0, 1.0);
+            let mut sum = 0.0;
+            let row = &mut self.w2[j * NNUE_HIDDEN2..(j + 1) * NNUE_HIDDEN2];
+            for (w, &g) in row.iter_mut().zip(d2.iter()) {
+                sum += g * *w;
+                *w -= learning_rate * g * a;
+            }
+            if 0.0 < h && h < 1.0 {
+                *d = sum;
+            }
+        }
+        for (b, &g) in self.b2.iter_mut().zip(d2.iter()) {
+            *b -= learning_rate * g;
+        }
+        for i in inputs {
+            let column = &mut self.w1[i * NNUE_HIDDEN1..(i + 1) * NNUE_HIDDEN1];
+            for (w, &g) in column.iter_mut().zip(d1.iter()) {
+                *w -= learning_rate * g;
+            }
+        }
+        for (b, &g) in self.b1.iter_mut().zip(d1.iter()) {
+            *b -= learning_rate * g;
+        }
+        error
+    }
+
+    pub fn quantise(&self) -> Nnue {
+        Nnue {
+            w1: self
+                .w1
+                .iter()
+                .map(|&w| quantise(w, NNUE_SCALE) as i16)
+                .collect(),
+            b1: self.b1.iter().map(|&b| quantise(b, NNUE_SCALE)).collect(),
+            w2: self.w2.iter().map(|&w| quantise(w, NNUE_SCALE)).collect(),
+            b2: self
+                .b2
+                .iter()
+                .map(|&b| quantise(b, NNUE_SCALE * NNUE_SCALE))
+                .collect(),
+            w3: self.w3.iter().map(|&w| quantise(w, NNUE_SCALE)).collect(),
+            b3: quantise(self.b3, NNUE_SCALE * NNUE_SCALE),
+        }
+    }
+
+    pub fn save(&self, path: &str) {
+        let mut file = File::create(path).unwrap();
+        file.write_all(
+            format!("nnue {} {} {}\n", NNUE_INPUT, NNUE_HIDDEN1, NNUE_HIDDEN2).as_bytes(),
+        )
+        .unwrap();
+        let values = self
+            .w1
+            .iter()
+            .chain(self.b1.iter())
+            .chain(self.w2.iter())
+            .chain(self.b2.iter())
+            .chain(self.w3.iter())
+            .chain(std::iter::once(&self.b3));
+        for item in values {
+            file.write_all(item.to_string().as_bytes()).unwrap();
+            file.write_all(b"\n").unwrap();
+        }
+        file.flush().unwrap();
+    }
+
+    pub fn load(path: &str) -> Result<NnueModel, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut lines = BufReader::new(file).lines();
+        let header = lines
+            .next()
+            .and_then(|l| l.ok())
+            .ok_or(format!("{}: empty file", path))?;
+        let expected = format!("nnue {} {} {}", NNUE_INPUT, NNUE_HIDDEN1, NNUE_HIDDEN2);
+        if header.trim() != expected {
+            return Err(format!("{}: expected header \"{}\"", path, expected));
+        }
+        let mut values: Vec<f32> = vec![];
+        for (i, line) in lines.enumerate() {
+            let line = line.map_err(|e| format!("{}: {}", path, e))?;
+            let value = line
+                .trim()
+                .parse()
+                .map_err(|_| format!("{}:{}: invalid number \"{}\"", path, i + 2, line))?;
+            values.push(value);
+        }
+        let sizes = [
+            NNUE_INPUT * NNUE_HIDDEN1,
+            NNUE_HIDDEN1,
+            NNUE_HIDDEN1 * NNUE_HIDDEN2,
+            NNUE_HIDDEN2,
+            NNUE_HIDDEN2,
+            1,
+        ];
+        if values.len() != sizes.iter().sum::<usize>() {
+            return Err(format!(
+                "{}: expected {} weights, found {}",
+                path,
+                sizes.iter().sum::<usize>(),
+                values.len()
+            ));
+        }
+        let mut rest = values.as_slice();
+        let mut take = |n: usize| {
+            let (head, tail) = rest.split_at(n);
+            rest = tail;
+            head.to_vec()
+        };
+        Ok(NnueModel {
+            w1: take(sizes[0]),
+            b1: take(sizes[1]),
+            w2: take(sizes[2]),
+            b2: take(sizes[3]),
+            w3: take(sizes[4]),
+            b3: take(sizes[5])[0],
+        })
+    }
+}
+
+impl Nnue {
+    // Disc difference from black's point of view, times `NNUE_SCALE`.
+    pub fn evaluate(&self, board: &Board) -> i32 {
+        let mut h1 = [0i32; NNUE_HIDDEN1];
+        h1.copy_from_slice(&self.b1);
+        for i in active_inputs(board) {
+            let column = &self.w1[i * NNUE_HIDDEN1..(i + 1) * NNUE_HIDDEN1];
+            for (h, &w) in h1.iter_mut().zip(column) {
+                *h += w as i32;
+            }
+        }
+        let mut h2 = [0i32; NNUE_HIDDEN2];
+        h2.copy_from_slice(&self.b2);
+        for (j, h) in h1.iter().enumerate() {
+            let a = h.clamp(&0, &NNUE_SCALE);
+            if *a == 0 {
+                continue;
+            }
+            let row = &self.w2[j * NNUE_HIDDEN2..(j + 1) * NNUE_HIDDEN2];
+            for (h, w) in h2.iter_mut().zip(row) {
+                *h += w * a;
+            }
+        }
+        let out = self.b3
+            + self
+                .w3
+                .iter()
+                .zip(h2.iter())
+                .map(|(w, h)| w * (h / NNUE_SCALE).clamp(0, NNUE_SCALE))
+                .sum::<i32>();
+        out / NNUE_SCALE
+    }
+}
+
+// Switches `evaluate_board` to the network stored at `path`.
+pub fn load_nnue(path: &str) -> Result<(), String> {
+    let model = NnueModel::load(path)?;
+    NNUE.set(model.quantise())
+        .map_err(|_| "NNUE is already loaded".to_string())
+}
+
+pub fn nnue() -> Option<&'static Nnue> {
+    NNUE.get()
+}
+
+pub fn evaluate_nnue_model(records: &[String], model: &NnueModel) -> f32 {
+    let mut loss = 0.0;
+    let mut record_count = 0;
+    for record in records {
+        let (boards, final_diff) = replay_record(record);
+        if boards.is_empty() {
+            continue;
+        }
+        record_count += 1;
+        let mut one_loss = 0.0;
+        for board in &boards {
+            one_loss += (model.predict(board) - final_diff as f32).abs();
+        }
+        loss += one_loss / boards.len() as f32;
+    }
+    loss /= record_count.max(1) as f32;
+    println!("loss = {}", loss);
+    loss
+}
+
+pub fn train_nnue(epoch: usize, learning_rate: f32) -> Result<(), String> {
+    let records = load_records(&training_paths(), false)?;
+    let validation = load_records(&["self_play/0000019.txt".to_string()], false)?;
+    let mut model = NnueModel::new();
+    let mut rng = rand::thread_rng();
+
+    for i in 0..epoch {
+        println!("epoch: {}", i);
+        for record in &records {
+            let (mut boards, final_diff) = replay_record(record);
+            boards.shuffle(&mut rng);
+            for board in &boards {
+                model.train_step(board, final_diff as f32, learning_rate);
+            }
+        }
+        evaluate_nnue_model(&validation, &model);
+        model.save("train_result_nnue.txt");
+    }
+    Ok(())
+}
@@ -0,0 +1,291 @@
+use crate::learning::learning::*;
+use crate::learning::trainer::*;
+use crate::reversi::reversi::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Replaces the final disc difference of each training position with a
+// search score:
+//
+//   empties <= label_exact  exact endgame solve
+//   otherwise               depth `label_depth` search over `label_model`
+//
+// and blends the scores along the game like TD(lambda):
+//
+//   target_t = (1 - lambda) * score_t + lambda * target_{t+1}
+//
+// where the target after the last position is the final disc difference, so
+// `label_lambda = 1` gives the plain final result back.
+
+pub struct Labeller {
+    // One model per phase, empty when only exact scores are used.
+    models: Vec<Model>,
+    phases: usize,
+    depth: i32,
+    exact: i32,
+    lambda: f32,
+}
+
+fn empties(board: &Board) -> i32 {
+    64 - (board.black_board | board.white_board).count_ones() as i32
+}
+
+fn disc_diff(board: &Board) -> i32 {
+    let diff = board.black_board.count_ones() as i32 - board.white_board.count_ones() as i32;
+    if board.turn == BLACK {
+        diff
+    } else {
+        -diff
+    }
+}
+
+// Children sorted so the opponent has the fewest replies first.
+fn ordered_children(board: &Board) -> Vec<Board> {
+    let mut children: Vec<(u32, Board)> = legal_poss(board)
+        .into_iter()
+        .map(|pos| {
+            let mut child = *board;
+            child = execute_pos(&mut child, pos);
+            (legal(child).count_ones(), child)
+        })
+        .collect();
+    children.sort_by_key(|(mobility, _)| *mobility);
+    children.into_iter().map(|(_, child)| child).collect()
+}
+
+// Largest `label_exact`. A solve at 16 empty squares takes about 0.3s on one
+// core, and a record has one per position in the exact range.
+pub const MAX_LABEL_EXACT: i32 = 16;
+// Nodes with fewer empty squares are searched in square order and not
+// stored, which costs less than ordering and probing that close to the end.
+const SHALLOW_EMPTIES: i32 = 7;
+
+// Final disc difference for the side to move with perfect play.
+pub fn exact_score(board: &Board, alpha: i32, beta: i32, passed: bool) -> i32 {
+    let mut table: HashMap<Board, (i32, i32)> = HashMap::new();
+    exact_search(board, alpha, beta, passed, &mut table)
+}
+
+// `table` keeps the proven lower and upper bound of each deeper position.
+fn exact_search(
+    board: &Board,
+    mut alpha: i32,
+    mut beta: i32,
+    passed: bool,
+    table: &mut HashMap<Board, (i32, i32)>,
+) -> i32 {
+    let moves = legal(*board);
+    if moves == 0 {
+        if passed {
+            return disc_diff(board);
+        }
+        let mut next = *board;
+        next.turn = !next.turn;
+        return -exact_search(&next, -beta, -alpha, true, table);
+    }
+    if empties(board) < SHALLOW_EMPTIES {
+        let mut rest = moves;
+        while rest != 0 {
+            let pos = rest & rest.wrapping_neg();
+            rest ^= pos;
+            let child = play_discs(board, pos);
+            let score = -exact_search(&child, -beta, -alpha, false, table);
+            if score > alpha {
+                alpha = score;
+                if alpha >= beta {
+                    break;
+                }
+            }
+        }
+        return alpha;
+    }
+    let (mut lower, mut upper) = table.get(board).copied().unwrap_or((-64, 64));
+    if lower >= beta || lower == upper {
+        return lower;
+    }
+    if upper <= alpha {
+        return upper;
+    }
+    alpha = alpha.max(lower);
+    beta = beta.min(upper);
+    let window_alpha = alpha;
+    let mut best = -65;
+    for child in ordered_children(board) {
+        let score = -exact_search(&child, -beta, -alpha, false, table);
+        best = best.max(score);
+        if score > alpha {
+            alpha = score;
+            if alpha >= beta {
+                break;
+            }
+        }
+    }
+    if best >= beta {
+        lower = best;
+    } else if best <= window_alpha {
+        upper = best;
+    } else {
+        (lower, upper) = (best, best);
+    }
+    table.insert(*board, (lower, upper));
+    best
+}
+
+impl Labeller {
+    pub fn new(option: &TrainOption) -> Result<Labeller, String> {
+        let mut models: Vec<Model> = vec![];
+        if option.label_depth > 0 {
+            if option.label_model.is_empty() {
+                return Err("label_depth needs label_model".to_string());
+            }
+            for phase in 0..option.phases {
+                let path = phase_output(&option.label_model, phase, option.phases);
+                models.push(Model::load(&path, option.pattern_set, option.side_to_move)?);
+            }
+        }
+        Ok(Labeller {
+            models,
+            phases: option.phases,
+            depth: option.label_depth,
+            exact: option.label_exact,
+            lambda: option.label_lambda,
+        })
+    }
+
+    // Model score for the side to move.
+    fn evaluate(&self, board: &Board) -> f32 {
+        let model = &self.models[board_phase(board, self.phases)];
+        let score = model.predict(&model.sample(board, 0.0, 0));
+        if board.turn == BLACK {
+            score
+        } else {
+            -score
+        }
+    }
+
+    fn search(&self, board: &Board, depth: i32, mut alpha: f32, beta: f32, passed: bool) -> f32 {
+        if empties(board) <= self.exact {
+            return exact_score(board, -64, 64, passed) as f32;
+        }
+        if legal(*board) == 0 {
+            if passed {
+                return disc_diff(board) as f32;
+            }
+            let mut next = *board;
+            next.turn = !next.turn;
+            return -self.search(&next, depth, -beta, -alpha, true);
+        }
+        if depth <= 0 {
+            return self.evaluate(board);
+        }
+        for child in ordered_children(board) {
+            let score = -self.search(&child, depth - 1, -beta, -alpha, false);
+            if score > alpha {
+                alpha = score;
+                if alpha >= beta {
+                    break;
+                }
+            }
+        }
+        alpha
+    }
+
+    // Search score from black's point of view, or `None` when the position
+    // is outside the exact range and there is no model to search with.
+    pub fn label(&self, board: &Board) -> Option<f32> {
+        if empties(board) > self.exact && self.models.is_empty() {
+            return None;
+        }
+        let score = self.search(board, self.depth, f32::MIN, f32::MAX, false);
+        Some(if board.turn == BLACK { score } else { -score })
+    }
+
+    // Targets for the positions `replay_record` returns.
+    pub fn relabel(&self, record: &str) -> Vec<f32> {
+        let (boards, final_diff) = replay_record(record);
+        let mut target = final_diff as f32;
+        let mut targets: Vec<f32> = boards
+            .iter()
+            .rev()
+            .map(|board| {
+                if let Some(score) = self.label(board) {
+                    target = (1.0 - self.lambda) * score + self.lambda * target;
+                }
+                target
+            })
+            .collect();
+        targets.reverse();
+        targets
+    }
+}
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Relabels every record, reusing the cache file for the same records,
+// settings and label model.
+pub fn relabel_records(option: &TrainOption, records: &[String]) -> Result<Vec<Vec<f32>>, String> {
+    let labeller = Labeller::new(option)?;
+    let mut hash = fnv1a(
+        0xcbf29ce484222325,
+        format!(
+            "{:?} {} {} {} {} {}",
+            option.pattern_set,
+            option.side_to_move,
+            option.phases,
+            option.label_depth,
+            option.label_exact,
+            option.label_lambda
+        )
+        .as_bytes(),
+    );
+    if !labeller.models.is_empty() {
+        for phase in 0..option.phases {
+            let path = phase_output(&option.label_model, phase, option.phases);
+            let bytes = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+            hash = fnv1a(hash, &bytes);
+        }
+    }
+    for record in records {
+        hash = fnv1a(hash, record.as_bytes());
+        hash = fnv1a(hash, b"\n");
+    }
+    let cache = Path::new(&option.label_cache).join(format!("{:016x}.txt", hash));
+
+    if let Ok(text) = fs::read_to_string(&cache) {
+        let targets: Option<Vec<Vec<f32>>> = text
+            .lines()
+            .map(|line| {
+                line.split(',')
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.parse().ok())
+                    .collect()
+            })
+            .collect();
+        if let Some(targets) = targets.filter(|t| t.len() == records.len()) {
+            println!("labels are read from {}", cache.display());
+            return Ok(targets);
+        }
+    }
+
+    println!("relabelling {} records", records.len());
+    let targets = parallel_map(records, thread_count(option.threads), |record| {
+        labeller.relabel(record)
+    });
+    fs::create_dir_all(&option.label_cache)
+        .map_err(|e| format!("{}: {}", option.label_cache, e))?;
+    let text: String = targets
+        .iter()
+        .map(|t| {
+            let values: Vec<String> = t.iter().map(|v| v.to_string()).collect();
+            values.join(",") + "\n"
+        })
+        .collect();
+    fs::write(&cache, text).map_err(|e| format!("{}: {}", cache.display(), e))?;
+    println!("labels are cached in {}", cache.display());
+    Ok(targets)
+}
@@ -0,0 +1,279 @@
+use crate::ai::ai::*;
+use crate::learning::learning::*;
+use crate::record::record::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Thinking time used when `time=0`, i.e. the search is limited by depth only.
+const UNLIMITED_TIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Debug)]
+pub struct SelfPlayOption {
+    pub games: usize,
+    // "ns" (nega scout), "na" (nega alpha), "ab" (alpha beta) or "rn".
+    pub engine: String,
+    pub depth: i32,
+    // Milliseconds per move, 0 for depth only.
+    pub time: u64,
+    // Empty squares from which the rest of the game is searched to the end.
+    pub exact: i32,
+    // Random moves at the start of every game, after the book opening.
+    pub random_plies: usize,
+    // Openings to start from, any file `read_games` accepts. Empty for none.
+    pub book: String,
+    // Chance of a random move after the opening.
+    pub epsilon: f32,
+    // Softmax temperature over the move scores of a `depth` search, in the
+    // units of the evaluation function. 0 plays the engine's move.
+    pub temperature: f32,
+    pub seed: u64,
+    pub threads: usize,
+    // Games are spread over `files` files named like `0000000.txt`. Existing
+    // files are never overwritten.
+    pub output_dir: String,
+    pub files: usize,
+}
+
+impl Default for SelfPlayOption {
+    fn default() -> SelfPlayOption {
+        SelfPlayOption {
+            games: 1000,
+            engine: "ns".to_string(),
+            depth: 6,
+            time: 0,
+            exact: 12,
+            random_plies: 8,
+            book: String::new(),
+            epsilon: 0.0,
+            temperature: 0.0,
+            seed: 0,
+            threads: 0,
+            output_dir: "self_play".to_string(),
+            files: 20,
+        }
+    }
+}
+
+impl SelfPlayOption {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "games" => self.games = option_value(key, value)?,
+            "engine" => {
+                if !["ns", "na", "ab", "rn"].contains(&value) {
+                    return Err(format!("unknown engine: {}", value));
+                }
+                self.engine = value.to_string()
+            }
+            "depth" => self.depth = option_value(key, value)?,
+            "time" => self.time = option_value(key, value)?,
+            "exact" => self.exact = option_value(key, value)?,
+            "random_plies" => self.random_plies = option_value(key, value)?,
+            "book" => self.book = value.to_string(),
+            "epsilon" => self.epsilon = option_value(key, value)?,
+            "temperature" => {
+                self.temperature = option_value(key, value)?;
+                if self.temperature < 0.0 {
+                    return Err("temperature must not be negative".to_string());
+                }
+            }
+            "seed" => self.seed = option_value(key, value)?,
+            "threads" => self.threads = option_value(key, value)?,
+            "output_dir" => self.output_dir = value.to_string(),
+            "files" => self.files = option_value(key, value)?,
+            _ => return Err(format!("unknown option: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+fn random_move(board: &Board, rng: &mut StdRng) -> u64 {
+    let poss = legal_poss(board);
+    poss[rng.gen_range(0..poss.len())]
+}
+
+fn engine_pos(board: &Board, option: &SelfPlayOption) -> u64 {
+    let empties = 64 - (board.black_board | board.white_board).count_ones() as i32;
+    let depth = if empties <= option.exact {
+        empties
+    } else {
+        option.depth
+    };
+    let time = if option.time == 0 {
+        UNLIMITED_TIME
+    } else {
+        Duration::from_millis(option.time)
+    };
+    match option.engine.as_str() {
+        "ab" => alpha_beta_pos(board, depth),
+        "na" => nega_alpha_transpose_pos(board, depth, time),
+        _ => nega_scout_transpose_pos(board, depth, time),
+    }
+}
+
+// Samples a move with probability proportional to exp(score / temperature).
+// The exact endgame is left to the engine.
+fn temperature_pos(board: &Board, option: &SelfPlayOption, rng: &mut StdRng) -> u64 {
+    let empties = 64 - (board.black_board | board.white_board).count_ones() as i32;
+    if empties <= option.exact {
+        return engine_pos(board, option);
+    }
+    let poss = legal_poss(board);
+    let scores: Vec<f32> = poss
+        .iter()
+        .map(|&pos| {
+            let child = execute_pos(&mut board.clone(), pos);
+            -search_score(&child, option.depth - 1) as f32
+        })
+        .collect();
+    let best = scores.iter().cloned().fold(f32::MIN, f32::max);
+    let weights: Vec<f32> = scores
+        .iter()
+        .map(|score| ((score - best) / option.temperature).exp())
+        .collect();
+    let mut r = rng.gen::<f32>() * weights.iter().sum::<f32>();
+    for (pos, weight) in poss.iter().zip(&weights) {
+        if r < *weight {
+            return *pos;
+        }
+        r -= weight;
+    }
+    poss[scores.iter().position(|&score| score == best).unwrap()]
+}
+
+// Plays one game from `opening` and returns it as a training record.
+fn play_game(option: &SelfPlayOption, opening: &[u64], rng: &mut StdRng) -> String {
+    let mut board = cmds_to_board("");
+    let mut record = String::new();
+    let mut ply = 0;
+    loop {
+        if legal(board) == 0 {
+            board.turn = !board.turn;
+            if legal(board) == 0 {
+                break;
+            }
+        }
+        let pos = if ply < opening.len() && is_legal_pos(&board, &opening[ply]) {
+            opening[ply]
+        } else if ply < opening.len() + option.random_plies
+            || rng.gen::<f32>() < option.epsilon
+            || option.engine == "rn"
+        {
+            random_move(&board, rng)
+        } else if option.temperature > 0.0 {
+            temperature_pos(&board, option, rng)
+        } else {
+            engine_pos(&board, option)
+        };
+        record += &pos_to_cmd(&pos).to_lowercase();
+        board = execute_pos(&mut board, pos);
+        ply += 1;
+    }
+    record
+}
+
+fn load_openings(path: &str) -> Result<Vec<Vec<u64>>, String> {
+    if path.is_empty() {
+        return Ok(vec![vec![]]);
+    }
+    let openings: Vec<Vec<u64>> = read_games(path)?
+        .iter()
+        .map(|game| {
+            game.moves
+                .iter()
+                .map(|game_move| game_move.pos)
+                .filter(|&pos| pos != 0)
+                .collect()
+        })
+        .collect();
+    if openings.is_empty() {
+        return Err(format!("{}: no openings", path));
+    }
+    Ok(openings)
+}
+
+// Generates `option.games` distinct games. Game `i` is seeded with
+// `seed + i`, so with `ns` or `na` and a depth limit the corpus is the same
+// for any number of threads.
+pub fn self_play(option: &SelfPlayOption) -> Result<Vec<String>, String> {
+    let openings = load_openings(&option.book)?;
+    let threads = thread_count(option.threads);
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut games: Vec<String> = vec![];
+    let mut next_index = 0;
+    // Give up when duplicates make the target unreachable.
+    let max_index = option.games.saturating_mul(10).max(100);
+    set_search_log(false);
+    while games.len() < option.games && next_index < max_index {
+        let count = (option.games - games.len()).min(max_index - next_index);
+        let indexes: Vec<usize> = (next_index..next_index + count).collect();
+        next_index += count;
+        let played = parallel_map(&indexes, threads, |&i| {
+            let mut rng = StdRng::seed_from_u64(option.seed.wrapping_add(i as u64));
+            let opening = &openings[rng.gen_range(0..openings.len())];
+            play_game(option, opening, &mut rng)
+        });
+        for game in played {
+            if seen.insert(game.clone()) {
+                games.push(game);
+            }
+        }
+        println!(
+            "games: {} / {}  duplicates: {}",
+            games.len(),
+            option.games,
+            next_index - games.len()
+        );
+    }
+    set_search_log(true);
+    Ok(games)
+}
+
+fn output_paths(option: &SelfPlayOption) -> Vec<PathBuf> {
+    (0..option.files.max(1))
+        .map(|i| Path::new(&option.output_dir).join(format!("{:0>7}.txt", i)))
+        .collect()
+}
+
+// Fails if any file `write_self_play` would write already exists, so a long
+// run is not wasted on an output it refuses to replace.
+pub fn check_self_play_output(option: &SelfPlayOption) -> Result<(), String> {
+    match output_paths(option).iter().find(|path| path.exists()) {
+        Some(path) => Err(format!(
+            "{} already exists; choose another output_dir",
+            path.display()
+        )),
+        None => Ok(()),
+    }
+}
+
+pub fn write_self_play(option: &SelfPlayOption, games: &[String]) -> Result<(), String> {
+    fs::create_dir_all(&option.output_dir).map_err(|e| format!("{}: {}", option.output_dir, e))?;
+    let paths = output_paths(option);
+    let files = paths.len();
+    let per_file = games.len().div_ceil(files).max(1);
+    for (i, path) in paths.iter().enumerate() {
+        let start = (i * per_file).min(games.len());
+        let end = ((i + 1) * per_file).min(games.len());
+        let text: String = games[start..end].iter().map(|g| g.clone() + "\n").collect();
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    println!(
+        "Wrote {} games to {} files in {}.",
+        games.len(),
+        files,
+        option.output_dir
+    );
+    Ok(())
+}
@@ -0,0 +1,783 @@
+use crate::learning::checkpoint::*;
+use crate::learning::dataset::*;
+use crate::learning::learning::*;
+use crate::learning::relabel::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PatternSet {
+    Main,
+    Mini,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Optimiser {
+    Sgd,
+    AdaGrad,
+    Adam,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Schedule {
+    Constant,
+    // Multiply the rate by `gamma` every `every` epochs.
+    Step { every: usize, gamma: f32 },
+    Cosine,
+}
+
+#[derive(Clone, Debug)]
+pub struct TrainOption {
+    pub pattern_set: PatternSet,
+    pub side_to_move: bool,
+    pub epoch: usize,
+    pub batch_size: usize,
+    pub learning_rate: f32,
+    pub optimiser: Optimiser,
+    pub l2: f32,
+    pub schedule: Schedule,
+    pub seed: u64,
+    pub phases: usize,
+    pub output: String,
+    // Checkpoint path, empty to disable. Written every `checkpoint_every`
+    // epochs with the optimiser state.
+    pub checkpoint: String,
+    pub checkpoint_every: usize,
+    pub resume: bool,
+    // Stop after this many epochs without a better validation loss, 0 to
+    // disable.
+    pub patience: usize,
+    // Per-epoch CSV log, empty to disable.
+    pub metrics: String,
+    // Worker threads, 0 for one per core. Results do not depend on it.
+    pub threads: usize,
+    // Fail instead of skipping broken records.
+    pub strict: bool,
+    // Search-backed targets, see `relabel`. Off while both depths are 0.
+    pub label_depth: i32,
+    pub label_exact: i32,
+    pub label_lambda: f32,
+    pub label_model: String,
+    pub label_cache: String,
+    // Position dataset to stream the training samples from instead of
+    // replaying records, see `dataset`. Empty to replay records.
+    pub dataset: String,
+    // Positions read and shuffled together when streaming.
+    pub stream_block: usize,
+}
+
+impl Default for TrainOption {
+    fn default() -> TrainOption {
+        TrainOption {
+            pattern_set: PatternSet::Main,
+            side_to_move: false,
+            epoch: 100,
+            batch_size: 256,
+            learning_rate: 0.01,
+            optimiser: Optimiser::Adam,
+            l2: 0.0,
+            schedule: Schedule::Constant,
+            seed: 0,
+            phases: 1,
+            output: "train_result4.txt".to_string(),
+            checkpoint: String::new(),
+            checkpoint_every: 1,
+            resume: false,
+            patience: 0,
+            metrics: String::new(),
+            threads: 0,
+            strict: false,
+            label_depth: 0,
+            label_exact: 0,
+            label_lambda: 0.0,
+            label_model: String::new(),
+            label_cache: "label_cache".to_string(),
+            dataset: String::new(),
+            stream_block: 1 << 20,
+        }
+    }
+}
+
+impl TrainOption {
+    // Applies one `key=value` setting such as `optimiser=adam` or
+    // `schedule=step:10:0.5`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "pattern_set" => {
+                self.pattern_set = match value {
+                    "main" => PatternSet::Main,
+                    "mini" => PatternSet::Mini,
+                    _ => return Err(format!("unknown pattern set: {}", value)),
+                }
+            }
+            "side_to_move" => self.side_to_move = option_value(key, value)?,
+            "epoch" => self.epoch = option_value(key, value)?,
+            "batch_size" => self.batch_size = option_value(key, value)?,
+            "learning_rate" => self.learning_rate = option_value(key, value)?,
+            "l2" => self.l2 = option_value(key, value)?,
+            "seed" => self.seed = option_value(key, value)?,
+            "phases" => {
+                self.phases = option_value(key, value)?;
+                if self.phases == 0 {
+                    return Err("phases must be positive".to_string());
+                }
+            }
+            "output" => self.output = value.to_string(),
+            "checkpoint" => self.checkpoint = value.to_string(),
+            "checkpoint_every" => self.checkpoint_every = option_value(key, value)?,
+            "resume" => self.resume = option_value(key, value)?,
+            "patience" => self.patience = option_value(key, value)?,
+            "metrics" => self.metrics = value.to_string(),
+            "threads" => self.threads = option_value(key, value)?,
+            "strict" => self.strict = option_value(key, value)?,
+            "label_depth" => self.label_depth = option_value(key, value)?,
+            "label_exact" => {
+                self.label_exact = option_value(key, value)?;
+                if self.label_exact > MAX_LABEL_EXACT {
+                    return Err(format!("label_exact must be at most {}", MAX_LABEL_EXACT));
+                }
+            }
+            "label_lambda" => self.label_lambda = option_value(key, value)?,
+            "label_model" => self.label_model = value.to_string(),
+            "label_cache" => self.label_cache = value.to_string(),
+            "dataset" => self.dataset = value.to_string(),
+            "stream_block" => self.stream_block = option_value(key, value)?,
+            "optimiser" => {
+                self.optimiser = match value {
+                    "sgd" => Optimiser::Sgd,
+                    "adagrad" => Optimiser::AdaGrad,
+                    "adam" => Optimiser::Adam,
+                    _ => return Err(format!("unknown optimiser: {}", value)),
+                }
+            }
+            "schedule" => {
+                let parts: Vec<&str> = value.split(':').collect();
+                self.schedule = match parts.as_slice() {
+                    ["constant"] => Schedule::Constant,
+                    ["cosine"] => Schedule::Cosine,
+                    ["step", every, gamma] => Schedule::Step {
+                        every: option_value(key, every)?,
+                        gamma: option_value(key, gamma)?,
+                    },
+                    _ => return Err(format!("unknown schedule: {}", value)),
+                }
+            }
+            _ => return Err(format!("unknown option: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+impl PatternSet {
+    pub fn indexes(self, board: &Board) -> [usize; PATTERN_INSTANCE_NUM] {
+        match self {
+            PatternSet::Main => pattern_indexes(board),
+            PatternSet::Mini => {
+                let (p3_0, p3_1, p3_2, p3_3) = pattern3_indexes(board);
+                let (p4_0, p4_1) = pattern4_indexes(board);
+                let (p5_0, p5_1, p5_2, p5_3) = pattern5_indexes(board);
+                [p3_0, p3_1, p3_2, p3_3, p4_0, p4_1, p5_0, p5_1, p5_2, p5_3]
+            }
+        }
+    }
+
+    pub fn shares(self, side_to_move: bool) -> PatternShares {
+        match self {
+            PatternSet::Main => pattern_shares(side_to_move),
+            PatternSet::Mini => pattern_shares_mini(side_to_move),
+        }
+    }
+}
+
+// Instances 0-3, 4-5 and 6-9 read the first, second and third pattern table.
+#[inline]
+fn instance_table(p: usize) -> usize {
+    if p < 4 {
+        0
+    } else if p < 6 {
+        1
+    } else {
+        2
+    }
+}
+
+// One training position reduced to the weights it touches.
+pub struct Sample {
+    pub weight: [u32; PATTERN_INSTANCE_NUM],
+    pub sign: [i8; PATTERN_INSTANCE_NUM],
+    pub scalar: ScalarFeature,
+    pub target: f32,
+    pub phase: u8,
+}
+
+// All trainable weights in one vector: the three shared pattern tables
+// followed by the scalar weights.
+pub struct Model {
+    pub pattern_set: PatternSet,
+    pub shares: PatternShares,
+    pub offsets: [usize; 4],
+    pub weights: Vec<f32>,
+}
+
+impl Model {
+    pub fn new(pattern_set: PatternSet, side_to_move: bool) -> Model {
+        let shares = pattern_set.shares(side_to_move);
+        let offsets = [
+            0,
+            shares.0.size,
+            shares.0.size + shares.1.size,
+            shares.0.size + shares.1.size + shares.2.size,
+        ];
+        let weights = vec![0.0; offsets[3] + SCALAR_NUM];
+        Model {
+            pattern_set,
+            shares,
+            offsets,
+            weights,
+        }
+    }
+
+    fn share(&self, table: usize) -> &PatternShare {
+        match table {
+            0 => &self.shares.0,
+            1 => &self.shares.1,
+            _ => &self.shares.2,
+        }
+    }
+
+    pub fn sample(&self, board: &Board, target: f32, phase: usize) -> Sample {
+        let indexes = self.pattern_set.indexes(board);
+        let mut weight = [0; PATTERN_INSTANCE_NUM];
+        let mut sign = [0; PATTERN_INSTANCE_NUM];
+        for (p, &index) in indexes.iter().enumerate() {
+            let table = instance_table(p);
+            let share = self.share(table);
+            weight[p] = (self.offsets[table] + share.slot[index]) as u32;
+            sign[p] = share.sign[index] as i8;
+        }
+        Sample {
+            weight,
+            sign,
+            scalar: scalar_features(board),
+            target,
+            phase: phase as u8,
+        }
+    }
+
+    pub fn predict(&self, sample: &Sample) -> f32 {
+        let mut score = 0.0;
+        for (&w, &s) in sample.weight.iter().zip(sample.sign.iter()) {
+            score += self.weights[w as usize] * s as f32;
+        }
+        for (k, &f) in sample.scalar.iter().enumerate() {
+            score += self.weights[self.offsets[3] + k] * f;
+        }
+        score
+    }
+
+    // Reads weights written by `save`.
+    pub fn load(path: &str, pattern_set: PatternSet, side_to_move: bool) -> Result<Model, String> {
+        let mut model = Model::new(pattern_set, side_to_move);
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let values: Vec<f32> = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| {
+                line.trim()
+                    .parse()
+                    .map_err(|_| format!("{}:{}: invalid number \"{}\"", path, i + 1, line))
+            })
+            .collect::<Result<_, _>>()?;
+        let expanded =
+            model.shares.0.slot.len() + model.shares.1.slot.len() + model.shares.2.slot.len();
+        if values.len() != expanded + SCALAR_NUM {
+            return Err(format!(
+                "{}: expected {} weights, found {}",
+                path,
+                expanded + SCALAR_NUM,
+                values.len()
+            ));
+        }
+        let mut rest = values.as_slice();
+        for table in 0..3 {
+            let share = model.share(table);
+            let (head, tail) = rest.split_at(share.slot.len());
+            rest = tail;
+            let updates: Vec<(usize, f32)> = head
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| share.sign[i] != 0.0)
+                .map(|(i, &value)| (model.offsets[table] + share.slot[i], value * share.sign[i]))
+                .collect();
+            for (w, value) in updates {
+                model.weights[w] = value;
+            }
+        }
+        let offset = model.offsets[3];
+        model.weights[offset..].copy_from_slice(rest);
+        Ok(model)
+    }
+
+    pub fn save(&self, path: &str) {
+        let pattern_value = (
+            self.weights[self.offsets[0]..self.offsets[1]].to_vec(),
+            self.weights[self.offsets[1]..self.offsets[2]].to_vec(),
+            self.weights[self.offsets[2]..self.offsets[3]].to_vec(),
+        );
+        write_weights(
+            path,
+            &pattern_value,
+            &self.shares,
+            &self.weights[self.offsets[3]..],
+        );
+    }
+}
+
+// Splits the game into `phases` equal ranges of disc count. `phases` is
+// positive.
+pub fn board_phase(board: &Board, phases: usize) -> usize {
+    let discs = (board.black_board | board.white_board).count_ones() as usize;
+    (discs.saturating_sub(4) * phases / 61).min(phases - 1)
+}
+
+// Records are replayed in parallel; samples keep the record order. The
+// target is the final disc difference unless `targets` gives one per
+// position.
+pub fn extract_samples(
+    model: &Model,
+    records: &[String],
+    targets: Option<&[Vec<f32>]>,
+    phases: usize,
+    threads: usize,
+) -> Vec<Sample> {
+    let indexes: Vec<usize> = (0..records.len()).collect();
+    parallel_map(&indexes, threads, |&i| {
+        let (boards, final_diff) = replay_record(&records[i]);
+        boards
+            .iter()
+            .enumerate()
+            .map(|(j, board)| {
+                let target = targets.map_or(final_diff as f32, |targets| targets[i][j]);
+                model.sample(board, target, board_phase(board, phases))
+            })
+            .collect::<Vec<Sample>>()
+    })
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+pub fn phase_output(output: &str, phase: usize, phases: usize) -> String {
+    if phases <= 1 {
+        return output.to_string();
+    }
+    match output.rsplit_once('.') {
+        Some((stem, extension)) => format!("{}_{}.{}", stem, phase, extension),
+        None => format!("{}_{}", output, phase),
+    }
+}
+
+// Samples per block when the loss is summed in parallel. The blocks are
+// fixed, so the sums do not change with the thread count.
+const LOSS_BLOCK: usize = 4096;
+// Fewest batch samples worth a thread of their own. A prediction takes about
+// 15ns and starting a thread about 20µs, so smaller shares cost more to hand
+// out than to compute.
+const PARALLEL_CHUNK: usize = 8192;
+
+// Sums of absolute and squared errors.
+fn loss_sums(model: &Model, samples: &[&Sample], threads: usize) -> (f64, f64) {
+    let blocks: Vec<&[&Sample]> = samples.chunks(LOSS_BLOCK).collect();
+    let sums = parallel_map(&blocks, threads, |block| {
+        let mut abs_sum = 0.0;
+        let mut square_sum = 0.0;
+        for sample in block.iter() {
+            let error = model.predict(sample) - sample.target;
+            abs_sum += error.abs() as f64;
+            square_sum += (error * error) as f64;
+        }
+        (abs_sum, square_sum)
+    });
+    sums.iter().fold((0.0, 0.0), |(a, s), (block_a, block_s)| {
+        (a + block_a, s + block_s)
+    })
+}
+
+// Mean absolute and mean squared error.
+pub fn sample_loss(model: &Model, samples: &[&Sample], threads: usize) -> (f32, f32) {
+    let (abs_sum, square_sum) = loss_sums(model, samples, threads);
+    let len = samples.len().max(1) as f64;
+    ((abs_sum / len) as f32, (square_sum / len) as f32)
+}
+
+pub fn scheduled_rate(option: &TrainOption, epoch: usize) -> f32 {
+    match option.schedule {
+        Schedule::Constant => option.learning_rate,
+        Schedule::Step { every, gamma } => {
+            option.learning_rate * gamma.powi((epoch / every.max(1)) as i32)
+        }
+        Schedule::Cosine => {
+            let progress = epoch as f32 / option.epoch.max(1) as f32;
+            option.learning_rate * 0.5 * (1.0 + (std::f32::consts::PI * progress).cos())
+        }
+    }
+}
+
+pub struct OptimiserState {
+    pub first: Vec<f32>,
+    pub second: Vec<f32>,
+    pub step: i32,
+}
+
+impl OptimiserState {
+    pub fn new(size: usize) -> OptimiserState {
+        OptimiserState {
+            first: vec![0.0; size],
+            second: vec![0.0; size],
+            step: 0,
+        }
+    }
+}
+
+// Trains `model` in place on one mini-batch. Only the weights the batch
+// touches are updated, and L2 is applied to those weights lazily.
+pub fn train_batch(
+    model: &mut Model,
+    state: &mut OptimiserState,
+    batch: &[&Sample],
+    option: &TrainOption,
+    learning_rate: f32,
+    gradient: &mut [f32],
+    touched: &mut Vec<usize>,
+) {
+    let scale = 1.0 / batch.len() as f32;
+    // Errors of large batches are computed in parallel and scattered into the
+    // gradient in batch order, which gives the same sums as a serial pass.
+    let threads = thread_count(option.threads).min(batch.len() / PARALLEL_CHUNK);
+    let errors = parallel_map(batch, threads, |sample| {
+        (model.predict(sample) - sample.target) * scale
+    });
+    for (sample, &error) in batch.iter().zip(errors.iter()) {
+        for (&w, &s) in sample.weight.iter().zip(sample.sign.iter()) {
+            if s == 0 {
+                continue;
+            }
+            let w = w as usize;
+            if gradient[w] == 0.0 {
+                touched.push(w);
+            }
+            gradient[w] += error * s as f32;
+        }
+        for (k, &f) in sample.scalar.iter().enumerate() {
+            let w = model.offsets[3] + k;
+            if gradient[w] == 0.0 {
+                touched.push(w);
+            }
+            gradient[w] += error * f;
+        }
+    }
+    touched.sort_unstable();
+    touched.dedup();
+
+    state.step += 1;
+    let (beta1, beta2, epsilon) = (0.9f32, 0.999f32, 1e-8f32);
+    let correction1 = 1.0 - beta1.powi(state.step);
+    let correction2 = 1.0 - beta2.powi(state.step);
+    for &w in touched.iter() {
+        let g = gradient[w] + option.l2 * model.weights[w];
+        match option.optimiser {
+            Optimiser::Sgd => {
+                model.weights[w] -= learning_rate * g;
+            }
+            Optimiser::AdaGrad => {
+                state.second[w] += g * g;
+                model.weights[w] -= learning_rate * g / (state.second[w].sqrt() + epsilon);
+            }
+            Optimiser::Adam => {
+                state.first[w] = beta1 * state.first[w] + (1.0 - beta1) * g;
+                state.second[w] = beta2 * state.second[w] + (1.0 - beta2) * g * g;
+                let m = state.first[w] / correction1;
+                let v = state.second[w] / correction2;
+                model.weights[w] -= learning_rate * m / (v.sqrt() + epsilon);
+            }
+        }
+        gradient[w] = 0.0;
+    }
+    touched.clear();
+}
+
+fn samples_in_phase(samples: &[Sample], phase: usize) -> Vec<&Sample> {
+    samples
+        .iter()
+        .filter(|s| s.phase as usize == phase)
+        .collect()
+}
+
+// Where the training samples of one phase come from.
+pub enum TrainData<'a> {
+    Samples(&'a [&'a Sample]),
+    // Positions of `phase` read in blocks of `stream_block`; `template` only
+    // turns them into samples.
+    Stream {
+        dataset: &'a Dataset,
+        phase: usize,
+        template: &'a Model,
+    },
+}
+
+impl TrainData<'_> {
+    fn len(&self) -> usize {
+        match self {
+            TrainData::Samples(samples) => samples.len(),
+            TrainData::Stream { dataset, phase, .. } => dataset.counts[*phase] as usize,
+        }
+    }
+
+    // Calls `f` block by block, shuffling the blocks and the samples in them
+    // when `rng` is given. Samples in memory are a single block.
+    fn for_each_block(
+        &self,
+        option: &TrainOption,
+        mut rng: Option<&mut StdRng>,
+        f: &mut dyn FnMut(&[&Sample]),
+    ) -> Result<(), String> {
+        match self {
+            TrainData::Samples(samples) => match rng {
+                Some(rng) => {
+                    let mut order: Vec<usize> = (0..samples.len()).collect();
+                    order.shuffle(rng);
+                    let shuffled: Vec<&Sample> = order.iter().map(|&j| samples[j]).collect();
+                    f(&shuffled);
+                }
+                None => f(samples),
+            },
+            TrainData::Stream {
+                dataset,
+                phase,
+                template,
+            } => {
+                let block = option.stream_block.max(1);
+                let mut starts: Vec<u64> = (0..dataset.counts[*phase]).step_by(block).collect();
+                if let Some(rng) = rng.as_deref_mut() {
+                    starts.shuffle(rng);
+                }
+                for start in starts {
+                    let positions = dataset
+                        .read(*phase, start, block)
+                        .map_err(|e| format!("{}: {}", dataset.path, e))?;
+                    let samples = parallel_map(&positions, thread_count(option.threads), |p| {
+                        template.sample(&p.board(), p.target, *phase)
+                    });
+                    let mut block_samples: Vec<&Sample> = samples.iter().collect();
+                    if let Some(rng) = rng.as_deref_mut() {
+                        block_samples.shuffle(rng);
+                    }
+                    f(&block_samples);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn train_phase(
+    option: &TrainOption,
+    phase: usize,
+    data: &TrainData,
+    validation_samples: &[&Sample],
+) -> Model {
+    let mut model = Model::new(option.pattern_set, option.side_to_move);
+    let threads = thread_count(option.threads);
+    println!(
+        "phase: {}  samples: {}  validation samples: {}  weights: {}",
+        phase,
+        data.len(),
+        validation_samples.len(),
+        model.weights.len()
+    );
+
+    let mut state = OptimiserState::new(model.weights.len());
+    let mut gradient = vec![0.0; model.weights.len()];
+    let mut touched: Vec<usize> = vec![];
+    let output = phase_output(&option.output, phase, option.phases);
+    let checkpoint = phase_output(&option.checkpoint, phase, option.phases);
+    let mut start_epoch = 0;
+    let mut best_loss = f32::INFINITY;
+    let mut stale = 0;
+
+    if option.resume && !option.checkpoint.is_empty() && Path::new(&checkpoint).exists() {
+        match load_checkpoint(&checkpoint) {
+            Ok(saved) if saved.weights.len() == model.weights.len() => {
+                println!("resume from {} at epoch {}", checkpoint, saved.epoch);
+                start_epoch = saved.epoch;
+                best_loss = saved.best_loss;
+                stale = saved.stale;
+                model.weights = saved.weights;
+                state = saved.state;
+            }
+            Ok(_) => println!("{}: weight count does not match, starting over", checkpoint),
+            Err(e) => println!("{}: {}, starting over", checkpoint, e),
+        }
+    }
+
+    for i in start_epoch..option.epoch {
+        let learning_rate = scheduled_rate(option, i);
+        // Seeded per epoch so a resumed run shuffles exactly like an
+        // uninterrupted one.
+        let mut rng = StdRng::seed_from_u64(option.seed ^ ((phase as u64) << 32) ^ i as u64);
+        let trained = data.for_each_block(option, Some(&mut rng), &mut |samples| {
+            for batch in samples.chunks(option.batch_size.max(1)) {
+                train_batch(
+                    &mut model,
+                    &mut state,
+                    batch,
+                    option,
+                    learning_rate,
+                    &mut gradient,
+                    &mut touched,
+                );
+            }
+        });
+        let mut sums = (0.0, 0.0);
+        let measured = trained.and_then(|()| {
+            data.for_each_block(option, None, &mut |samples| {
+                let block_sums = loss_sums(&model, samples, threads);
+                sums = (sums.0 + block_sums.0, sums.1 + block_sums.1);
+            })
+        });
+        if let Err(e) = measured {
+            println!("{}", e);
+            break;
+        }
+        let len = data.len().max(1) as f64;
+        let train_loss = ((sums.0 / len) as f32, (sums.1 / len) as f32);
+        let validation_loss = if validation_samples.is_empty() {
+            train_loss
+        } else {
+            sample_loss(&model, validation_samples, threads)
+        };
+        println!(
+            "epoch: {}  rate: {}  train loss = {}  validation loss = {}",
+            i, learning_rate, train_loss.0, validation_loss.0
+        );
+        if !option.metrics.is_empty() {
+            if let Err(e) = append_metrics(
+                &option.metrics,
+                phase,
+                i,
+                learning_rate,
+                train_loss,
+                validation_loss,
+            ) {
+                println!("{}: {}", option.metrics, e);
+            }
+        }
+
+        // The output always holds the weights with the best validation loss.
+        if validation_loss.0 < best_loss {
+            best_loss = validation_loss.0;
+            stale = 0;
+            model.save(&output);
+        } else {
+            stale += 1;
+        }
+        let stop = option.patience > 0 && stale >= option.patience;
+        if !option.checkpoint.is_empty()
+            && ((i + 1) % option.checkpoint_every.max(1) == 0 || stop || i + 1 == option.epoch)
+        {
+            if let Err(e) = save_checkpoint(&checkpoint, i + 1, stale, best_loss, &model, &state) {
+                println!("{}: {}", checkpoint, e);
+            }
+        }
+        if stop {
+            println!(
+                "early stopping: no improvement for {} epochs, best validation loss = {}",
+                stale, best_loss
+            );
+            break;
+        }
+    }
+    if !Path::new(&output).exists() {
+        model.save(&output);
+    }
+    model
+}
+
+pub fn train_batches(
+    option: &TrainOption,
+    records: &[String],
+    validation_records: &[String],
+) -> Vec<Model> {
+    let template = Model::new(option.pattern_set, option.side_to_move);
+    let threads = thread_count(option.threads);
+    // Validation keeps the final results so runs stay comparable.
+    let validation_samples =
+        extract_samples(&template, validation_records, None, option.phases, threads);
+    if !option.dataset.is_empty() {
+        let dataset = match Dataset::open(&option.dataset) {
+            Ok(dataset) if dataset.phases() == option.phases => dataset,
+            Ok(dataset) => {
+                println!(
+                    "{}: built for {} phases, not {}",
+                    option.dataset,
+                    dataset.phases(),
+                    option.phases
+                );
+                return vec![];
+            }
+            Err(e) => {
+                println!("{}: {}", option.dataset, e);
+                return vec![];
+            }
+        };
+        return (0..option.phases)
+            .map(|phase| {
+                let data = TrainData::Stream {
+                    dataset: &dataset,
+                    phase,
+                    template: &template,
+                };
+                train_phase(
+                    option,
+                    phase,
+                    &data,
+                    &samples_in_phase(&validation_samples, phase),
+                )
+            })
+            .collect();
+    }
+
+    let targets = if option.label_depth > 0 || option.label_exact > 0 {
+        match relabel_records(option, records) {
+            Ok(targets) => Some(targets),
+            Err(e) => {
+                println!("{}", e);
+                return vec![];
+            }
+        }
+    } else {
+        None
+    };
+    let samples = extract_samples(
+        &template,
+        records,
+        targets.as_deref(),
+        option.phases,
+        threads,
+    );
+    (0..option.phases)
+        .map(|phase| {
+            train_phase(
+                option,
+                phase,
+                &TrainData::Samples(&samples_in_phase(&samples, phase)),
+                &samples_in_phase(&validation_samples, phase),
+            )
+        })
+        .collect()
+}
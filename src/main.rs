@@ -1,4 +1,3 @@
-use std::collections::HashMap;
 use std::env;
 use std::io;
 use std::net::SocketAddr;
@@ -6,57 +5,315 @@ use std::net::ToSocketAddrs;
 mod ai;
 mod client;
 mod learning;
+mod record;
 mod reversi;
 mod util;
-use client::client::{ClientState::*, Command::*, *};
+use client::server::*;
+use client::session::*;
+use learning::config::*;
+use learning::dataset::*;
 use learning::learning::*;
+use learning::nnue::*;
+use learning::self_play::*;
+use learning::trainer::*;
 mod parameter;
 use ai::ai::*;
+use ai::book::*;
+use ai::book_builder::*;
+use ai::book_file::*;
+use ai::book_learning::*;
+use ai::game_book::*;
+use ai::opening_suite::*;
+use record::loader::*;
+use record::opening::*;
+use record::record::*;
+use record::wthor::*;
 use reversi::reversi::*;
-use std::io::Write;
-use std::io::{BufRead, BufReader};
 use std::net::TcpStream;
+use std::path::Path;
+use std::process;
 use std::time::Duration;
 use std::time::Instant;
 use util::util::*;
 
+// Removes `flag` and the value after it from `args`. A flag without a value
+// is a usage error.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == flag)?;
+    if i + 1 >= args.len() {
+        println!("{} needs a value", flag);
+        process::exit(1);
+    }
+    let value = args.remove(i + 1);
+    args.remove(i);
+    Some(value)
+}
+
+// Reads the `key=value` arguments of a mode into its options.
+fn parse_options<T: Default>(
+    args: &[String],
+    set: fn(&mut T, &str, &str) -> Result<(), String>,
+) -> Result<T, String> {
+    let mut option = T::default();
+    for arg in args {
+        let (key, value) = arg.split_once('=').unwrap_or((arg, ""));
+        set(&mut option, key, value)?;
+    }
+    Ok(option)
+}
+
+// The network client's engine, with the book and the positions it learned
+// in earlier games.
+fn search_engine(
+    book_path: String,
+    book_margin: f32,
+    games_path: String,
+    openings: Openings,
+    depth: i32,
+) -> SearchEngine {
+    let mut book = Book {
+        margin: book_margin,
+        ..Book::default()
+    };
+    let mut learned = Book::default();
+    if Path::new(&book_path).is_file() {
+        create_book(&book_path, &mut book);
+        match load_learned_book(&book_path, &mut book) {
+            Ok(book) => learned = book,
+            Err(e) => log!("Failed in opening learned book ({}).", e),
+        }
+    } else {
+        log!("{} is not found, playing without a book.", book_path);
+    }
+    SearchEngine {
+        book,
+        learned,
+        book_path,
+        games_path,
+        openings,
+        depth,
+    }
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // With `-stdio` stdout carries the protocol from the first line on.
+    if args.iter().any(|arg| arg == "-stdio") {
+        set_log_to_stderr(true);
+    }
+    if let Some(path) = take_flag(&mut args, "-nnue") {
+        match load_nnue(&path) {
+            Ok(()) => log!("NNUE is loaded."),
+            Err(e) => log!("Failed in loading NNUE ({}).", e),
+        }
+    }
+    // Every network game is appended to this GGF file.
+    let games_path = take_flag(&mut args, "-games").unwrap_or(String::from("games.ggf"));
+    // Book the client loads when it connects.
+    let book_path = take_flag(&mut args, "-book").unwrap_or(String::from("book.txt"));
+    // Book moves this much worse than the best one are played too.
+    let mut book_margin = 0.0;
+    if let Some(value) = take_flag(&mut args, "-bookmargin") {
+        book_margin = match value.parse::<f32>() {
+            Ok(margin) if margin >= 0.0 => margin,
+            _ => {
+                println!("invalid book margin: {}", value);
+                process::exit(1);
+            }
+        };
+    }
+    // Opening names for the interactive mode, the client log and exports.
+    let openings_path = take_flag(&mut args, "-openings").unwrap_or(String::from("openings.txt"));
+    let openings = if Path::new(&openings_path).is_file() {
+        Openings::load(&openings_path).unwrap_or_else(|e| {
+            log!("{}", e);
+            Openings::default()
+        })
+    } else {
+        Openings::default()
+    };
     let argc = args.len();
 
-    if argc == 2 && args[1] == String::from("-train") {
+    if argc == 4 && args[1] == "-train" && args[2] == "--config" {
+        println!("Training mode");
+        train_from_config(&args[3]);
+        return;
+    }
+    if argc >= 2 && args[1] == "-train" {
+        if argc > 3 || (argc == 3 && args[2] != "-side") {
+            println!("usage: -train [-side] | -train --config <file>");
+            return;
+        }
         println!("Training mode");
-        train(100, 0.0001);
+        if let Err(e) = train(100, 0.0001, argc == 3) {
+            println!("{}", e);
+        }
+        return;
+    }
+    if argc >= 2 && args[1] == "-batchtrain" {
+        println!("Mini-batch training mode");
+        let loaded = parse_options(&args[2..], TrainOption::set).and_then(|option| {
+            // A dataset replaces the training records.
+            let paths = if option.dataset.is_empty() {
+                training_paths()
+            } else {
+                vec![]
+            };
+            let records = load_records(&paths, option.strict)?;
+            let validation = load_records(&["self_play/0000019.txt".to_string()], option.strict)?;
+            Ok((option, records, validation))
+        });
+        match loaded {
+            Ok((option, records, validation_records)) => {
+                train_batches(&option, &records, &validation_records);
+            }
+            Err(e) => println!("{}", e),
+        }
+        return;
+    }
+    if argc >= 2 && args[1] == "-dataset" {
+        println!("Dataset mode");
+        if let Err(e) = parse_options(&args[2..], DatasetOption::set).and_then(|option| {
+            let records = load_records(&training_paths(), option.train.strict)?;
+            make_dataset(&option, &records)
+        }) {
+            println!("{}", e);
+        }
+        return;
+    }
+    if argc >= 2 && args[1] == "-buildbook" {
+        println!("Book building mode");
+        match parse_options(&args[2..], BookBuildOption::set) {
+            Ok(option) => build_book_file(&option),
+            Err(e) => println!("{}", e),
+        }
+        return;
+    }
+    if argc == 4 && args[1] == "-bookconvert" {
+        convert_book(&args[2], &args[3]);
+        return;
+    }
+    if argc >= 4 && args[1] == "-bookmerge" {
+        merge_books(&args[3..], &args[2]);
+        return;
+    }
+    if argc >= 2 && args[1] == "-booklearn" {
+        println!("Book learning mode");
+        if let Err(e) =
+            parse_options(&args[2..], BookLearnOption::set).and_then(|option| learn_book(&option))
+        {
+            println!("{}", e);
+        }
+        return;
+    }
+    if argc >= 4 && args[1] == "-bookprune" {
+        prune_book(&args[2], &args[3], &args[4..]);
+        return;
+    }
+    if argc >= 2 && args[1] == "-gamebook" {
+        println!("Book from games mode");
+        match parse_options(&args[2..], GameBookOption::set) {
+            Ok(option) => make_game_book(&option),
+            Err(e) => println!("{}", e),
+        }
+        return;
+    }
+    if argc >= 2 && args[1] == "-suite" {
+        println!("Opening suite mode");
+        if let Err(e) =
+            parse_options(&args[2..], SuiteOption::set).and_then(|option| write_suite(&option))
+        {
+            println!("{}", e);
+        }
+        return;
+    }
+    if argc >= 2 && args[1] == "-match" {
+        println!("Match mode");
+        if let Err(e) =
+            parse_options(&args[2..], MatchOption::set).and_then(|option| run_match(&option))
+        {
+            println!("{}", e);
+        }
+        return;
+    }
+    if argc >= 2 && args[1] == "-server" {
+        println!("Server mode");
+        if let Err(e) =
+            parse_options(&args[2..], ServerOption::set).and_then(|option| serve(&option))
+        {
+            println!("{}", e);
+        }
+        return;
+    }
+    if argc >= 2 && args[1] == "-selfplay" {
+        println!("Self-play mode");
+        if let Err(e) = parse_options(&args[2..], SelfPlayOption::set).and_then(|option| {
+            check_self_play_output(&option)?;
+            let games = self_play(&option)?;
+            write_self_play(&option, &games)
+        }) {
+            println!("{}", e);
+        }
+        return;
+    }
+    if argc == 2 && args[1] == "-nnuetrain" {
+        println!("NNUE training mode");
+        if let Err(e) = train_nnue(100, 0.001) {
+            println!("{}", e);
+        }
         return;
     }
-    if argc == 2 && args[1] == String::from("-minitrain") {
+    if (3..=5).contains(&argc) && args[1] == "-wthor" {
+        print_wthor(&args[2..]);
+        return;
+    }
+    if argc == 4 && args[1] == "-convert" {
+        convert_games(&args[2], &args[3], &openings);
+        return;
+    }
+    if argc == 3 && args[1] == "-explain" {
+        explain(&cmds_to_board(&args[2]));
+        return;
+    }
+    if argc >= 2 && args[1] == "-minitrain" {
+        if argc > 3 || (argc == 3 && args[2] != "-side") {
+            println!("usage: -minitrain [-side]");
+            return;
+        }
         println!("Mini training mode");
-        train_mini(300, 0.0001);
+        if let Err(e) = train_mini(300, 0.0001, argc == 3) {
+            println!("{}", e);
+        }
         return;
     }
 
-    let mut board: Board = Board {
-        black_board: 0,
-        white_board: 0,
-        turn: BLACK,
-        no_legal_command: 0,
-        value: 0,
-        before_pos: 0,
-    };
+    let depth = 12;
+    if argc == 3 && args[1] == "-stdio" {
+        let engine = search_engine(book_path, book_margin, games_path, openings, depth);
+        let transport = LineTransport {
+            reader: io::stdin().lock(),
+            writer: io::stdout(),
+        };
+        let mut session = ClientSession::new(transport, engine, &args[2]);
+        if let Err(e) = session.run() {
+            log!("Session error: {}", e);
+        }
+        return;
+    }
+
+    let mut board = Board::new(0, 0, BLACK);
 
-    let mut book: HashMap<Board, u64> = HashMap::new();
-    // create_book("book.txt", &mut book);
+    let book = Book {
+        margin: book_margin,
+        ..Book::default()
+    };
 
     init_board(&mut board);
     print_board(&board);
 
-    let mut player_turn;
-    let mut ai_turn;
+    let remaining_time: u64 = 60000;
 
-    let depth = 12;
-    let mut remaining_time: u64 = 60000;
-
-    let mut client_state = CardWaiting;
+    let mut game_record = GameRecord::default();
 
     if argc == 1 || args[1] != String::from("-debug") {
         let server_address;
@@ -72,165 +329,13 @@ fn main() {
             server_address = server_addresses[0].to_string();
             name = args[6].to_string();
         }
-        match TcpStream::connect(server_address) {
-            Ok(mut stream) => {
+        let engine = search_engine(book_path, book_margin, games_path, openings, depth);
+        match TcpStream::connect(server_address).and_then(LineTransport::tcp) {
+            Ok(transport) => {
                 println!("Connected to server.");
-                let mut reader = BufReader::new(stream.try_clone().unwrap());
-                let mut received_mes = String::new();
-
-                let request = String::from("OPEN ") + &name + &String::from("\n");
-                let request_mes = request.as_bytes();
-                stream.write_all(request_mes).unwrap();
-                print!("Sent: {}", request);
-
-                'main: loop {
-                    match client_state {
-                        CardWaiting => {
-                            received_mes.clear();
-                            reader.read_line(&mut received_mes).unwrap();
-                            print!("Recieved: {}", received_mes);
-
-                            match mes_to_command(&received_mes) {
-                                Start(wb, opponent_name, _) => {
-                                    ai_turn = if wb == String::from("BLACK") {
-                                        BLACK
-                                    } else {
-                                        WHITE
-                                    };
-                                    player_turn = !ai_turn;
-                                    println!("Opponent name: {}", opponent_name);
-                                    println!("ai_turn {}  oppoennt_turn {}", ai_turn, player_turn);
-                                    client_state = if ai_turn == BLACK {
-                                        MyTurn
-                                    } else {
-                                        OpponentTurn
-                                    };
-                                }
-                                Bye(stat) => {
-                                    println!("Stat: {}", stat);
-                                    println!("Bybye.");
-                                    client_state = Ended;
-                                }
-                                _ => {
-                                    println!("Unexpected message (waiting card): {}", received_mes);
-                                    panic!();
-                                }
-                            }
-                        }
-                        MyTurn => {
-                            if book.is_empty() {
-                                println!("Book is created.");
-                                create_book("book.txt", &mut book);
-                            }
-                            let (pos, _) = ai_pos(
-                                &mut board,
-                                depth,
-                                String::from("ns"),
-                                &book,
-                                remaining_time,
-                            );
-                            if pos == 0 {
-                                println!("No legal command");
-                                board.no_legal_command += 1;
-                                board.turn = !board.turn;
-
-                                let request = String::from("MOVE PASS\n");
-                                let request_mes = request.as_bytes();
-                                stream.write_all(request_mes).unwrap();
-                                print!("Sent: {}", request);
-                            } else {
-                                println!("{}", pos_to_cmd(&pos));
-                                let request_string =
-                                    String::from("MOVE ") + &pos_to_cmd(&pos) + &String::from("\n");
-                                let request = request_string.as_bytes();
-                                stream.write_all(request).unwrap();
-                                board = execute_pos(&mut board, pos);
-                            }
-                            client_state = AckWaiting;
-                        }
-                        AckWaiting => {
-                            received_mes.clear();
-                            reader.read_line(&mut received_mes).unwrap();
-                            print!("Recieved: {}", received_mes);
-
-                            match mes_to_command(&received_mes) {
-                                Ack(time) => {
-                                    remaining_time = time;
-                                    println!("Remaining time: {}", time);
-                                    client_state = OpponentTurn;
-                                }
-                                End(wl, n, m, reason) => {
-                                    println!("{} : {}", n, m);
-                                    if wl == String::from("WIN") {
-                                        println!("Win!");
-                                    } else if wl == String::from("LOSE") {
-                                        println!("Lose...");
-                                    } else {
-                                        println!("Tie.");
-                                    }
-                                    println!("Ended reason: {}", reason);
-                                    print_board(&board);
-                                    init_board(&mut board);
-                                    client_state = CardWaiting;
-                                }
-                                _ => {
-                                    println!("Unexpected message (waiting ack): {}", received_mes);
-                                    panic!();
-                                }
-                            }
-                        }
-                        OpponentTurn => {
-                            received_mes.clear();
-                            reader.read_line(&mut received_mes).unwrap();
-                            print!("Recieved: {}", received_mes);
-
-                            match mes_to_command(&received_mes) {
-                                Move(move_cmd) => {
-                                    if move_cmd == String::from("PASS") {
-                                        board.no_legal_command += 1;
-                                        board.turn = !board.turn;
-                                    } else {
-                                        let pos = cmd_to_pos(move_cmd);
-                                        if pos == 0 {
-                                            panic!();
-                                        }
-                                        if is_legal_pos(&board, &pos) {
-                                            board = execute_pos(&mut board, pos);
-                                        } else {
-                                            println!("Illegal command");
-                                            panic!();
-                                        }
-                                    }
-                                    client_state = MyTurn;
-                                }
-                                End(wl, n, m, reason) => {
-                                    println!("{} : {}", n, m);
-                                    if wl == String::from("WIN") {
-                                        println!("Win!");
-                                    } else if wl == String::from("LOSE") {
-                                        println!("Lose...");
-                                    } else {
-                                        println!("Tie.");
-                                    }
-                                    println!("Ended reason: {}", reason);
-                                    print_board(&board);
-                                    init_board(&mut board);
-                                    client_state = CardWaiting;
-                                }
-                                _ => {
-                                    println!(
-                                        "Unexpected message (opponent turn): {}",
-                                        received_mes
-                                    );
-                                    panic!();
-                                }
-                            }
-                        }
-                        Ended => {
-                            println!("Ended");
-                            break 'main;
-                        }
-                    }
+                let mut session = ClientSession::new(transport, engine, &name);
+                if let Err(e) = session.run() {
+                    println!("Session error: {}", e);
                 }
             }
             Err(e) => {
@@ -243,6 +348,7 @@ fn main() {
         let mut white_duration_sum = Duration::from_secs(0);
         if argc == 4 {
             while board_state(&board) == 0 {
+                let occupied = board.black_board | board.white_board;
                 if board.turn {
                     let (pos, duration) =
                         ai_pos(&mut board, depth, args[2].clone(), &book, remaining_time);
@@ -269,6 +375,9 @@ fn main() {
                     }
                 }
                 print_board(&board);
+                let pos = (board.black_board | board.white_board) & !occupied;
+                game_record.moves.push(GameMove { pos, time: None });
+                openings.log_opening(&game_record);
                 println!(
                     "{}: {}  {}: {}",
                     BLACK_STONE,
@@ -279,8 +388,9 @@ fn main() {
             }
         } else {
             assert_eq!(argc, 5);
-            player_turn = if args[3] == "s" { BLACK } else { WHITE };
+            let player_turn = if args[3] == "s" { BLACK } else { WHITE };
             while board_state(&board) == 0 {
+                let occupied = board.black_board | board.white_board;
                 if board.turn == player_turn {
                     let start_time = Instant::now();
                     if legal_poss(&board).len() == 0 {
@@ -328,6 +438,9 @@ fn main() {
                     }
                 }
                 print_board(&board);
+                let pos = (board.black_board | board.white_board) & !occupied;
+                game_record.moves.push(GameMove { pos, time: None });
+                openings.log_opening(&game_record);
                 println!(
                     "{}: {}  {}: {}",
                     BLACK_STONE,
@@ -337,6 +450,9 @@ fn main() {
                 );
             }
         }
+        if let Some(name) = openings.record_name(&game_record) {
+            println!("Opening: {}", name);
+        }
         if board_state(&board) == 1 {
             println!("{} win!", BLACK_STONE);
         } else if board_state(&board) == 2 {
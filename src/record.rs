@@ -0,0 +1,6 @@
+pub mod ggf;
+pub mod loader;
+pub mod opening;
+#[allow(clippy::module_inception)]
+pub mod record;
+pub mod wthor;
@@ -0,0 +1,246 @@
+use crate::record::record::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+/*
+
+GGF (Generic Game Format), one game per line
+
+(;GM[Othello]PC[place]DT[date]PB[black]PW[white]TI[time control]ON[opening]TY[8]
+RE[+12.000]BO[8 -------- -------- -------- ---O*--- ---*O--- -------- -------- -------- *]
+B[f5//1.20]W[d6]B[pa]...;)
+
+moves are `square/eval/seconds` with `pa` for a pass, and a result may end
+in `:t` (timeout), `:r` (resignation) or `:s` (agreed score)
+
+*/
+
+pub fn is_ggf(path: &str) -> bool {
+    path.to_lowercase().ends_with(".ggf")
+}
+
+// The standard start position in GGF board notation.
+fn start_board_ggf() -> String {
+    let board = cmds_to_board("");
+    let rows: Vec<String> = (0..8)
+        .map(|row| {
+            (0..8)
+                .map(|column| {
+                    let pos = 1u64 << (column + 8 * row);
+                    if board.black_board & pos != 0 {
+                        '*'
+                    } else if board.white_board & pos != 0 {
+                        'O'
+                    } else {
+                        '-'
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    format!("8 {} *", rows.join(" "))
+}
+
+fn parse_seconds(text: &str) -> Option<f32> {
+    // seconds, or minutes:seconds
+    let mut seconds = 0.0;
+    for part in text.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f32>().ok()?;
+    }
+    Some(seconds)
+}
+
+fn parse_ggf_move(value: &str) -> Result<GameMove, String> {
+    let mut fields = value.split('/');
+    let square = fields.next().unwrap_or("").trim().to_lowercase();
+    let _eval = fields.next();
+    let time = fields.next().and_then(|t| parse_seconds(t.trim()));
+    let pos = if square == "pa" {
+        0
+    } else {
+        let bytes = square.as_bytes();
+        if bytes.len() != 2
+            || !(b'a'..=b'h').contains(&bytes[0])
+            || !(b'1'..=b'8').contains(&bytes[1])
+        {
+            return Err(format!("invalid move \"{}\"", value));
+        }
+        lower_cmd_to_pos(square)
+    };
+    Ok(GameMove { pos, time })
+}
+
+fn parse_result(value: &str) -> Result<(i32, String), String> {
+    let (score, reason) = match value.split_once(':') {
+        Some((score, "t")) => (score, "timeout"),
+        Some((score, "r")) => (score, "resign"),
+        Some((score, "s")) => (score, "agreed"),
+        Some(_) => return Err(format!("invalid result \"{}\"", value)),
+        None => (value, ""),
+    };
+    let score: f32 = score
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid result \"{}\"", value))?;
+    Ok((score.round() as i32, reason.to_string()))
+}
+
+fn parse_ggf_game(game: &str) -> Result<GameRecord, String> {
+    let mut record = GameRecord::default();
+    let mut moves: Vec<GameMove> = vec![];
+    let mut colours: Vec<bool> = vec![];
+    let mut rest = game;
+    while let Some(open) = rest.find('[') {
+        let key = rest[..open].trim();
+        let close = rest[open..]
+            .find(']')
+            .ok_or(format!("unterminated property {}", key))?
+            + open;
+        let value = &rest[open + 1..close];
+        rest = &rest[close + 1..];
+        match key {
+            "GM" if !value.eq_ignore_ascii_case("othello") => {
+                return Err(format!("unsupported game \"{}\"", value))
+            }
+            "TY" if value.trim() != "8" => {
+                return Err(format!("unsupported board type \"{}\"", value))
+            }
+            "BO" => {
+                let normalised = value.split_whitespace().collect::<Vec<&str>>().join(" ");
+                if normalised != start_board_ggf() {
+                    return Err("only the standard start position is supported".to_string());
+                }
+            }
+            "PC" => record.place = value.to_string(),
+            "DT" => record.date = value.to_string(),
+            "PB" => record.black = value.to_string(),
+            "PW" => record.white = value.to_string(),
+            "TI" => record.time_control = value.to_string(),
+            "ON" => record.opening = value.to_string(),
+            "RE" => {
+                let (result, reason) = parse_result(value)?;
+                record.result = Some(result);
+                record.reason = reason;
+            }
+            "B" | "W" => {
+                moves.push(parse_ggf_move(value)?);
+                colours.push(key == "B");
+            }
+            _ => {}
+        }
+    }
+    let normalised = normalise_moves(&moves)?;
+    if normalised.len() != moves.len() {
+        return Err("a pass is missing".to_string());
+    }
+    // normalise_moves checked legality, this checks the colours match.
+    let mut board = cmds_to_board("");
+    for (i, (game_move, &colour)) in moves.iter().zip(colours.iter()).enumerate() {
+        if board.turn != colour {
+            return Err(format!("move {}: played by the wrong colour", i + 1));
+        }
+        if game_move.pos == 0 {
+            board.turn = !board.turn;
+        } else {
+            board = execute_pos(&mut board, game_move.pos);
+        }
+    }
+    record.moves = moves;
+    Ok(record)
+}
+
+// Reads every `(; ... ;)` game in `text`.
+pub fn parse_ggf(text: &str) -> Result<Vec<GameRecord>, String> {
+    let mut records: Vec<GameRecord> = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find("(;") {
+        let end = rest[start..]
+            .find(";)")
+            .ok_or(format!("game {}: missing \";)\"", records.len() + 1))?
+            + start;
+        let game = parse_ggf_game(&rest[start + 2..end])
+            .map_err(|e| format!("game {}: {}", records.len() + 1, e))?;
+        records.push(game);
+        rest = &rest[end + 2..];
+    }
+    Ok(records)
+}
+
+pub fn write_ggf(record: &GameRecord) -> String {
+    let mut text = String::from("(;GM[Othello]");
+    for (key, value) in [
+        ("PC", &record.place),
+        ("DT", &record.date),
+        ("PB", &record.black),
+        ("PW", &record.white),
+        ("TI", &record.time_control),
+        ("ON", &record.opening),
+    ] {
+        if !value.is_empty() {
+            text += &format!("{}[{}]", key, value.replace(']', ")"));
+        }
+    }
+    text += "TY[8]";
+    if let Some(result) = record.result {
+        let suffix = match record.reason.as_str() {
+            "timeout" => ":t",
+            "" | "double_pass" => "",
+            "agreed" => ":s",
+            _ => ":r",
+        };
+        text += &format!("RE[{:+}.000{}]", result, suffix);
+    }
+    text += &format!("BO[{}]", start_board_ggf());
+    let mut board = cmds_to_board("");
+    for game_move in &record.moves {
+        let square = if game_move.pos == 0 {
+            "pa".to_string()
+        } else {
+            pos_to_cmd(&game_move.pos).to_lowercase()
+        };
+        let time = game_move
+            .time
+            .map(|t| format!("//{:.2}", t))
+            .unwrap_or_default();
+        text += &format!("{}[{}{}]", if board.turn { "B" } else { "W" }, square, time);
+        if game_move.pos == 0 {
+            board.turn = !board.turn;
+        } else {
+            board = execute_pos(&mut board, game_move.pos);
+        }
+    }
+    text + ";)"
+}
+
+pub fn append_ggf(path: &str, record: &GameRecord) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    writeln!(file, "{}", write_ggf(record)).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Stores a finished network game; `discs` is (own, opponent's) as sent in
+// END.
+pub fn save_network_game(
+    path: &str,
+    record: &mut GameRecord,
+    own_turn: bool,
+    discs: (u32, u32),
+    reason: &str,
+) {
+    let (own, opponent) = (discs.0 as i32, discs.1 as i32);
+    record.result = Some(if own_turn == BLACK {
+        own - opponent
+    } else {
+        opponent - own
+    });
+    record.reason = reason.to_lowercase();
+    match append_ggf(path, record) {
+        Ok(()) => log!("Game is saved to {}.", path),
+        Err(e) => log!("Failed in saving game ({}).", e),
+    }
+}
@@ -0,0 +1,209 @@
+use crate::record::ggf::*;
+use crate::record::record::*;
+use crate::record::wthor::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use std::fmt;
+
+// Number of broken records printed in full before only counting them.
+const REPORTED_ERRORS: usize = 20;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordError {
+    OddLength(usize),
+    InvalidSquare(usize, String),
+    IllegalMove(usize, String),
+    // The move is only legal for the other side although the side to move
+    // still has moves, so a pass was recorded where none can happen.
+    MissingPass(usize, String),
+    Unfinished(usize),
+}
+
+impl RecordError {
+    fn kind(&self) -> &'static str {
+        match self {
+            RecordError::OddLength(_) => "odd length",
+            RecordError::InvalidSquare(..) => "invalid square",
+            RecordError::IllegalMove(..) => "illegal move",
+            RecordError::MissingPass(..) => "missing pass",
+            RecordError::Unfinished(_) => "unfinished game",
+        }
+    }
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordError::OddLength(len) => write!(f, "odd length {}", len),
+            RecordError::InvalidSquare(i, square) => {
+                write!(f, "move {}: invalid square \"{}\"", i, square)
+            }
+            RecordError::IllegalMove(i, square) => write!(f, "move {}: {} is illegal", i, square),
+            RecordError::MissingPass(i, square) => write!(
+                f,
+                "move {}: {} is a move of the other side (missing pass?)",
+                i, square
+            ),
+            RecordError::Unfinished(empties) => {
+                write!(f, "game does not end ({} empty squares)", empties)
+            }
+        }
+    }
+}
+
+// Checks a lowercase training record the way `replay_record` plays it:
+// passes are implicit and the game has to be played to the end.
+pub fn validate_record(record: &str) -> Result<(), RecordError> {
+    if !record.len().is_multiple_of(2) {
+        return Err(RecordError::OddLength(record.len()));
+    }
+    let mut board = cmds_to_board("");
+    for (i, bytes) in record.as_bytes().chunks(2).enumerate() {
+        let square = String::from_utf8_lossy(bytes).to_string();
+        if !(b'a'..=b'h').contains(&bytes[0]) || !(b'1'..=b'8').contains(&bytes[1]) {
+            return Err(RecordError::InvalidSquare(i + 1, square));
+        }
+        let pos = lower_cmd_to_pos(square.clone());
+        if !is_legal_pos(&board, &pos) {
+            let mut other = board;
+            other.turn = !other.turn;
+            return Err(if is_legal_pos(&other, &pos) {
+                RecordError::MissingPass(i + 1, square)
+            } else {
+                RecordError::IllegalMove(i + 1, square)
+            });
+        }
+        board = execute_pos(&mut board, pos);
+        if legal(board) == 0 {
+            board.turn = !board.turn;
+        }
+    }
+    if legal(board) != 0 {
+        let empties = 64 - (board.black_board | board.white_board).count_ones() as usize;
+        return Err(RecordError::Unfinished(empties));
+    }
+    Ok(())
+}
+
+// Reads and validates training records. Blank lines are ignored, broken
+// records are reported with their file and line (or game number) and
+// skipped; in `strict` mode any broken record fails the whole load.
+pub fn load_records(paths: &[String], strict: bool) -> Result<Vec<String>, String> {
+    let mut records: Vec<String> = vec![];
+    let mut errors: Vec<(String, RecordError)> = vec![];
+    let mut total = 0;
+    for path in paths {
+        let unit = if is_wtb(path) || is_ggf(path) {
+            "game "
+        } else {
+            ""
+        };
+        for (i, record) in read_record_file(path)?.into_iter().enumerate() {
+            let record = record.trim().to_string();
+            if record.is_empty() {
+                continue;
+            }
+            total += 1;
+            match validate_record(&record) {
+                Ok(()) => records.push(record),
+                Err(e) => errors.push((format!("{}:{}{}", path, unit, i + 1), e)),
+            }
+        }
+    }
+    if errors.is_empty() {
+        return Ok(records);
+    }
+
+    for (place, e) in errors.iter().take(REPORTED_ERRORS) {
+        println!("{}: {}", place, e);
+    }
+    if errors.len() > REPORTED_ERRORS {
+        println!("... and {} more", errors.len() - REPORTED_ERRORS);
+    }
+    let mut kinds: Vec<(&str, usize)> = vec![];
+    for (_, e) in &errors {
+        match kinds.iter_mut().find(|(kind, _)| *kind == e.kind()) {
+            Some((_, count)) => *count += 1,
+            None => kinds.push((e.kind(), 1)),
+        }
+    }
+    let breakdown: Vec<String> = kinds
+        .iter()
+        .map(|(kind, count)| format!("{}: {}", kind, count))
+        .collect();
+    let summary = format!(
+        "{} of {} records are broken ({})",
+        errors.len(),
+        total,
+        breakdown.join(", ")
+    );
+    if strict {
+        Err(format!("{}, stopping in strict mode", summary))
+    } else {
+        println!("{}, skipping them", summary);
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // A game played to the end with the first legal move each turn.
+    fn finished_game() -> String {
+        let mut board = cmds_to_board("");
+        let mut record = String::new();
+        loop {
+            if legal(board) == 0 {
+                board.turn = !board.turn;
+                if legal(board) == 0 {
+                    return record;
+                }
+            }
+            let pos = legal_poss(&board)[0];
+            record += &pos_to_cmd(&pos).to_lowercase();
+            board = execute_pos(&mut board, pos);
+        }
+    }
+
+    #[test]
+    fn every_error_kind_is_detected() {
+        assert_eq!(validate_record(&finished_game()), Ok(()));
+        assert_eq!(validate_record("f5d"), Err(RecordError::OddLength(3)));
+        assert_eq!(
+            validate_record("f5z9"),
+            Err(RecordError::InvalidSquare(2, "z9".to_string()))
+        );
+        assert_eq!(
+            validate_record("a1"),
+            Err(RecordError::IllegalMove(1, "a1".to_string()))
+        );
+        // c3 is black's after f5, but white still has moves.
+        assert_eq!(
+            validate_record("f5c3"),
+            Err(RecordError::MissingPass(2, "c3".to_string()))
+        );
+        assert_eq!(validate_record("f5d6"), Err(RecordError::Unfinished(58)));
+    }
+
+    #[test]
+    fn broken_records_are_counted_and_skipped() {
+        let path = temp_path("records.txt");
+        let game = finished_game();
+        fs::write(&path, format!("{}\n\nf5d\nf5d6\n  {}  \n", game, game)).unwrap();
+        let paths = vec![path.clone()];
+        let loaded = load_records(&paths, false);
+        let strict = load_records(&paths, true);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Ok(vec![game.clone(), game]));
+        assert_eq!(
+            strict,
+            Err(
+                "2 of 4 records are broken (odd length: 1, unfinished game: 1), \
+                 stopping in strict mode"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use crate::record::record::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use std::fs;
+
+// Opening names keyed by move sequence, one `moves name` per line, e.g.
+// `f5d6c3d3c4 Tiger`. A game is named after the longest sequence it starts
+// with under any symmetry of the start position.
+
+#[derive(Clone, Debug, Default)]
+pub struct Openings {
+    lines: Vec<(Vec<u64>, String)>,
+}
+
+// Symmetries that keep the start position, so that they map games to games.
+fn start_symmetries() -> Vec<usize> {
+    let start = cmds_to_board("");
+    (0..8)
+        .filter(|&k| {
+            symmetric_pos(start.black_board, k) == start.black_board
+                && symmetric_pos(start.white_board, k) == start.white_board
+        })
+        .collect()
+}
+
+// Moves of `record` without the passes.
+fn played_moves(record: &GameRecord) -> Vec<u64> {
+    record
+        .moves
+        .iter()
+        .map(|m| m.pos)
+        .filter(|&pos| pos != 0)
+        .collect()
+}
+
+impl Openings {
+    pub fn parse(text: &str) -> Result<Openings, String> {
+        let mut lines = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (cmds, name) = line
+                .split_once(char::is_whitespace)
+                .ok_or(format!("line {}: missing name", i + 1))?;
+            let record = parse_transcript(cmds).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            let moves = record.moves.iter().map(|m| m.pos).collect();
+            lines.push((moves, name.trim().to_string()));
+        }
+        Ok(Openings { lines })
+    }
+
+    pub fn load(path: &str) -> Result<Openings, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Openings::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn longest(&self, moves: &[u64]) -> Option<&(Vec<u64>, String)> {
+        let symmetries = start_symmetries();
+        self.lines
+            .iter()
+            .filter(|(line, _)| {
+                line.len() <= moves.len()
+                    && symmetries.iter().any(|&k| {
+                        line.iter()
+                            .zip(moves)
+                            .all(|(&named, &pos)| symmetric_pos(pos, k) == named)
+                    })
+            })
+            .max_by_key(|(line, _)| line.len())
+    }
+
+    // Name of the longest known sequence `moves` starts with. `moves` has no
+    // passes.
+    pub fn name(&self, moves: &[u64]) -> Option<&str> {
+        self.longest(moves).map(|(_, name)| name.as_str())
+    }
+
+    // The name when the last of `moves` completes a known sequence, for
+    // logging the opening as a game goes on.
+    pub fn reached(&self, moves: &[u64]) -> Option<&str> {
+        self.longest(moves)
+            .filter(|(line, _)| line.len() == moves.len())
+            .map(|(_, name)| name.as_str())
+    }
+
+    pub fn record_name(&self, record: &GameRecord) -> Option<&str> {
+        self.name(&played_moves(record))
+    }
+
+    // Sets `record.opening` when it is not set yet.
+    pub fn tag(&self, record: &mut GameRecord) {
+        if record.opening.is_empty() {
+            if let Some(name) = self.record_name(record) {
+                record.opening = name.to_string();
+            }
+        }
+    }
+
+    // Prints the name when the last move of `record` completes a known
+    // sequence.
+    pub fn log_opening(&self, record: &GameRecord) {
+        if record.moves.last().is_some_and(|m| m.pos != 0) {
+            if let Some(name) = self.reached(&played_moves(record)) {
+                log!("Opening: {}", name);
+            }
+        }
+    }
+}
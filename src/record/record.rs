@@ -0,0 +1,272 @@
+use crate::record::ggf::*;
+use crate::record::opening::*;
+use crate::record::wthor::*;
+use crate::reversi::reversi::*;
+use crate::util::util::*;
+use std::fs;
+
+// One move of a game; `pos` is 0 for a pass and `time` is the thinking time
+// in seconds when it is known.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GameMove {
+    pub pos: u64,
+    pub time: Option<f32>,
+}
+
+// A game from the standard start position. `moves` always holds the passes
+// explicitly.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GameRecord {
+    pub black: String,
+    pub white: String,
+    pub place: String,
+    pub date: String,
+    pub time_control: String,
+    pub moves: Vec<GameMove>,
+    // Final disc difference from black's point of view.
+    pub result: Option<i32>,
+    // How the game ended when it was not played out, e.g. "timeout".
+    pub reason: String,
+    // Name of the opening, see `Openings`.
+    pub opening: String,
+}
+
+// Replays `moves` from the start position, inserting the passes a transcript
+// leaves out and rejecting illegal moves and unneeded passes.
+pub fn normalise_moves(moves: &[GameMove]) -> Result<Vec<GameMove>, String> {
+    let mut board = cmds_to_board("");
+    let mut normalised: Vec<GameMove> = vec![];
+    for (i, game_move) in moves.iter().enumerate() {
+        if legal(board) == 0 {
+            if game_move.pos == 0 {
+                board.turn = !board.turn;
+                normalised.push(game_move.clone());
+                continue;
+            }
+            board.turn = !board.turn;
+            normalised.push(GameMove::default());
+        }
+        if game_move.pos == 0 {
+            return Err(format!("move {}: pass with legal moves left", i + 1));
+        }
+        if !is_legal_pos(&board, &game_move.pos) {
+            return Err(format!(
+                "move {}: {} is illegal",
+                i + 1,
+                pos_to_cmd(&game_move.pos).to_lowercase()
+            ));
+        }
+        board = execute_pos(&mut board, game_move.pos);
+        normalised.push(game_move.clone());
+    }
+    Ok(normalised)
+}
+
+impl GameRecord {
+    pub fn from_moves(moves: &[GameMove]) -> Result<GameRecord, String> {
+        Ok(GameRecord {
+            moves: normalise_moves(moves)?,
+            ..GameRecord::default()
+        })
+    }
+
+    // Lowercase moves without passes, the form used by training records.
+    pub fn transcript(&self) -> String {
+        self.moves
+            .iter()
+            .filter(|game_move| game_move.pos != 0)
+            .map(|game_move| pos_to_cmd(&game_move.pos).to_lowercase())
+            .collect()
+    }
+}
+
+// Reads a transcript such as `f5d6c3`, `F5 D6 C3` or `1. f5 d6 2. c3 pa`.
+// Move numbers are skipped and `pa`, `ps` or `--` mark a pass.
+pub fn parse_transcript(text: &str) -> Result<GameRecord, String> {
+    let chars: Vec<char> = text.to_lowercase().chars().collect();
+    let mut moves: Vec<GameMove> = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied().unwrap_or(' ');
+        if c.is_whitespace() || c == ',' {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let end = (i..chars.len())
+                .find(|&j| !chars[j].is_ascii_digit())
+                .unwrap_or(chars.len());
+            if chars.get(end) != Some(&'.') {
+                return Err(format!("column {}: unexpected number", i + 1));
+            }
+            i = end + 1;
+        } else if ('a'..='h').contains(&c) && ('1'..='8').contains(&next) {
+            moves.push(GameMove {
+                pos: lower_cmd_to_pos(format!("{}{}", c, next)),
+                time: None,
+            });
+            i += 2;
+        } else if matches!((c, next), ('p', 'a') | ('p', 's') | ('-', '-')) {
+            moves.push(GameMove::default());
+            i += 2;
+        } else {
+            return Err(format!("column {}: unexpected \"{}\"", i + 1, c));
+        }
+    }
+    GameRecord::from_moves(&moves)
+}
+
+// Reads every game in a GGF file, a `.wtb` database, or a file with one
+// transcript per line.
+pub fn read_games(path: &str) -> Result<Vec<GameRecord>, String> {
+    if is_wtb(path) {
+        return read_wtb(path)?
+            .games
+            .iter()
+            .enumerate()
+            .map(|(i, game)| {
+                let moves: Vec<GameMove> = game
+                    .moves
+                    .iter()
+                    .map(|&pos| GameMove { pos, time: None })
+                    .collect();
+                let mut record = GameRecord::from_moves(&moves)
+                    .map_err(|e| format!("{}: game {}: {}", path, i + 1, e))?;
+                record.result = Some(game.disc_diff());
+                Ok(record)
+            })
+            .collect();
+    }
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    if is_ggf(path) {
+        return parse_ggf(&text).map_err(|e| format!("{}: {}", path, e));
+    }
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| parse_transcript(line).map_err(|e| format!("{}:{}: {}", path, i + 1, e)))
+        .collect()
+}
+
+// Writes games as GGF when `path` ends in `.ggf` and as transcripts
+// otherwise.
+pub fn write_games(path: &str, records: &[GameRecord]) -> Result<(), String> {
+    let text: String = records
+        .iter()
+        .map(|record| {
+            if is_ggf(path) {
+                write_ggf(record) + "\n"
+            } else {
+                record.transcript() + "\n"
+            }
+        })
+        .collect();
+    fs::write(path, text).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Reads game records as lowercase move strings, one per game. `.wtb` and
+// `.ggf` files are decoded; anything else is read one record per line.
+pub fn read_record_file(path: &str) -> Result<Vec<String>, String> {
+    if is_wtb(path) {
+        let base = read_wtb(path)?;
+        return Ok(base.games.iter().map(|game| game.record()).collect());
+    }
+    if is_ggf(path) {
+        let records = read_games(path)?;
+        return Ok(records.iter().map(|record| record.transcript()).collect());
+    }
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(text.lines().map(|line| line.to_string()).collect())
+}
+
+// Converts between game record formats by file extension, naming the
+// openings of the games.
+pub fn convert_games(input: &str, output: &str, openings: &Openings) {
+    match read_games(input).and_then(|mut records| {
+        for record in records.iter_mut() {
+            openings.tag(record);
+        }
+        write_games(output, &records)?;
+        Ok(records.len())
+    }) {
+        Ok(count) => println!("Converted {} games to {}.", count, output),
+        Err(e) => println!("{}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A finished game in which black has to pass after the first eight moves.
+    fn game_with_pass() -> GameRecord {
+        let mut record = parse_transcript("d3c3b3b2f5a3a1c1").unwrap();
+        let mut board = cmds_to_board("");
+        for game_move in &record.moves {
+            board = execute_pos(&mut board, game_move.pos);
+        }
+        loop {
+            if legal(board) == 0 {
+                board.turn = !board.turn;
+                if legal(board) == 0 {
+                    break;
+                }
+                record.moves.push(GameMove::default());
+            }
+            let pos = legal_poss(&board)[0];
+            board = execute_pos(&mut board, pos);
+            record.moves.push(GameMove {
+                pos,
+                time: Some(1.25),
+            });
+        }
+        record.black = "Alice".to_string();
+        record.white = "Bob".to_string();
+        record.date = "1792400000".to_string();
+        record.opening = "Test".to_string();
+        record.result =
+            Some(board.black_board.count_ones() as i32 - board.white_board.count_ones() as i32);
+        record.reason = "timeout".to_string();
+        record
+    }
+
+    #[test]
+    fn ggf_round_trip() {
+        let record = game_with_pass();
+        assert_eq!(record.moves[8], GameMove::default());
+        let path = temp_path("round_trip.ggf");
+        write_games(&path, &[record.clone(), GameRecord::default()]).unwrap();
+        let records = read_games(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records, [record, GameRecord::default()]);
+    }
+
+    #[test]
+    fn transcript_round_trip() {
+        let record = game_with_pass();
+        let path = temp_path("round_trip.txt");
+        write_games(&path, std::slice::from_ref(&record)).unwrap();
+        let records = read_games(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        // Transcripts keep the moves, with the passes put back, and nothing
+        // else.
+        let moves: Vec<u64> = record.moves.iter().map(|m| m.pos).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0].moves.iter().map(|m| m.pos).collect::<Vec<u64>>(),
+            moves
+        );
+        assert_eq!(
+            parse_transcript("1. F5 d6 2. c3, D3 pa").unwrap_err(),
+            "move 5: pass with legal moves left".to_string()
+        );
+    }
+
+    #[test]
+    fn invalid_ggf_is_rejected() {
+        let valid = write_ggf(&game_with_pass());
+        assert!(valid.contains("B[pa]"));
+        assert!(parse_ggf(&valid.replace("B[pa]", "")).is_err());
+        assert!(parse_ggf(&valid.replace("B[d3", "B[d4")).is_err());
+        assert!(parse_ggf(&valid.replace(";)", "")).is_err());
+    }
+}
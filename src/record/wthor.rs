@@ -0,0 +1,331 @@
+use crate::util::util::*;
+use std::fs;
+
+/*
+
+WTHOR database files (little endian)
+
+header (16 bytes)
+  0      century of creation
+  1      year of creation
+  2      month
+  3      day
+  4..8   number of games (u32)
+  8..10  number of names (u16)
+  10..12 year of the games (u16)
+  12     board size (0 or 8 for 8x8)
+  13     1 for solitaire games
+  14     depth of the theoretical score
+  15     reserved
+
+game (.wtb, 68 bytes)
+  0..2   tournament id (u16)
+  2..4   black player id (u16)
+  4..6   white player id (u16)
+  6      black discs at the end of the game
+  7      theoretical black discs
+  8..68  moves as 10 * row + column (11 = a1, 88 = h8), 0 after the last move
+
+names (.jou 20 bytes, .trn 26 bytes)
+  Latin-1, padded with zeros
+
+*/
+
+pub const WTHOR_HEADER_SIZE: usize = 16;
+pub const WTHOR_GAME_SIZE: usize = 68;
+pub const WTHOR_PLAYER_SIZE: usize = 20;
+pub const WTHOR_TOURNAMENT_SIZE: usize = 26;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WthorHeader {
+    pub created: (u16, u8, u8),
+    pub game_count: u32,
+    pub name_count: u16,
+    pub game_year: u16,
+    pub board_size: u8,
+    pub solitaire: bool,
+    pub theoretical_depth: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WthorGame {
+    pub tournament: u16,
+    pub black_player: u16,
+    pub white_player: u16,
+    pub black_discs: u8,
+    pub theoretical_black_discs: u8,
+    pub moves: Vec<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct WthorBase {
+    pub header: WthorHeader,
+    pub games: Vec<WthorGame>,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_header(path: &str, bytes: &[u8]) -> Result<WthorHeader, String> {
+    if bytes.len() < WTHOR_HEADER_SIZE {
+        return Err(format!("{}: shorter than the WTHOR header", path));
+    }
+    Ok(WthorHeader {
+        created: (bytes[0] as u16 * 100 + bytes[1] as u16, bytes[2], bytes[3]),
+        game_count: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        name_count: read_u16(bytes, 8),
+        game_year: read_u16(bytes, 10),
+        board_size: bytes[12],
+        solitaire: bytes[13] == 1,
+        theoretical_depth: bytes[14],
+    })
+}
+
+// 0 is returned for anything outside 11..88.
+pub fn wthor_move_to_pos(value: u8) -> u64 {
+    let (row, column) = (value / 10, value % 10);
+    if !(1..=8).contains(&row) || !(1..=8).contains(&column) {
+        return 0;
+    }
+    1 << ((column - 1) + 8 * (row - 1))
+}
+
+pub fn read_wtb(path: &str) -> Result<WthorBase, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let header = read_header(path, &bytes)?;
+    if header.board_size != 0 && header.board_size != 8 {
+        return Err(format!(
+            "{}: unsupported board size {}",
+            path, header.board_size
+        ));
+    }
+    let body = &bytes[WTHOR_HEADER_SIZE..];
+    let expected = header.game_count as usize * WTHOR_GAME_SIZE;
+    if body.len() < expected {
+        return Err(format!(
+            "{}: header declares {} games but the file holds {}",
+            path,
+            header.game_count,
+            body.len() / WTHOR_GAME_SIZE
+        ));
+    }
+
+    let mut games: Vec<WthorGame> = vec![];
+    for (i, game) in body[..expected].chunks(WTHOR_GAME_SIZE).enumerate() {
+        let mut moves: Vec<u64> = vec![];
+        for &value in game[8..].iter().take_while(|&&value| value != 0) {
+            let pos = wthor_move_to_pos(value);
+            if pos == 0 {
+                return Err(format!(
+                    "{}: game {} has an invalid move {}",
+                    path,
+                    i + 1,
+                    value
+                ));
+            }
+            moves.push(pos);
+        }
+        games.push(WthorGame {
+            tournament: read_u16(game, 0),
+            black_player: read_u16(game, 2),
+            white_player: read_u16(game, 4),
+            black_discs: game[6],
+            theoretical_black_discs: game[7],
+            moves,
+        });
+    }
+    Ok(WthorBase { header, games })
+}
+
+// Reads a `.jou` (players) or `.trn` (tournaments) file; the id used in
+// games is the index into the returned list.
+pub fn read_wthor_names(path: &str) -> Result<Vec<String>, String> {
+    let size = if path.to_lowercase().ends_with(".trn") {
+        WTHOR_TOURNAMENT_SIZE
+    } else if path.to_lowercase().ends_with(".jou") {
+        WTHOR_PLAYER_SIZE
+    } else {
+        return Err(format!("{}: expected a .jou or .trn file", path));
+    };
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let header = read_header(path, &bytes)?;
+    let body = &bytes[WTHOR_HEADER_SIZE..];
+    let count = (header.name_count as usize).min(body.len() / size);
+    Ok(body
+        .chunks(size)
+        .take(count)
+        .map(|name| {
+            name.iter()
+                .take_while(|&&c| c != 0)
+                .map(|&c| c as char)
+                .collect::<String>()
+                .trim_end()
+                .to_string()
+        })
+        .collect())
+}
+
+impl WthorGame {
+    // Moves in the lowercase form used by training records.
+    pub fn record(&self) -> String {
+        self.moves
+            .iter()
+            .map(|pos| pos_to_cmd(pos).to_lowercase())
+            .collect()
+    }
+
+    pub fn disc_diff(&self) -> i32 {
+        2 * self.black_discs as i32 - 64
+    }
+
+    pub fn theoretical_diff(&self) -> i32 {
+        2 * self.theoretical_black_discs as i32 - 64
+    }
+}
+
+pub fn is_wtb(path: &str) -> bool {
+    path.to_lowercase().ends_with(".wtb")
+}
+
+fn name_of(names: &[String], id: u16) -> String {
+    names
+        .get(id as usize)
+        .cloned()
+        .unwrap_or_else(|| format!("#{}", id))
+}
+
+// Prints a `.wtb` file as tab separated games, with player and tournament
+// names when the `.jou` and `.trn` files are given.
+pub fn print_wthor(paths: &[String]) {
+    let base = match read_wtb(&paths[0]) {
+        Ok(base) => base,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let mut players: Vec<String> = vec![];
+    let mut tournaments: Vec<String> = vec![];
+    for path in &paths[1..] {
+        match read_wthor_names(path) {
+            Ok(names) if path.to_lowercase().ends_with(".jou") => players = names,
+            Ok(names) => tournaments = names,
+            Err(e) => println!("{}", e),
+        }
+    }
+    let header = &base.header;
+    println!(
+        "# created {}-{:02}-{:02}  games {}  year {}  solitaire {}  theoretical depth {}",
+        header.created.0,
+        header.created.1,
+        header.created.2,
+        header.game_count,
+        header.game_year,
+        header.solitaire,
+        header.theoretical_depth
+    );
+    for game in &base.games {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            name_of(&tournaments, game.tournament),
+            name_of(&players, game.black_player),
+            name_of(&players, game.white_player),
+            game.disc_diff(),
+            game.theoretical_diff(),
+            game.record()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(count: usize) -> Vec<u8> {
+        let mut bytes = vec![20, 24, 10, 19];
+        bytes.extend_from_slice(&(count as u32).to_le_bytes());
+        bytes.extend_from_slice(&(count as u16).to_le_bytes());
+        bytes.extend_from_slice(&2024u16.to_le_bytes());
+        bytes.extend_from_slice(&[8, 0, 22, 0]);
+        bytes
+    }
+
+    fn wtb_bytes(games: &[WthorGame]) -> Vec<u8> {
+        let mut bytes = header(games.len());
+        for game in games {
+            bytes.extend_from_slice(&game.tournament.to_le_bytes());
+            bytes.extend_from_slice(&game.black_player.to_le_bytes());
+            bytes.extend_from_slice(&game.white_player.to_le_bytes());
+            bytes.push(game.black_discs);
+            bytes.push(game.theoretical_black_discs);
+            let mut moves = [0u8; 60];
+            for (value, pos) in moves.iter_mut().zip(&game.moves) {
+                let square = pos.trailing_zeros() as u8;
+                *value = 10 * (square / 8 + 1) + square % 8 + 1;
+            }
+            bytes.extend_from_slice(&moves);
+        }
+        bytes
+    }
+
+    fn game(cmds: &str, black_discs: u8) -> WthorGame {
+        WthorGame {
+            tournament: 1,
+            black_player: 0,
+            white_player: 1,
+            black_discs,
+            theoretical_black_discs: black_discs + 2,
+            moves: (0..cmds.len() / 2)
+                .map(|i| cmd_to_pos(cmds[2 * i..2 * i + 2].to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn games_and_names_are_read_back() {
+        let games = vec![game("F5D6C3D3C4", 40), game("A1H8", 20), game("", 32)];
+        let path = temp_path("games.wtb");
+        fs::write(&path, wtb_bytes(&games)).unwrap();
+        let base = read_wtb(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(base.header.created, (2024, 10, 19));
+        assert_eq!(base.header.game_count, 3);
+        assert_eq!(base.header.game_year, 2024);
+        assert_eq!(base.header.theoretical_depth, 22);
+        assert_eq!(base.games, games);
+        assert_eq!(base.games[0].record(), "f5d6c3d3c4");
+        assert_eq!(base.games[0].disc_diff(), 16);
+        assert_eq!(base.games[0].theoretical_diff(), 20);
+
+        let path = temp_path("players.jou");
+        let mut bytes = header(2);
+        for name in ["Alice", "Bob"] {
+            let mut padded = [0u8; WTHOR_PLAYER_SIZE];
+            padded[..name.len()].copy_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&padded);
+        }
+        fs::write(&path, bytes).unwrap();
+        let names = read_wthor_names(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(names, ["Alice", "Bob"]);
+    }
+
+    #[test]
+    fn broken_files_are_rejected() {
+        let path = temp_path("broken.wtb");
+        let mut bytes = wtb_bytes(&[game("F5D6", 40), game("F5F6", 30)]);
+        bytes.truncate(bytes.len() - 1);
+        fs::write(&path, &bytes).unwrap();
+        assert!(read_wtb(&path).is_err());
+
+        let mut bytes = wtb_bytes(&[game("F5D6", 40)]);
+        bytes[WTHOR_HEADER_SIZE + 8] = 99;
+        fs::write(&path, &bytes).unwrap();
+        assert!(read_wtb(&path).is_err());
+
+        fs::write(&path, &bytes[..10]).unwrap();
+        assert!(read_wtb(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
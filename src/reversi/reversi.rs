@@ -31,6 +31,101 @@ pub const WALL_BIT: u64 = 1 << 1
     | 1 << 61;
 // | 1 << 62;
 
+pub const PATTERN_INSTANCE_NUM: usize = 10;
+
+// Squares of every pattern instance the evaluation reads, in the same order
+// as `pattern0_indexes`, `pattern1_indexes` and `pattern2_indexes` of the
+// learning module. Every board keeps the indexes of these instances.
+pub const PATTERN_SQUARES: [&[usize]; PATTERN_INSTANCE_NUM] = [
+    &[0, 1, 2, 3, 4, 5, 6, 7, 9, 14],
+    &[56, 48, 40, 32, 24, 16, 8, 0, 49, 9],
+    &[63, 62, 61, 60, 59, 58, 57, 56, 54, 49],
+    &[7, 15, 23, 31, 39, 47, 55, 63, 14, 54],
+    &[0, 9, 18, 27, 36, 45, 54, 63],
+    &[7, 14, 21, 28, 35, 42, 49, 56],
+    &[0, 1, 2, 3, 8, 9, 10, 16, 17, 24],
+    &[56, 48, 40, 32, 57, 49, 41, 58, 50, 59],
+    &[63, 62, 61, 60, 55, 54, 53, 47, 46, 39],
+    &[7, 15, 23, 31, 6, 14, 22, 5, 13, 4],
+];
+
+const MAX_SQUARE_PATTERN: usize = 4;
+
+// For every square, the pattern instances containing it and the power of 3
+// of its digit in that instance's index.
+const SQUARE_PATTERNS: [[(usize, usize); MAX_SQUARE_PATTERN]; 64] = square_patterns();
+const SQUARE_PATTERN_NUM: [usize; 64] = square_pattern_num();
+
+const fn square_patterns() -> [[(usize, usize); MAX_SQUARE_PATTERN]; 64] {
+    let mut table = [[(0, 0); MAX_SQUARE_PATTERN]; 64];
+    let mut count = [0; 64];
+    let mut p = 0;
+    while p < PATTERN_INSTANCE_NUM {
+        let squares = PATTERN_SQUARES[p];
+        let mut power = 1;
+        let mut i = squares.len();
+        while i > 0 {
+            i -= 1;
+            let square = squares[i];
+            table[square][count[square]] = (p, power);
+            count[square] += 1;
+            power *= 3;
+        }
+        p += 1;
+    }
+    table
+}
+
+const fn square_pattern_num() -> [usize; 64] {
+    let mut count = [0; 64];
+    let mut p = 0;
+    while p < PATTERN_INSTANCE_NUM {
+        let squares = PATTERN_SQUARES[p];
+        let mut i = 0;
+        while i < squares.len() {
+            count[squares[i]] += 1;
+            i += 1;
+        }
+        p += 1;
+    }
+    count
+}
+
+pub fn pattern_indexes(board: &Board) -> [usize; PATTERN_INSTANCE_NUM] {
+    let mut indexes = [0; PATTERN_INSTANCE_NUM];
+    for (p, squares) in PATTERN_SQUARES.iter().enumerate() {
+        for &square in squares.iter() {
+            let digit =
+                (((board.black_board >> square) & 1) << 1) + ((board.white_board >> square) & 1);
+            indexes[p] = indexes[p] * 3 + digit as usize;
+        }
+    }
+    indexes
+}
+
+// Called after `pos` has been placed and `flipped` turned over for the side
+// that has just moved (`board.turn` is not yet switched).
+#[inline]
+pub fn update_pattern_indexes(board: &mut Board, pos: u64, flipped: u64) {
+    let square = pos.trailing_zeros() as usize;
+    let placed = if board.turn { 2 } else { 1 };
+    for &(p, power) in &SQUARE_PATTERNS[square][..SQUARE_PATTERN_NUM[square]] {
+        board.pattern_indexes[p] += placed * power;
+    }
+    let mut rest = flipped;
+    while rest != 0 {
+        let square = rest.trailing_zeros() as usize;
+        for &(p, power) in &SQUARE_PATTERNS[square][..SQUARE_PATTERN_NUM[square]] {
+            if board.turn {
+                board.pattern_indexes[p] += power;
+            } else {
+                board.pattern_indexes[p] -= power;
+            }
+        }
+        rest &= rest - 1;
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Board {
     pub black_board: u64,
@@ -39,6 +134,7 @@ pub struct Board {
     pub no_legal_command: i32,
     pub value: i32,
     pub before_pos: u64,
+    pub pattern_indexes: [usize; PATTERN_INSTANCE_NUM],
 }
 
 impl Hash for Board {
@@ -59,6 +155,23 @@ impl PartialEq for Board {
 
 impl Eq for Board {}
 
+impl Board {
+    // A board with these discs and the pattern indexes that go with them.
+    pub fn new(black_board: u64, white_board: u64, turn: bool) -> Board {
+        let mut board = Board {
+            black_board,
+            white_board,
+            turn,
+            no_legal_command: 0,
+            value: 0,
+            before_pos: 0,
+            pattern_indexes: [0; PATTERN_INSTANCE_NUM],
+        };
+        board.pattern_indexes = pattern_indexes(&board);
+        board
+    }
+}
+
 enum LineDirection {
     Vertical,
     Horizontal,
@@ -153,6 +266,11 @@ pub const fn rotate180_pos(pos: u64) -> u64 {
     pos.reverse_bits()
 }
 
+#[inline]
+pub const fn flip_vertical_pos(pos: u64) -> u64 {
+    pos.swap_bytes()
+}
+
 #[inline]
 pub const fn flip_diagonal_pos(pos: u64) -> u64 {
     macro_rules! calc {
@@ -169,43 +287,74 @@ pub const fn flip_diagonal_pos(pos: u64) -> u64 {
     result
 }
 
+// The `k`-th of the 8 board symmetries, `k = 0` being the identity.
+pub fn symmetric_pos(pos: u64, k: usize) -> u64 {
+    let mut pos = pos;
+    if k & 1 != 0 {
+        pos = flip_diagonal_pos(pos);
+    }
+    if k & 2 != 0 {
+        pos = flip_vertical_pos(pos);
+    }
+    if k & 4 != 0 {
+        pos = rotate180_pos(pos);
+    }
+    pos
+}
+
+// Undoes `symmetric_pos(_, k)`.
+pub fn inverse_symmetric_pos(pos: u64, k: usize) -> u64 {
+    let mut pos = pos;
+    if k & 4 != 0 {
+        pos = rotate180_pos(pos);
+    }
+    if k & 2 != 0 {
+        pos = flip_vertical_pos(pos);
+    }
+    if k & 1 != 0 {
+        pos = flip_diagonal_pos(pos);
+    }
+    pos
+}
+
 pub fn init_board(board: &mut Board) {
-    board.black_board = 1 << 28 | 1 << 35;
-    board.white_board = 1 << 27 | 1 << 36;
-    board.turn = BLACK;
-    board.no_legal_command = 0;
-    board.value = 0;
-    board.before_pos = 0;
+    *board = Board::new(1 << 28 | 1 << 35, 1 << 27 | 1 << 36, BLACK);
 }
 
 pub fn print_board(board: &Board) {
+    println!("{}", board_text(board));
+}
+
+// The board as `print_board` shows it, without the last newline.
+pub fn board_text(board: &Board) -> String {
     let result = legal_poss(board).iter().fold(0, |acc, &x| acc | x);
-    print!("    A   B   C   D   E   F   G   H");
+    let mut text = String::from("    A   B   C   D   E   F   G   H");
     let line = "+---+---+---+---+---+---+---+---+";
     for i in 0..64 {
         if i % CELL == 0 {
-            print!("\n  {}\n", line);
-            print!("{} ", i / CELL + 1);
+            text += &format!("\n  {}\n", line);
+            text += &format!("{} ", i / CELL + 1);
         }
-        print!("|");
+        text += "|";
         if (board.black_board >> i) & 1 as u64 == 1 {
-            print!(" {} ", BLACK_STONE);
+            text += &format!(" {} ", BLACK_STONE);
         } else if (board.white_board >> i) & 1 as u64 == 1 {
-            print!(" {} ", WHITE_STONE);
+            text += &format!(" {} ", WHITE_STONE);
         } else if (result >> i) & 1 as u64 == 1 {
-            print!("[ ]");
+            text += "[ ]";
         } else {
-            print!("   ")
+            text += "   ";
         }
         if i % CELL == 7 {
-            print!("|");
+            text += "|";
         }
     }
-    print!("\n  {}\n", line);
-    print!(
-        "turn: {}\n",
+    text += &format!("\n  {}\n", line);
+    text += &format!(
+        "turn: {}",
         if board.turn { BLACK_STONE } else { WHITE_STONE }
     );
+    text
 }
 
 pub fn board_state(board: &Board) -> i32 {
@@ -252,9 +401,28 @@ fn flip(board: &mut Board, pos: &u64) -> Board {
         new_board.white_board |= reversed_pos;
         new_board.black_board &= !reversed_pos;
     }
+    update_pattern_indexes(&mut new_board, *pos, reversed_pos);
     new_board
 }
 
+// Replays a concatenated move string such as "F5D6C3" from the initial
+// position, letting the other side move when one has no legal move.
+pub fn cmds_to_board(cmds: &str) -> Board {
+    let mut board = Board::new(0, 0, BLACK);
+    init_board(&mut board);
+    let cmds = cmds.trim().to_uppercase();
+    for i in 0..(cmds.len() / 2) {
+        if legal(board) == 0 {
+            board.turn = !board.turn;
+        }
+        board = execute_cmd(&mut board, cmds[(2 * i)..=(2 * i + 1)].to_string());
+    }
+    if legal(board) == 0 {
+        board.turn = !board.turn;
+    }
+    board
+}
+
 pub fn execute_cmd(board: &mut Board, cmd: String) -> Board {
     execute_pos(board, cmd_to_pos(cmd))
 }
@@ -263,9 +431,25 @@ pub fn execute_lower_cmd(board: &mut Board, cmd: String) -> Board {
     execute_pos(board, lower_cmd_to_pos(cmd))
 }
 
+// Plays the legal move `pos` on the discs only. The pattern indexes are left
+// as they were, for endgame solvers that never evaluate the result.
+pub fn play_discs(board: &Board, pos: u64) -> Board {
+    let flipped = reverse(*board, pos) | pos;
+    let mut new_board = *board;
+    if board.turn {
+        new_board.black_board |= flipped;
+        new_board.white_board &= !flipped;
+    } else {
+        new_board.white_board |= flipped;
+        new_board.black_board &= !flipped;
+    }
+    new_board.turn = !board.turn;
+    new_board
+}
+
 pub fn execute_pos(board: &mut Board, pos: u64) -> Board {
     if pos == 0 || !is_legal_pos(board, &pos) {
-        println!("{}: illegal command", pos_to_cmd(&pos));
+        log!("{}: illegal command", pos_to_cmd(&pos));
         return *board;
     }
     if board.turn {
@@ -291,3 +475,64 @@ pub fn msb(pos: u64) -> u64 {
     current_pos &= !(current_pos >> 1);
     current_pos
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn incremental_pattern_indexes_match_recomputed() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..200 {
+            let mut board = cmds_to_board("");
+            loop {
+                if legal(board) == 0 {
+                    board.turn = !board.turn;
+                    if legal(board) == 0 {
+                        break;
+                    }
+                }
+                let poss = legal_poss(&board);
+                board = execute_pos(&mut board, poss[rng.gen_range(0..poss.len())]);
+                assert_eq!(board.pattern_indexes, pattern_indexes(&board));
+                let fresh = Board::new(board.black_board, board.white_board, board.turn);
+                assert_eq!(fresh.pattern_indexes, board.pattern_indexes);
+            }
+        }
+    }
+
+    #[test]
+    fn patterns_are_read_alike_under_rotation() {
+        let mut rng = StdRng::seed_from_u64(1);
+        // The two diagonals are read from opposite ends after a quarter turn,
+        // which the reversed mirror of their weights already covers.
+        let groups = [0..4, 6..10];
+        for _ in 0..50 {
+            let mut board = cmds_to_board("");
+            for _ in 0..20 {
+                let poss = legal_poss(&board);
+                if poss.is_empty() {
+                    break;
+                }
+                board = execute_pos(&mut board, poss[rng.gen_range(0..poss.len())]);
+            }
+            // Symmetries 3, 4 and 7 are the rotations by 90, 180 and 270 degrees.
+            for k in [3, 4, 7] {
+                let rotated = Board::new(
+                    symmetric_pos(board.black_board, k),
+                    symmetric_pos(board.white_board, k),
+                    board.turn,
+                );
+                for group in groups.clone() {
+                    let mut expected = board.pattern_indexes[group.clone()].to_vec();
+                    let mut actual = rotated.pattern_indexes[group].to_vec();
+                    expected.sort_unstable();
+                    actual.sort_unstable();
+                    assert_eq!(expected, actual);
+                }
+            }
+        }
+    }
+}
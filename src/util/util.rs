@@ -1,25 +1,32 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
 pub fn cmd_to_pos(cmd: String) -> u64 {
     let mut pos: u64 = 1;
     if let Some(first) = cmd.chars().nth(0) {
         if (first as u8) < ('A' as u8) || ('H' as u8) < (first as u8) {
-            println!("invalid command");
+            log!("invalid command");
             return 0;
         }
         let x = first as u8 - 'A' as u8;
         pos <<= x;
     } else {
-        println!("too short command");
+        log!("too short command");
         return 0;
     }
     if let Some(second) = cmd.chars().nth(1) {
         if (second as u8) < ('1' as u8) || ('8' as u8) < (second as u8) {
-            println!("invalid command");
+            log!("invalid command");
             return 0;
         }
         let y = second as u8 - '1' as u8;
         pos <<= y * 8;
     } else {
-        println!("too short command");
+        log!("too short command");
         return 0;
     }
     pos
@@ -29,24 +36,24 @@ pub fn lower_cmd_to_pos(cmd: String) -> u64 {
     let mut pos: u64 = 1;
     if let Some(first) = cmd.chars().nth(0) {
         if (first as u8) < ('a' as u8) || ('h' as u8) < (first as u8) {
-            println!("invalid command");
+            log!("invalid command");
             return 0;
         }
         let x = first as u8 - 'a' as u8;
         pos <<= x;
     } else {
-        println!("too short command");
+        log!("too short command");
         return 0;
     }
     if let Some(second) = cmd.chars().nth(1) {
         if (second as u8) < ('1' as u8) || ('8' as u8) < (second as u8) {
-            println!("invalid command");
+            log!("invalid command");
             return 0;
         }
         let y = second as u8 - '1' as u8;
         pos <<= y * 8;
     } else {
-        println!("too short command");
+        log!("too short command");
         return 0;
     }
     pos
@@ -61,3 +68,56 @@ pub fn pos_to_cmd(pos: &u64) -> String {
     let cmd = format!("{}{}", first as char, second as char);
     cmd
 }
+
+// Parses the value of a `key=value` option.
+pub fn option_value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", key, value))
+}
+
+// Writes `path` through a synced temporary file that is then renamed over
+// it, so an interrupted write never leaves a broken file behind.
+pub fn write_atomically(
+    path: &str,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+    write(&mut file)?;
+    file.into_inner()?.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+// Messages of the client and the book go to stderr once stdout carries the
+// protocol, see `-stdio`.
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_log_to_stderr(on: bool) {
+    LOG_TO_STDERR.store(on, Ordering::Relaxed);
+}
+
+pub fn log_to_stderr() -> bool {
+    LOG_TO_STDERR.load(Ordering::Relaxed)
+}
+
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if $crate::util::util::log_to_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use log;
+
+// A file name in the temporary directory that other test processes do not
+// use.
+#[cfg(test)]
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("othello-{}-{}", std::process::id(), name))
+        .to_string_lossy()
+        .into_owned()
+}